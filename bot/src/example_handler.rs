@@ -14,7 +14,7 @@ impl handler::Handler for ExampleHandler {
         } = msg.command
             && message.starts_with("!test")
         {
-            let nick = msg.nick().unwrap_or("someone".into());
+            let nick = msg.nick().unwrap_or("someone");
             let _ = ctx.client.privmsg(reply_to, &format!("hi {}", nick)).await;
         }
 
@@ -7,14 +7,23 @@ mod score;
 mod seen;
 mod welcome;
 
+use std::path::PathBuf;
+
+use anyhow::Context as _;
 use clap::Parser;
-use irc_core::{self, bot};
+use irc_core::{
+    self, bot, client::ClientBuilder, ctcp::CtcpReplies, decode::Decoder, decode::Encoding,
+    sasl::SaslMechanism, tls::TlsConfig,
+};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(short, long)]
     nick: String,
+    /// Nicks to fall back on if `--nick` is taken.
+    #[arg(long)]
+    alt_nick: Vec<String>,
     #[arg(short, long)]
     user: String,
     #[arg(short, long, default_value_t = String::from("irc.libera.chat:6667"))]
@@ -23,6 +32,46 @@ struct Args {
     channels: Vec<String>,
     #[arg(short, long, default_value_t = String::from("rumors.db"))]
     db_url: String,
+    #[arg(long, requires = "sasl_password")]
+    sasl_user: Option<String>,
+    #[arg(long, requires = "sasl_user")]
+    sasl_password: Option<String>,
+    #[arg(long, default_value = "plain")]
+    sasl_mechanism: SaslMechanism,
+    #[arg(long)]
+    tls: bool,
+    /// Extra CA certificate (PEM) to trust, e.g. for a private network.
+    #[arg(long, requires = "tls")]
+    tls_ca: Option<PathBuf>,
+    /// Client certificate (PEM) for CertFP.
+    #[arg(long, requires_all = ["tls", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Skip certificate validation. Only for self-signed test servers.
+    #[arg(long, requires = "tls")]
+    tls_insecure: bool,
+    /// Encoding to assume for lines that aren't UTF-8, e.g. `latin1` or `koi8-r`.
+    #[arg(long, default_value_t = String::from("windows-1252"))]
+    fallback_encoding: String,
+    /// Sent with QUIT when the bot is stopped with Ctrl-C or SIGTERM.
+    #[arg(long, default_value_t = String::from("Shutting down"))]
+    quit_message: String,
+}
+
+fn tls_config(args: &Args) -> anyhow::Result<TlsConfig> {
+    let read = |path: &PathBuf| {
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+    };
+
+    let mut tls = TlsConfig::new().danger_accept_invalid_certs(args.tls_insecure);
+    if let Some(ca) = &args.tls_ca {
+        tls = tls.with_ca_pem(read(ca)?);
+    }
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        tls = tls.with_client_cert_pem(read(cert)?, read(key)?);
+    }
+    Ok(tls)
 }
 
 #[tokio::main]
@@ -41,8 +90,36 @@ async fn main() -> anyhow::Result<()> {
 
     let rumors_handler = rumors::RumorsHandler::new(&args.db_url, &args.nick).await?;
 
-    let client = irc_core::connect(args.server, args.nick, args.user).await?;
+    let tls = args.tls.then(|| tls_config(&args)).transpose()?;
+
+    let fallback = Encoding::for_label(args.fallback_encoding.as_bytes())
+        .with_context(|| format!("unknown encoding {}", args.fallback_encoding))?;
+
+    let mut builder = ClientBuilder::new(args.server, args.nick, args.user)
+        .with_capabilities([
+            "server-time",
+            "message-tags",
+            "multi-prefix",
+            "away-notify",
+            "account-tag",
+            "batch",
+            "labeled-response",
+            "draft/chathistory",
+        ])
+        .with_alt_nicks(args.alt_nick)
+        .with_decoder(Decoder::new().with_fallback(fallback))
+        .with_ctcp_replies(
+            CtcpReplies::new().with_version(format!("rustirc {}", env!("CARGO_PKG_VERSION"))),
+        );
+    if let (Some(account), Some(password)) = (args.sasl_user, args.sasl_password) {
+        builder = builder.with_sasl(args.sasl_mechanism, account, password);
+    }
+    if let Some(tls) = tls {
+        builder = builder.with_tls(tls);
+    }
+    let client = builder.connect().await?;
     let bot = bot::BotBuilder::new_with_state(state)
+        .with_quit_message(args.quit_message)
         .with_handler(ping::PingHandler)
         .with_handler(example_handler::ExampleHandler)
         .with_handler(welcome::WelcomeHandler)
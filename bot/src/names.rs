@@ -3,8 +3,11 @@ use std::ops::ControlFlow;
 use crate::irc_core::{
     handler::{self, Handler},
     irc_msg::{self},
+    numeric::Numeric,
 };
 
+/// Logs who comes and goes. Membership itself is tracked by `irc_core`; see
+/// [`handler::Context::members`].
 pub struct NamesHandler;
 
 #[async_trait::async_trait]
@@ -13,40 +16,50 @@ impl Handler for NamesHandler {
         match msg.command {
             irc_msg::Command::Join { ref channel, .. } => {
                 if let Some(nick) = msg.nick()
-                    && nick != ctx.client.nick
+                    && nick != ctx.client.nick()
                 {
                     println!("=== {0} joined {1}", nick, channel);
-                    ctx.with_state(|state| {
-                        state.names.push(nick.to_string());
-                    })
-                    .await;
                 }
             }
 
             irc_msg::Command::Part { ref channel, .. } => {
                 if let Some(nick) = msg.nick() {
                     println!("=== {0} left {1}", nick, channel);
-                    ctx.with_state(|state| {
-                        state.names.retain(|n| n != &nick);
-                    })
-                    .await;
+                }
+            }
+
+            irc_msg::Command::Quit { .. } => {
+                if let Some(nick) = msg.nick() {
+                    println!("=== {0} quit", nick);
+                }
+            }
+
+            irc_msg::Command::Kick {
+                ref channel,
+                ref nick,
+                ..
+            } => {
+                println!("=== {0} was kicked from {1}", nick, channel);
+            }
+
+            irc_msg::Command::Nick { nick: ref new_nick } => {
+                if let Some(old_nick) = msg.nick() {
+                    println!("=== {0} is now known as {1}", old_nick, new_nick);
                 }
             }
 
             irc_msg::Command::Numeric {
-                code: 353,
-                trailing: Some(ref new_names_list),
+                code: Numeric::RplEndOfNames,
                 ..
             } => {
-                let new_names = &mut new_names_list
-                    .split_ascii_whitespace()
-                    .filter(|s| *s != ctx.client.nick)
-                    .map(str::to_owned)
-                    .collect();
-                ctx.with_state(|state| {
-                    state.names.append(new_names);
-                })
-                .await;
+                if let Some(channel) = msg.reply().and_then(|r| r.channel()) {
+                    let names: Vec<String> = ctx
+                        .members(channel)
+                        .into_iter()
+                        .map(|m| format!("{}{}", m.prefixes, m.nick))
+                        .collect();
+                    println!("=== {0}: {1}", channel, names.join(" "));
+                }
             }
             _ => (),
         }
@@ -12,11 +12,13 @@ impl handler::Handler for ReplyHandler {
             ref message,
             ..
         } = msg.command
-            && message
-                .to_ascii_lowercase()
-                .contains(ctx.client.nick.to_lowercase().as_str())
+            && ctx
+                .client
+                .casemapping()
+                .fold(message)
+                .contains(&ctx.client.casemapping().fold(&ctx.client.nick()))
         {
-            let reply = format!("where is {0}, where is {0}", ctx.client.nick);
+            let reply = format!("where is {0}, where is {0}", ctx.client.nick());
             let _ = ctx.client.privmsg(reply_to, &reply).await;
         }
 
@@ -1,6 +1,8 @@
 use std::ops::ControlFlow;
 
+use crate::irc_core::casemap::CaseMapping;
 use crate::irc_core::handler::{Context, PrivmsgHandler};
+use crate::irc_core::prefix::Prefix;
 use sqlx::{Pool, Result as SqlxResult, Sqlite};
 use tracing::info;
 
@@ -16,11 +18,11 @@ impl PrivmsgHandler for RumorsHandler {
     async fn handle_privmsg(
         &self,
         ctx: &Context,
-        source: &str,
+        source: &Prefix,
         channel: &str,
         message: &str,
     ) -> ControlFlow<()> {
-        let stripped = match strip_bot_prefix(&self.bot_name, message) {
+        let stripped = match strip_bot_prefix(ctx.client.casemapping(), &self.bot_name, message) {
             Some(s) => s,
             None => return ControlFlow::Continue(()),
         };
@@ -52,15 +54,21 @@ impl PrivmsgHandler for RumorsHandler {
             return ControlFlow::Break(());
         }
 
-        if !source.is_empty() {
+        if let Some(nick) = source.nick() {
             // Store the rumor only if it has a source and a message
-            let _ = self.store_rumor(source, channel, stripped).await;
+            let _ = self.store_rumor(nick, channel, stripped).await;
             let _ = ctx.client.privmsg(channel, "Good to know!").await;
             return ControlFlow::Break(());
         }
 
         ControlFlow::Continue(())
     }
+
+    async fn on_shutdown(&self, _ctx: &Context) {
+        // Waits for queries still running on other connections, then closes the
+        // database cleanly.
+        self.db_pool.close().await;
+    }
 }
 
 impl RumorsHandler {
@@ -143,17 +151,10 @@ impl RumorsHandler {
 }
 
 /// Strips the bot's name prefix from a message; returns None if bot_name is not present.
-fn strip_bot_prefix<'a>(bot_name: &str, message: &'a str) -> Option<&'a str> {
-    let lowered = message.to_lowercase();
-    let bot_name_lower = bot_name.to_lowercase();
-
-    if lowered.starts_with(&format!("{},", bot_name_lower))
-        || lowered.starts_with(&format!("{}:", bot_name_lower))
-    {
-        Some(message[bot_name.len() + 1..].trim())
-    } else {
-        None
-    }
+fn strip_bot_prefix<'a>(mapping: CaseMapping, bot_name: &str, message: &'a str) -> Option<&'a str> {
+    let rest = mapping.strip_prefix(message, bot_name)?;
+    let rest = rest.strip_prefix(',').or_else(|| rest.strip_prefix(':'))?;
+    Some(rest.trim())
 }
 
 // /// Returns the tail of a message after the first interrogative word, if any.
@@ -191,14 +192,17 @@ mod tests {
     fn test_strip_bot_prefix() {
         let bot_name = "RumorBot";
         assert_eq!(
-            strip_bot_prefix(bot_name, "RumorBot, tell me a rumor"),
+            strip_bot_prefix(CaseMapping::Rfc1459, bot_name, "RumorBot, tell me a rumor"),
             Some("tell me a rumor")
         );
         assert_eq!(
-            strip_bot_prefix(bot_name, "RumorBot: what's the news?"),
+            strip_bot_prefix(CaseMapping::Rfc1459, bot_name, "RumorBot: what's the news?"),
             Some("what's the news?")
         );
-        assert_eq!(strip_bot_prefix(bot_name, "Hello RumorBot"), None);
+        assert_eq!(
+            strip_bot_prefix(CaseMapping::Rfc1459, bot_name, "Hello RumorBot"),
+            None
+        );
     }
 
     // #[test]
@@ -1,6 +1,10 @@
 use std::{collections::HashMap, ops::ControlFlow};
 
+use crate::irc_core::batch::Batch;
+use crate::irc_core::casemap::Nick;
 use crate::irc_core::handler::{Context, PrivmsgHandler};
+use crate::irc_core::irc_msg::Command;
+use crate::irc_core::prefix::Prefix;
 
 pub struct ScoreHandler;
 
@@ -9,7 +13,7 @@ impl PrivmsgHandler for ScoreHandler {
     async fn handle_privmsg(
         &self,
         ctx: &Context,
-        _source: &str,
+        _source: &Prefix,
         channel: &str,
         message: &str,
     ) -> ControlFlow<()> {
@@ -24,8 +28,9 @@ impl PrivmsgHandler for ScoreHandler {
         let delta: Option<(&str, i32)> = parse_score_delta(message);
 
         if let Some((nick, d)) = delta {
+            let key = Nick::new(nick, ctx.client.casemapping());
             let new_score = ctx
-                .with_state(|state| ScoreHandler::add_to_score(&mut state.scores, nick, d))
+                .with_state(|state| ScoreHandler::add_to_score(&mut state.scores, key, d))
                 .await;
             let response = format!("{nick}'s score is now {new_score}");
             let _ = ctx.client.privmsg(channel, &response).await;
@@ -34,6 +39,26 @@ impl PrivmsgHandler for ScoreHandler {
 
         ControlFlow::Continue(())
     }
+
+    /// Counts votes cast while we were away, without announcing them.
+    async fn on_history(&self, ctx: &Context, batch: &Batch) {
+        for msg in &batch.messages {
+            let Command::Privmsg { reply_to, message } = &msg.command else {
+                continue;
+            };
+            let Some((nick, d)) = parse_score_delta(message) else {
+                continue;
+            };
+            let mapping = ctx.client.casemapping();
+            let key = Nick::new(nick, mapping);
+            ctx.with_state(|state| {
+                if state.channels.iter().any(|c| mapping.eq(reply_to, c)) {
+                    ScoreHandler::add_to_score(&mut state.scores, key, d);
+                }
+            })
+            .await;
+        }
+    }
 }
 
 fn parse_score_delta(message: &str) -> Option<(&str, i32)> {
@@ -56,9 +81,9 @@ fn parse_score_delta(message: &str) -> Option<(&str, i32)> {
 }
 
 impl ScoreHandler {
-    pub fn add_to_score(scores: &mut HashMap<String, i32>, nick: &str, d: i32) -> i32 {
+    pub fn add_to_score(scores: &mut HashMap<Nick, i32>, nick: Nick, d: i32) -> i32 {
         *scores
-            .entry(nick.to_string())
+            .entry(nick)
             .and_modify(|it| *it += d)
             .or_insert_with(|| d)
     }
@@ -68,6 +93,11 @@ impl ScoreHandler {
 mod tests {
 
     use super::*;
+    use crate::irc_core::casemap::CaseMapping;
+
+    fn nick(name: &str) -> Nick {
+        Nick::new(name, CaseMapping::Rfc1459)
+    }
 
     #[test]
     fn test_parse_score_delta() {
@@ -89,20 +119,30 @@ mod tests {
 
     #[test]
     fn test_add_to_score() {
-        let mut scores: HashMap<String, i32> = HashMap::new();
-        scores.insert("botty".into(), 1);
-        scores.insert("thumbkin".into(), -1);
+        let mut scores: HashMap<Nick, i32> = HashMap::new();
+        scores.insert(nick("botty"), 1);
+        scores.insert(nick("thumbkin"), -1);
 
-        ScoreHandler::add_to_score(&mut scores, "botty", 1);
-        let new_score = scores.get("botty").copied();
+        ScoreHandler::add_to_score(&mut scores, nick("botty"), 1);
+        let new_score = scores.get(&nick("botty")).copied();
         assert_eq!(Some(2), new_score);
 
-        ScoreHandler::add_to_score(&mut scores, "thumbkin", -1);
-        let new_score = scores.get("thumbkin").copied();
+        ScoreHandler::add_to_score(&mut scores, nick("thumbkin"), -1);
+        let new_score = scores.get(&nick("thumbkin")).copied();
         assert_eq!(Some(-2), new_score);
 
-        ScoreHandler::add_to_score(&mut scores, "beelzebub", 1);
-        let new_score = scores.get("beelzebub").copied();
+        ScoreHandler::add_to_score(&mut scores, nick("beelzebub"), 1);
+        let new_score = scores.get(&nick("beelzebub")).copied();
         assert_eq!(Some(1), new_score);
     }
+
+    #[test]
+    fn test_add_to_score_ignores_case() {
+        let mut scores: HashMap<Nick, i32> = HashMap::new();
+        ScoreHandler::add_to_score(&mut scores, nick("Alice"), 1);
+        let new_score = ScoreHandler::add_to_score(&mut scores, nick("alice"), 1);
+
+        assert_eq!(new_score, 2);
+        assert_eq!(scores.len(), 1);
+    }
 }
@@ -1,6 +1,11 @@
 use std::{collections::HashMap, ops::ControlFlow};
 
+use irc_core::batch::Batch;
+use irc_core::casemap::Nick;
 use irc_core::handler::{self, PrivmsgHandler};
+use irc_core::irc_msg::Command;
+use irc_core::prefix::Prefix;
+use irc_core::users::UserEvent;
 
 use tracing::info;
 
@@ -11,7 +16,7 @@ impl PrivmsgHandler for SeenHandler {
     async fn handle_privmsg(
         &self,
         ctx: &handler::Context,
-        source: &str,
+        source: &Prefix,
         channel: &str,
         message: &str,
     ) -> ControlFlow<()> {
@@ -22,14 +27,17 @@ impl PrivmsgHandler for SeenHandler {
             return ControlFlow::Continue(());
         }
 
-        let query = format!("{},", ctx.client.nick);
-        if message.to_ascii_lowercase().starts_with(&query) {
-            let parts: Vec<&str> = message.split_whitespace().collect();
-            if parts.len() >= 3 && parts[1].to_lowercase() == "seen" {
-                let target_nick = parts[2];
+        let mapping = ctx.client.casemapping();
+        if let Some(query) = mapping
+            .strip_prefix(message, &ctx.client.nick())
+            .and_then(|rest| rest.strip_prefix(','))
+        {
+            let parts: Vec<&str> = query.split_whitespace().collect();
+            if parts.len() >= 2 && parts[0].eq_ignore_ascii_case("seen") {
+                let target_nick = Nick::new(parts[1], mapping);
 
                 let response = {
-                    ctx.with_state(|state| format_seen_response(state, target_nick))
+                    ctx.with_state(|state| format_seen_response(state, &target_nick))
                         .await
                 };
 
@@ -38,27 +46,85 @@ impl PrivmsgHandler for SeenHandler {
             return ControlFlow::Continue(());
         }
 
-        if !source.is_empty() {
-            let now = chrono::Local::now();
-            ctx.with_state(|state| {
-                update_seen(&mut state.seen, source, message, now);
-            })
-            .await;
+        if let Some(nick) = source.nick() {
+            record(ctx, nick, format!("saying: {message}")).await;
         }
 
         ControlFlow::Continue(())
     }
+
+    async fn on_user_event(&self, ctx: &handler::Context, event: &UserEvent) {
+        match event {
+            UserEvent::Joined { nick, channel } => {
+                record(ctx, nick, format!("joining {channel}")).await
+            }
+            UserEvent::Parted { nick, channel, .. } => {
+                record(ctx, nick, format!("leaving {channel}")).await
+            }
+            UserEvent::Kicked { nick, channel, .. } => {
+                record(ctx, nick, format!("being kicked from {channel}")).await
+            }
+            UserEvent::Quit { nick, message, .. } => {
+                let activity = match message {
+                    Some(message) => format!("quitting: {message}"),
+                    None => "quitting".to_owned(),
+                };
+                record(ctx, nick, activity).await
+            }
+            UserEvent::NickChanged { old, new, .. } => {
+                record(ctx, old, format!("changing nick to {new}")).await;
+                record(ctx, new, format!("changing nick from {old}")).await;
+            }
+            UserEvent::HostChanged { .. } => {}
+        }
+    }
+
+    /// Catches up on what people said while we were away, as of when they said it.
+    async fn on_history(&self, ctx: &handler::Context, batch: &Batch) {
+        let mapping = ctx.client.casemapping();
+        for msg in &batch.messages {
+            if let Command::Privmsg { reply_to, message } = &msg.command
+                && let Some(nick) = msg.nick()
+                && ctx
+                    .with_state(|state| state.channels.iter().any(|c| mapping.eq(reply_to, c)))
+                    .await
+            {
+                record_at(ctx, nick, format!("saying: {message}"), msg.meta.ts).await;
+            }
+        }
+    }
+}
+
+/// Remembers what `nick` was last doing, unless it's us.
+async fn record(ctx: &handler::Context, nick: &str, activity: String) {
+    record_at(ctx, nick, activity, chrono::Local::now()).await
+}
+
+async fn record_at(
+    ctx: &handler::Context,
+    nick: &str,
+    activity: String,
+    when: chrono::DateTime<chrono::Local>,
+) {
+    let mapping = ctx.client.casemapping();
+    if mapping.eq(nick, &ctx.client.nick()) {
+        return;
+    }
+    ctx.with_state(|state| {
+        update_seen(&mut state.seen, Nick::new(nick, mapping), &activity, when);
+    })
+    .await;
 }
 
-fn format_seen_response(state: &handler::State, target_nick: &str) -> String {
+fn format_seen_response(state: &handler::State, target_nick: &Nick) -> String {
     if let Some(info) = state.seen.get(target_nick) {
         let human_time = chrono_humanize::HumanTime::from(info.last_seen);
         info!(
-            "Saw `{target_nick}` at `{}` saying `{}`",
+            "Saw `{target_nick}` at `{}` {}",
             info.last_seen, info.message
         );
         format!(
-            "{} was last seen {} saying: {}",
+            "{} was last seen {} {}",
             target_nick, human_time, info.message,
         )
     } else {
@@ -67,19 +133,24 @@ fn format_seen_response(state: &handler::State, target_nick: &str) -> String {
     }
 }
 
+/// Records `message` as what `nick` did at `now`, unless we already know of something
+/// more recent, as we may when catching up on history.
 fn update_seen(
-    seen: &mut HashMap<String, handler::SeenInfo>,
-    source: &str,
+    seen: &mut HashMap<Nick, handler::SeenInfo>,
+    nick: Nick,
     message: &str,
     now: chrono::DateTime<chrono::Local>,
 ) {
-    seen.entry(source.to_string())
+    let name = nick.to_string();
+    seen.entry(nick)
         .and_modify(|info| {
-            info.last_seen = now;
-            info.message = message.to_string();
+            if now >= info.last_seen {
+                info.last_seen = now;
+                info.message = message.to_string();
+            }
         })
         .or_insert_with(|| handler::SeenInfo {
-            nick: source.to_string(),
+            nick: name,
             last_seen: now,
             message: message.to_string(),
         });
@@ -88,9 +159,14 @@ fn update_seen(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use irc_core::casemap::CaseMapping;
     use irc_core::handler::SeenInfo;
     use std::collections::HashMap;
 
+    fn nick(name: &str) -> Nick {
+        Nick::new(name, CaseMapping::Rfc1459)
+    }
+
     #[test]
     fn seen_response_when_user_not_seen() {
         let state = handler::State {
@@ -98,7 +174,7 @@ mod tests {
             ..Default::default()
         };
 
-        let resp = format_seen_response(&state, "alice");
+        let resp = format_seen_response(&state, &nick("alice"));
         assert_eq!(resp, "I have not seen alice");
     }
 
@@ -106,15 +182,15 @@ mod tests {
     fn seen_response_when_user_seen() {
         let mut state = handler::State::default();
         state.seen.insert(
-            "alice".to_string(),
+            nick("alice"),
             SeenInfo {
                 nick: "alice".to_string(),
                 last_seen: chrono::Local::now(),
-                message: "hello world".to_string(),
+                message: "saying: hello world".to_string(),
             },
         );
 
-        let resp = format_seen_response(&state, "alice");
+        let resp = format_seen_response(&state, &nick("alice"));
         assert!(
             resp.contains("alice was last seen"),
             "response was: {resp:?}"
@@ -124,4 +200,36 @@ mod tests {
             "response was: {resp:?}"
         );
     }
+
+    #[test]
+    fn seen_is_keyed_by_nick() {
+        let mut state = handler::State::default();
+        let source = Prefix::parse("alice!~a@example.org");
+        update_seen(
+            &mut state.seen,
+            nick(source.nick().unwrap()),
+            "saying: hi",
+            chrono::Local::now(),
+        );
+
+        let resp = format_seen_response(&state, &nick("ALICE"));
+        assert!(resp.contains("saying: hi"), "response was: {resp:?}");
+    }
+
+    #[test]
+    fn history_does_not_replace_newer_activity() {
+        let mut seen = HashMap::new();
+        let now = chrono::Local::now();
+        update_seen(&mut seen, nick("alice"), "leaving #rust", now);
+        update_seen(
+            &mut seen,
+            nick("alice"),
+            "saying: earlier",
+            now - chrono::Duration::minutes(5),
+        );
+        assert_eq!(seen[&nick("alice")].message, "leaving #rust");
+
+        update_seen(&mut seen, nick("bob"), "saying: earlier", now);
+        assert_eq!(seen[&nick("bob")].message, "saying: earlier");
+    }
 }
@@ -2,7 +2,7 @@ use std::ops::ControlFlow;
 
 use tracing::info;
 
-use crate::irc_core::{handler, irc_msg};
+use crate::irc_core::{handler, irc_msg, numeric::Numeric};
 
 pub struct WelcomeHandler;
 
@@ -12,9 +12,15 @@ impl handler::Handler for WelcomeHandler {
         if let irc_msg::Command::Numeric { code, .. } = msg.command {
             // End of MOTD, join a channel
             match code {
-                376 | 422 => {
+                Numeric::RplEndOfMotd | Numeric::ErrNoMotd => {
                     let channels = ctx.with_state(|state| state.channels.clone()).await;
+                    // After a reconnect the client rejoins the channels it was in itself.
+                    let mapping = ctx.client.casemapping();
+                    let rejoined = ctx.client.channels();
                     for channel in channels {
+                        if rejoined.iter().any(|c| mapping.eq(c, &channel)) {
+                            continue;
+                        }
                         let _ = ctx.client.join(&channel).await;
                         info!("Joined channel {}", channel);
                     }
@@ -0,0 +1,67 @@
+//! Owned versus borrowed parsing over a mix of typical server traffic.
+//!
+//! `owned` is `Msg::parse`, the parser every incoming line goes through and the
+//! baseline here; `borrowed` is the zero-copy `MsgRef::parse`. Both use the same
+//! tokenizer, so this measures what allocating costs, not one grammar against another.
+//!
+//! Run with `cargo bench -p irc_core --bench parse`.
+
+use std::hint::black_box;
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use irc_core::irc_msg::Msg;
+use irc_core::msg_ref::MsgRef;
+
+const LINES: &[&str] = &[
+    "@time=2024-05-01T12:00:00.000Z;account=alice :alice!~alice@user/alice PRIVMSG #rust :has anyone tried the new borrow checker yet?",
+    ":bob!bob@203.0.113.7 PRIVMSG #rust :yes, it's great",
+    ":carol!~c@example.org JOIN #rust",
+    ":irc.example.net 353 botty = #rust :@alice +bob carol dave erin frank",
+    ":irc.example.net 005 botty CHANTYPES=# PREFIX=(ov)@+ NETWORK=Example CASEMAPPING=rfc1459 :are supported by this server",
+    "PING :irc.example.net",
+    "@msgid=abc\\:123;+draft/reply=xyz :dave!d@h NOTICE #rust :reminder\\sabout the meeting",
+    ":erin!e@h MODE #rust +o bob",
+];
+
+fn corpus() -> Vec<&'static str> {
+    LINES.iter().copied().cycle().take(10_000).collect()
+}
+
+fn parse(c: &mut Criterion) {
+    let lines = corpus();
+    let bytes: usize = lines.iter().map(|l| l.len()).sum();
+    let now = chrono::Local::now();
+
+    let mut group = c.benchmark_group("owned vs borrowed");
+    group.throughput(Throughput::Bytes(bytes as u64));
+
+    group.bench_function("owned: Msg::parse", |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(Msg::parse(black_box(line), now));
+            }
+        })
+    });
+    group.bench_function("borrowed: MsgRef::parse", |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(MsgRef::parse(black_box(line)));
+            }
+        })
+    });
+    // A log-replay style scan: look at every line, only build the few that matter.
+    group.bench_function("borrowed: MsgRef::parse + filter", |b| {
+        b.iter(|| {
+            lines
+                .iter()
+                .filter_map(|line| MsgRef::parse(line))
+                .filter(|msg| msg.command() == "JOIN")
+                .filter_map(|msg| msg.to_msg(now))
+                .count()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
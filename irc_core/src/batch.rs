@@ -0,0 +1,196 @@
+//! IRCv3 batches: messages the server brackets with `BATCH +ref` and `BATCH -ref` so
+//! that they can be handled together, such as the quits of a netsplit or a page of
+//! chat history.
+//!
+//! See <https://ircv3.net/specs/extensions/batch>.
+
+use std::collections::HashMap;
+
+use crate::irc_msg::{Command, Msg, Tags};
+
+/// A complete batch, delivered as [`Event::Batch`](crate::client::Event::Batch).
+#[derive(Debug, PartialEq)]
+pub struct Batch {
+    /// The batch type, e.g. `netsplit` or `chathistory`.
+    pub kind: String,
+    pub params: Vec<String>,
+    /// Tags on the `BATCH` line that opened it.
+    pub tags: Tags,
+    /// The messages in the batch in the order they arrived, including those of any
+    /// batches nested inside it.
+    pub messages: Vec<Msg>,
+}
+
+impl Batch {
+    /// Chat history for `target`, as returned by
+    /// [`Client::chathistory`](crate::client::Client::chathistory).
+    pub(crate) fn history(target: &str, messages: Vec<Msg>) -> Self {
+        Batch {
+            kind: "chathistory".into(),
+            params: vec![target.to_owned()],
+            tags: Tags::new(),
+            messages,
+        }
+    }
+
+    /// True for replayed history, as opposed to things happening now.
+    pub fn is_history(&self) -> bool {
+        matches!(
+            self.kind.as_str(),
+            "chathistory" | "draft/chathistory" | "znc.in/playback"
+        )
+    }
+
+    /// The channel or nick a history batch is about.
+    pub fn target(&self) -> Option<&str> {
+        self.params
+            .first()
+            .map(String::as_str)
+            .filter(|_| self.is_history())
+    }
+}
+
+/// What became of a message given to [`Batches::apply`].
+#[derive(Debug)]
+pub(crate) enum Grouped {
+    /// Not part of a batch.
+    Alone(Msg),
+    /// Kept until its batch ends.
+    Held,
+    /// The message ended a batch.
+    Complete(Batch),
+}
+
+/// Batches still open on this connection.
+#[derive(Debug, Default)]
+pub(crate) struct Batches {
+    /// Outermost batches, by reference.
+    open: HashMap<String, Batch>,
+    /// Every open reference, nested ones included, mapped to its outermost batch.
+    roots: HashMap<String, String>,
+}
+
+impl Batches {
+    pub fn apply(&mut self, msg: Msg) -> Grouped {
+        if let Command::Raw { command, args } = &msg.command
+            && command == "BATCH"
+            && let Some(reference) = args.first()
+        {
+            if let Some(reference) = reference.strip_prefix('+') {
+                let parent = msg.tag("batch").and_then(|b| self.roots.get(b)).cloned();
+                match parent {
+                    Some(root) => {
+                        self.roots.insert(reference.to_owned(), root);
+                    }
+                    None => {
+                        self.roots
+                            .insert(reference.to_owned(), reference.to_owned());
+                        self.open.insert(
+                            reference.to_owned(),
+                            Batch {
+                                kind: args.get(1).cloned().unwrap_or_default(),
+                                params: args.iter().skip(2).cloned().collect(),
+                                tags: msg.tags.clone(),
+                                messages: vec![],
+                            },
+                        );
+                    }
+                }
+                return Grouped::Held;
+            }
+            if let Some(reference) = reference.strip_prefix('-')
+                && self.roots.remove(reference).is_some()
+            {
+                return match self.open.remove(reference) {
+                    Some(batch) => {
+                        // In case a nested batch was never closed.
+                        self.roots.retain(|_, root| root != reference);
+                        Grouped::Complete(batch)
+                    }
+                    None => Grouped::Held,
+                };
+            }
+        }
+
+        let root = msg.tag("batch").and_then(|b| self.roots.get(b));
+        match root.and_then(|root| self.open.get_mut(root)) {
+            Some(batch) => {
+                batch.messages.push(msg);
+                Grouped::Held
+            }
+            None => Grouped::Alone(msg),
+        }
+    }
+
+    /// Drops unfinished batches, e.g. after the connection drops.
+    pub fn clear(&mut self) {
+        self.open.clear();
+        self.roots.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn apply(batches: &mut Batches, line: &str) -> Grouped {
+        batches.apply(Msg::parse(line, SystemTime::UNIX_EPOCH.into()).unwrap())
+    }
+
+    #[test]
+    fn groups_messages_until_the_batch_ends() {
+        let mut batches = Batches::default();
+        assert!(matches!(
+            apply(&mut batches, ":srv BATCH +ns netsplit irc.a irc.b"),
+            Grouped::Held
+        ));
+        assert!(matches!(
+            apply(&mut batches, "@batch=ns :alice!a@h QUIT :irc.a irc.b"),
+            Grouped::Held
+        ));
+        assert!(matches!(
+            apply(&mut batches, ":carol!c@h PRIVMSG #rust :meanwhile"),
+            Grouped::Alone(_)
+        ));
+        assert!(matches!(
+            apply(&mut batches, "@batch=ns :bob!b@h QUIT :irc.a irc.b"),
+            Grouped::Held
+        ));
+
+        let Grouped::Complete(batch) = apply(&mut batches, ":srv BATCH -ns") else {
+            panic!("batch didn't end");
+        };
+        assert_eq!(batch.kind, "netsplit");
+        assert_eq!(batch.params, ["irc.a", "irc.b"]);
+        assert_eq!(batch.messages.len(), 2);
+        assert!(!batch.is_history());
+        // Its reference can be reused now.
+        assert!(matches!(
+            apply(&mut batches, "@batch=ns :dave!d@h QUIT"),
+            Grouped::Alone(_)
+        ));
+    }
+
+    #[test]
+    fn nested_batches_are_folded_into_the_outermost() {
+        let mut batches = Batches::default();
+        for line in [
+            "@label=x :srv BATCH +outer labeled-response",
+            "@batch=outer :srv BATCH +inner chathistory #rust",
+            "@batch=inner :alice!a@h PRIVMSG #rust :one",
+            "@batch=inner :bob!b@h PRIVMSG #rust :two",
+            ":srv BATCH -inner",
+        ] {
+            assert!(matches!(apply(&mut batches, line), Grouped::Held), "{line}");
+        }
+
+        let Grouped::Complete(batch) = apply(&mut batches, ":srv BATCH -outer") else {
+            panic!("batch didn't end");
+        };
+        assert_eq!(batch.kind, "labeled-response");
+        assert_eq!(batch.tags.get("label").map(String::as_str), Some("x"));
+        assert_eq!(batch.messages.len(), 2);
+    }
+}
@@ -1,25 +1,54 @@
-use crate::client::Client;
-use crate::handler::{Handler, State};
+use crate::batch::Batch;
+use crate::client::{Client, Event};
+use crate::handler::{Context, Handler, State};
+use crate::history::{Cursor, HistorySelector, MessageRef};
+use crate::irc_msg::{Command, Msg};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tracing::{info, warn};
 
 pub struct Bot {
     handlers: Vec<Box<dyn Handler>>,
     state: Arc<Mutex<State>>,
     client: Client,
+    shutdown: ShutdownHandle,
+    quit_message: String,
+    shutdown_timeout: Duration,
+    handle_signals: bool,
+    catch_up: bool,
 }
 
 pub struct BotBuilder {
     handlers: Vec<Box<dyn Handler>>,
     state: Arc<Mutex<State>>,
+    quit_message: String,
+    shutdown_timeout: Duration,
+    handle_signals: bool,
+    catch_up: bool,
+}
+
+/// Stops a running [`Bot`] gracefully. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle(Arc<Notify>);
+
+impl ShutdownHandle {
+    /// Asks the bot to shut down. It finishes the handler it is running, if any,
+    /// then quits. Calling this before [`Bot::run`] makes it shut down right away.
+    pub fn shutdown(&self) {
+        self.0.notify_one();
+    }
+}
+
+impl Default for BotBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BotBuilder {
     pub fn new() -> Self {
-        Self {
-            handlers: vec![],
-            state: Arc::new(Mutex::new(State::default())),
-        }
+        Self::new_with_state(State::default())
     }
 
     pub fn with_handler<H: Handler + 'static>(mut self, h: H) -> Self {
@@ -31,35 +60,380 @@ impl BotBuilder {
         Self {
             handlers: vec![],
             state: Arc::new(Mutex::new(state)),
+            quit_message: "Shutting down".into(),
+            shutdown_timeout: Duration::from_secs(5),
+            handle_signals: true,
+            catch_up: true,
         }
     }
 
+    /// The message sent with QUIT on shutdown.
+    pub fn with_quit_message(mut self, message: impl Into<String>) -> Self {
+        self.quit_message = message.into();
+        self
+    }
+
+    /// How long shutdown may spend sending queued lines and waiting for the server
+    /// to acknowledge our QUIT.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Whether [`Bot::run`] shuts down on SIGINT (Ctrl-C) and SIGTERM. On by default;
+    /// turn it off if the application handles signals itself.
+    pub fn with_signal_handling(mut self, enabled: bool) -> Self {
+        self.handle_signals = enabled;
+        self
+    }
+
+    /// Whether to fetch what was said in a channel while we were away when we rejoin
+    /// it, for [`Handler::on_history`]. On by default; it needs the server to offer
+    /// `draft/chathistory`.
+    pub fn with_history_catch_up(mut self, enabled: bool) -> Self {
+        self.catch_up = enabled;
+        self
+    }
+
     pub fn build(self, client: Client) -> Bot {
         Bot {
             handlers: self.handlers,
             state: self.state,
             client,
+            shutdown: ShutdownHandle::default(),
+            quit_message: self.quit_message,
+            shutdown_timeout: self.shutdown_timeout,
+            handle_signals: self.handle_signals,
+            catch_up: self.catch_up,
         }
     }
 }
 
 impl Bot {
+    /// A handle that stops [`run`](Self::run) from another task.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Dispatches events to the handlers until the connection closes for good or
+    /// the bot is asked to shut down.
     pub async fn run(self) -> anyhow::Result<()> {
-        let ctx = crate::handler::Context {
+        let ctx = Context {
             client: self.client.clone(),
             state: self.state.clone(),
         };
 
-        while let Some(msg) = self.client.recv().await? {
-            for h in &self.handlers {
-                use std::ops::ControlFlow;
-                let flow = h.handle(&ctx, &msg).await;
-                if matches!(flow, ControlFlow::Break(())) {
+        let stop = async {
+            tokio::select! {
+                _ = self.shutdown.0.notified() => info!("shutdown requested"),
+                _ = termination_signal(), if self.handle_signals => info!("received signal"),
+            }
+        };
+        tokio::pin!(stop);
+
+        // What handlers have seen, so that replayed history isn't handled twice.
+        let mut cursor = Cursor::default();
+
+        let connected = loop {
+            // Only waiting for the next event is interrupted; a running handler always
+            // gets to finish, so e.g. a database write isn't cut off halfway.
+            let event = tokio::select! {
+                _ = &mut stop => break true,
+                event = self.client.next_event() => event?,
+            };
+            let Some(event) = event else {
+                break false;
+            };
+
+            match event {
+                Event::Message(msg) => {
+                    // Already handled as part of history we fetched.
+                    if cursor.is_repeat(&msg) {
+                        continue;
+                    }
+                    for h in &self.handlers {
+                        use std::ops::ControlFlow;
+                        let flow = h.handle(&ctx, &msg).await;
+                        if matches!(flow, ControlFlow::Break(())) {
+                            break;
+                        }
+                    }
+                    if self.catch_up {
+                        self.catch_up(&ctx, &msg, &mut cursor).await;
+                    }
+                    self.observe(&msg, &mut cursor);
+                }
+                Event::Batch(batch) if batch.is_history() => {
+                    self.dispatch_history(&ctx, *batch, &mut cursor).await;
+                }
+                Event::Batch(batch) => {
+                    for h in &self.handlers {
+                        h.on_batch(&ctx, &batch).await;
+                    }
+                    for msg in &batch.messages {
+                        self.observe(msg, &mut cursor);
+                    }
+                }
+                Event::User(event) => {
+                    for h in &self.handlers {
+                        h.on_user_event(&ctx, &event).await;
+                    }
+                }
+                Event::Connected => {
+                    for h in &self.handlers {
+                        h.on_connect(&ctx).await;
+                    }
+                }
+                Event::Disconnected => {
+                    for h in &self.handlers {
+                        h.on_disconnect(&ctx).await;
+                    }
+                }
+            }
+        };
+
+        for h in &self.handlers {
+            h.on_shutdown(&ctx).await;
+        }
+        if connected {
+            self.quit().await;
+        }
+        Ok(())
+    }
+
+    /// Fetches what was said in a channel while we were away, once we are back in it.
+    /// Only channels with messages we have seen before count as a return.
+    async fn catch_up(&self, ctx: &Context, msg: &Msg, cursor: &mut Cursor) {
+        let Command::Join { channel, .. } = &msg.command else {
+            return;
+        };
+        let mapping = self.client.casemapping();
+        let ours = msg
+            .nick()
+            .is_some_and(|nick| mapping.eq(nick, &self.client.nick()));
+        if !ours || !self.client.has_cap("draft/chathistory") {
+            return;
+        }
+        let Some(since) = cursor.since(channel, mapping) else {
+            return;
+        };
+        let selector = HistorySelector::After(MessageRef::Timestamp(since));
+        match self.client.chathistory(channel, selector).await {
+            Ok(batch) => self.dispatch_history(ctx, batch, cursor).await,
+            Err(e) => warn!("couldn't catch up on {}: {e:#}", channel),
+        }
+    }
+
+    /// Hands replayed history to the handlers, leaving out what they have seen.
+    async fn dispatch_history(&self, ctx: &Context, mut batch: Batch, cursor: &mut Cursor) {
+        let mapping = self.client.casemapping();
+        let target = batch.target().unwrap_or_default().to_owned();
+        batch
+            .messages
+            .retain(|msg| cursor.is_new(msg, &target, mapping));
+        if batch.messages.is_empty() {
+            return;
+        }
+        for msg in &batch.messages {
+            cursor.observe(msg, &target, mapping);
+        }
+        for h in &self.handlers {
+            h.on_batch(ctx, &batch).await;
+        }
+    }
+
+    fn observe(&self, msg: &Msg, cursor: &mut Cursor) {
+        if let Some(target) = msg.channel() {
+            cursor.observe(msg, &target, self.client.casemapping());
+        }
+    }
+
+    /// Sends what's still queued, then quits and waits for the server to close the
+    /// connection, all within the shutdown timeout. While reconnecting nothing would
+    /// drain the queue, so the QUIT just stops the reconnect.
+    async fn quit(&self) {
+        let deadline = tokio::time::Instant::now() + self.shutdown_timeout;
+        if self.client.is_connected() && !self.client.flush(self.shutdown_timeout).await {
+            warn!(
+                "shutting down with {} lines unsent",
+                self.client.queue_depth()
+            );
+        }
+        if self.client.quit(Some(&self.quit_message)).await.is_err() {
+            // Already disconnected for good.
+            return;
+        }
+
+        let closed = async { while let Ok(Some(_)) = self.client.next_event().await {} };
+        if tokio::time::timeout_at(deadline, closed).await.is_err() {
+            warn!("server did not close the connection after QUIT");
+        }
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM (just Ctrl-C off Unix). A signal we can't listen
+/// for never fires, rather than shutting the bot down straight away.
+async fn termination_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("can't listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let term = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
+            }
+            Err(e) => {
+                warn!("can't listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let term = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = term => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::ControlFlow;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::client::ClientBuilder;
+    use crate::flood::FloodControl;
+    use crate::irc_msg::Msg;
+    use crate::reconnect::ReconnectPolicy;
+    use crate::testing::accept_and_register;
+
+    struct SaveOnShutdown(Arc<AtomicBool>);
+
+    #[async_trait::async_trait]
+    impl Handler for SaveOnShutdown {
+        async fn handle(&self, _ctx: &Context, _msg: &Msg) -> ControlFlow<()> {
+            ControlFlow::Continue(())
+        }
+
+        async fn on_shutdown(&self, ctx: &Context) {
+            for i in 1..=3 {
+                ctx.client
+                    .privmsg("#rust", &format!("saved {i}"))
+                    .await
+                    .unwrap();
+            }
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_queue_before_quit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut lines, _write) = accept_and_register(&listener).await;
+            let mut received = vec![];
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if line == "CAP END" {
+                    continue;
+                }
+                let quit = line.starts_with("QUIT");
+                received.push(line);
+                if quit {
                     break;
                 }
             }
+            received
+        });
+
+        // Paced so that QUIT, which skips the queue, would overtake unflushed lines.
+        let flood = FloodControl::new()
+            .with_burst(1)
+            .with_refill_interval(Duration::from_millis(20));
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_flood_control(flood)
+            .connect()
+            .await
+            .unwrap();
+
+        let saved = Arc::new(AtomicBool::new(false));
+        let bot = BotBuilder::new()
+            .with_handler(SaveOnShutdown(saved.clone()))
+            .with_quit_message("bye")
+            .with_signal_handling(false)
+            .build(client);
+        bot.shutdown_handle().shutdown();
+        tokio::time::timeout(Duration::from_secs(5), bot.run())
+            .await
+            .expect("shutdown hung")
+            .unwrap();
+
+        assert!(saved.load(Ordering::Relaxed));
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "PRIVMSG #rust :saved 1",
+                "PRIVMSG #rust :saved 2",
+                "PRIVMSG #rust :saved 3",
+                "QUIT :bye"
+            ]
+        );
+    }
+
+    struct NotifyOnDisconnect(Arc<Notify>);
+
+    #[async_trait::async_trait]
+    impl Handler for NotifyOnDisconnect {
+        async fn handle(&self, _ctx: &Context, _msg: &Msg) -> ControlFlow<()> {
+            ControlFlow::Continue(())
         }
 
-        Ok(())
+        async fn on_disconnect(&self, _ctx: &Context) {
+            self.0.notify_one();
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_during_backoff_stops_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let connect = ClientBuilder::new(addr, "botty", "bot")
+            .with_reconnect(ReconnectPolicy::new().with_initial_delay(Duration::from_millis(300)))
+            .connect();
+        let (client, connection) = tokio::join!(connect, accept_and_register(&listener));
+
+        let disconnected = Arc::new(Notify::new());
+        let bot = BotBuilder::new()
+            .with_handler(NotifyOnDisconnect(disconnected.clone()))
+            .with_signal_handling(false)
+            .with_shutdown_timeout(Duration::from_secs(10))
+            .build(client.unwrap());
+        let shutdown = bot.shutdown_handle();
+        let run = tokio::spawn(bot.run());
+
+        drop(connection);
+        disconnected.notified().await;
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(2), run)
+            .await
+            .expect("shutdown waited for the reconnect")
+            .unwrap()
+            .unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), listener.accept())
+                .await
+                .is_err(),
+            "reconnected during shutdown"
+        );
     }
 }
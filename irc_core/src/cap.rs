@@ -0,0 +1,305 @@
+//! IRCv3 capability negotiation (`CAP`).
+//!
+//! See <https://ircv3.net/specs/extensions/capability-negotiation>.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::irc_msg::{Command, Msg};
+
+/// The CAP protocol version we advertise with `CAP LS`.
+pub const CAP_VERSION: &str = "302";
+
+/// Capabilities the server has advertised, and the subset it has acknowledged.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Capabilities {
+    advertised: BTreeMap<String, String>,
+    enabled: BTreeSet<String>,
+}
+
+impl Capabilities {
+    /// True if the server acknowledged `name` and has not since removed it.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.contains(name)
+    }
+
+    /// True if the server offers `name`, whether or not we requested it.
+    pub fn is_advertised(&self, name: &str) -> bool {
+        self.advertised.contains_key(name)
+    }
+
+    /// The value advertised alongside `name`, e.g. `PLAIN,EXTERNAL` for `sasl`.
+    /// Capabilities advertised without a value have an empty one.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.advertised.get(name).map(String::as_str)
+    }
+
+    /// Names of all currently enabled capabilities.
+    pub fn enabled(&self) -> impl Iterator<Item = &str> {
+        self.enabled.iter().map(String::as_str)
+    }
+
+    pub(crate) fn advertise(&mut self, name: &str, value: Option<&str>) {
+        self.advertised
+            .insert(name.to_owned(), value.unwrap_or_default().to_owned());
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) {
+        self.advertised.remove(name);
+        self.enabled.remove(name);
+    }
+
+    /// Applies an acknowledged capability. A leading `-` means it was disabled.
+    pub(crate) fn ack(&mut self, name: &str) {
+        match name.strip_prefix('-') {
+            Some(disabled) => {
+                self.enabled.remove(disabled);
+            }
+            None => {
+                self.enabled.insert(name.to_owned());
+            }
+        }
+    }
+}
+
+/// A server `CAP` reply, borrowed from the parsed message.
+#[derive(Debug, PartialEq)]
+pub(crate) struct CapReply<'a> {
+    pub subcommand: &'a str,
+    /// Set on all but the last line of a multi-line `LS` or `LIST` reply.
+    pub more: bool,
+    /// Capability names and their optional `=value`.
+    pub caps: Vec<(&'a str, Option<&'a str>)>,
+}
+
+impl<'a> CapReply<'a> {
+    pub fn from_msg(msg: &'a Msg) -> Option<Self> {
+        let Command::Cap {
+            target: Some(_),
+            subcommand,
+            more,
+            caps,
+        } = &msg.command
+        else {
+            return None;
+        };
+
+        let caps = caps
+            .as_deref()
+            .unwrap_or_default()
+            .split_ascii_whitespace()
+            .map(|c| match c.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (c, None),
+            })
+            .collect();
+
+        Some(CapReply {
+            subcommand,
+            more: *more,
+            caps,
+        })
+    }
+}
+
+/// Drives `CAP LS` / `CAP REQ` during registration.
+///
+/// The negotiation never sends `CAP END` itself: registration decides when it is safe
+/// to end, since other steps (e.g. SASL) may still need to run.
+#[derive(Debug)]
+pub(crate) struct Negotiation {
+    wanted: Vec<String>,
+    listed: bool,
+    pending: usize,
+}
+
+impl Negotiation {
+    pub fn new(wanted: &[String]) -> Self {
+        Self {
+            wanted: wanted.to_vec(),
+            listed: false,
+            pending: 0,
+        }
+    }
+
+    /// True once the server's capability list has been received and every `REQ` we
+    /// sent has been answered.
+    pub fn is_settled(&self) -> bool {
+        self.listed && self.pending == 0
+    }
+
+    /// Handles a server `CAP` reply, returning any lines to send in response.
+    pub fn on_reply(&mut self, caps: &mut Capabilities, reply: &CapReply<'_>) -> Vec<String> {
+        match reply.subcommand {
+            "LS" => {
+                for (name, value) in &reply.caps {
+                    caps.advertise(name, *value);
+                }
+                if reply.more || self.listed {
+                    return vec![];
+                }
+                self.listed = true;
+                self.request(caps)
+            }
+            "ACK" | "NAK" => {
+                if reply.subcommand == "ACK" {
+                    for (name, _) in &reply.caps {
+                        caps.ack(name);
+                    }
+                }
+                self.pending = self.pending.saturating_sub(1);
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// The server does not support CAP at all, so there is nothing to wait for.
+    pub fn abandon(&mut self) {
+        self.listed = true;
+        self.pending = 0;
+    }
+
+    fn request(&mut self, caps: &Capabilities) -> Vec<String> {
+        let lines = request_lines(&self.wanted, caps);
+        self.pending += lines.len();
+        lines
+    }
+}
+
+/// Builds `CAP REQ` lines for every wanted capability that is advertised but not yet
+/// enabled.
+pub(crate) fn request_lines(wanted: &[String], caps: &Capabilities) -> Vec<String> {
+    // Keep each REQ comfortably inside a single 512-byte line.
+    const MAX_REQ_LEN: usize = 400;
+
+    let mut lines = vec![];
+    let mut current = String::new();
+    for name in wanted
+        .iter()
+        .filter(|w| caps.is_advertised(w) && !caps.is_enabled(w))
+    {
+        if !current.is_empty() && current.len() + name.len() + 1 > MAX_REQ_LEN {
+            lines.push(format!("CAP REQ :{}", current));
+            current.clear();
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(name);
+    }
+    if !current.is_empty() {
+        lines.push(format!("CAP REQ :{}", current));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn parse(line: &str) -> Msg {
+        Msg::parse(line, SystemTime::UNIX_EPOCH.into()).unwrap()
+    }
+
+    fn wanted(caps: &[&str]) -> Vec<String> {
+        caps.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn cap_reply_multiline_ls() {
+        let msg = parse(":irc.example.com CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL");
+        let reply = CapReply::from_msg(&msg).unwrap();
+
+        assert_eq!(
+            reply,
+            CapReply {
+                subcommand: "LS",
+                more: true,
+                caps: vec![("multi-prefix", None), ("sasl", Some("PLAIN,EXTERNAL"))],
+            }
+        );
+    }
+
+    #[test]
+    fn cap_reply_final_ls() {
+        let msg = parse(":irc.example.com CAP bot LS :server-time");
+        let reply = CapReply::from_msg(&msg).unwrap();
+
+        assert!(!reply.more);
+        assert_eq!(reply.caps, vec![("server-time", None)]);
+    }
+
+    #[test]
+    fn cap_reply_ignores_other_commands() {
+        let msg = parse(":irc.example.com NOTICE * :hello");
+        assert_eq!(CapReply::from_msg(&msg), None);
+    }
+
+    #[test]
+    fn negotiation_requests_only_advertised() {
+        let mut caps = Capabilities::default();
+        let mut neg = Negotiation::new(&wanted(&["server-time", "away-notify", "account-tag"]));
+
+        let first = parse(":irc.example.com CAP * LS * :server-time multi-prefix");
+        let sent = neg.on_reply(&mut caps, &CapReply::from_msg(&first).unwrap());
+        assert!(sent.is_empty());
+        assert!(!neg.is_settled());
+
+        let last = parse(":irc.example.com CAP * LS :account-tag sasl=PLAIN");
+        let sent = neg.on_reply(&mut caps, &CapReply::from_msg(&last).unwrap());
+        assert_eq!(sent, vec!["CAP REQ :server-time account-tag"]);
+        assert!(!neg.is_settled());
+        assert_eq!(caps.value("sasl"), Some("PLAIN"));
+
+        let ack = parse(":irc.example.com CAP * ACK :server-time account-tag");
+        neg.on_reply(&mut caps, &CapReply::from_msg(&ack).unwrap());
+        assert!(neg.is_settled());
+        assert!(caps.is_enabled("server-time"));
+        assert!(caps.is_enabled("account-tag"));
+        assert!(!caps.is_enabled("away-notify"));
+        assert!(!caps.is_enabled("multi-prefix"));
+    }
+
+    #[test]
+    fn negotiation_settles_without_requests() {
+        let mut caps = Capabilities::default();
+        let mut neg = Negotiation::new(&wanted(&["server-time"]));
+
+        let ls = parse(":irc.example.com CAP * LS :multi-prefix");
+        let sent = neg.on_reply(&mut caps, &CapReply::from_msg(&ls).unwrap());
+        assert!(sent.is_empty());
+        assert!(neg.is_settled());
+    }
+
+    #[test]
+    fn negotiation_nak_settles() {
+        let mut caps = Capabilities::default();
+        let mut neg = Negotiation::new(&wanted(&["server-time"]));
+
+        let ls = parse(":irc.example.com CAP * LS :server-time");
+        neg.on_reply(&mut caps, &CapReply::from_msg(&ls).unwrap());
+        let nak = parse(":irc.example.com CAP * NAK :server-time");
+        neg.on_reply(&mut caps, &CapReply::from_msg(&nak).unwrap());
+
+        assert!(neg.is_settled());
+        assert!(!caps.is_enabled("server-time"));
+    }
+
+    #[test]
+    fn capabilities_ack_and_remove() {
+        let mut caps = Capabilities::default();
+        caps.advertise("away-notify", None);
+        caps.ack("away-notify");
+        assert!(caps.is_enabled("away-notify"));
+
+        caps.ack("-away-notify");
+        assert!(!caps.is_enabled("away-notify"));
+
+        caps.ack("away-notify");
+        caps.remove("away-notify");
+        assert!(!caps.is_enabled("away-notify"));
+        assert!(!caps.is_advertised("away-notify"));
+    }
+}
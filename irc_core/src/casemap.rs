@@ -0,0 +1,178 @@
+//! Case-insensitive nick and channel names, following the server's `CASEMAPPING`.
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// How the server folds nicks and channel names when comparing them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum CaseMapping {
+    /// Only `A-Z` fold to `a-z`.
+    Ascii,
+    /// ASCII, plus `[]\~` fold to `{}|^`. The default when the server doesn't say.
+    #[default]
+    Rfc1459,
+    /// ASCII, plus `[]\` fold to `{}|`.
+    Rfc1459Strict,
+}
+
+impl CaseMapping {
+    /// Parses a `CASEMAPPING` value. Unknown mappings return `None`.
+    pub fn from_token(value: &str) -> Option<Self> {
+        match value {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "rfc1459-strict" | "strict-rfc1459" => Some(CaseMapping::Rfc1459Strict),
+            _ => None,
+        }
+    }
+
+    pub fn fold_char(self, c: char) -> char {
+        match (self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (CaseMapping::Ascii, _) => c,
+            (_, '[') => '{',
+            (_, ']') => '}',
+            (_, '\\') => '|',
+            (CaseMapping::Rfc1459, '~') => '^',
+            _ => c,
+        }
+    }
+
+    /// Folds `name` to its canonical lower-case form.
+    pub fn fold(self, name: &str) -> String {
+        name.chars().map(|c| self.fold_char(c)).collect()
+    }
+
+    /// True if `a` and `b` are the same name under this mapping.
+    pub fn eq(self, a: &str, b: &str) -> bool {
+        a.chars()
+            .map(|c| self.fold_char(c))
+            .eq(b.chars().map(|c| self.fold_char(c)))
+    }
+
+    /// Strips `prefix` from the start of `text` if it matches under this mapping.
+    pub fn strip_prefix<'a>(self, text: &'a str, prefix: &str) -> Option<&'a str> {
+        let head = text.get(..prefix.len())?;
+        self.eq(head, prefix).then(|| &text[prefix.len()..])
+    }
+}
+
+macro_rules! identifier {
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        ///
+        /// Equality and hashing use the folded form, so the original spelling is kept
+        /// for display but never affects lookups.
+        #[derive(Debug, Clone)]
+        pub struct $name {
+            name: String,
+            folded: String,
+        }
+
+        impl $name {
+            pub fn new(name: impl Into<String>, mapping: CaseMapping) -> Self {
+                let name = name.into();
+                let folded = mapping.fold(&name);
+                Self { name, folded }
+            }
+
+            /// The name as it was spelled when first seen.
+            pub fn as_str(&self) -> &str {
+                &self.name
+            }
+
+            /// The case-folded name used for comparisons.
+            pub fn folded(&self) -> &str {
+                &self.folded
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.folded == other.folded
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl Hash for $name {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.folded.hash(state);
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.name)
+            }
+        }
+    };
+}
+
+identifier! {
+    /// A nick, compared according to the server's casemapping.
+    Nick
+}
+
+identifier! {
+    /// A channel name, compared according to the server's casemapping.
+    ChannelName
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn fold_per_mapping() {
+        assert_eq!(CaseMapping::Ascii.fold("Nick[]\\~"), "nick[]\\~");
+        assert_eq!(CaseMapping::Rfc1459.fold("Nick[]\\~"), "nick{}|^");
+        assert_eq!(CaseMapping::Rfc1459Strict.fold("Nick[]\\~"), "nick{}|~");
+        // Non-ASCII letters are never folded.
+        assert_eq!(CaseMapping::Rfc1459.fold("Ärger"), "Ärger");
+    }
+
+    #[test]
+    fn from_token() {
+        assert_eq!(
+            CaseMapping::from_token("strict-rfc1459"),
+            Some(CaseMapping::Rfc1459Strict)
+        );
+        assert_eq!(CaseMapping::from_token("rfc7613"), None);
+    }
+
+    #[test]
+    fn nick_eq_and_hash_follow_mapping() {
+        let mapping = CaseMapping::Rfc1459;
+        let mut scores = HashMap::new();
+        scores.insert(Nick::new("Alice[m]", mapping), 1);
+        *scores.entry(Nick::new("alice{M}", mapping)).or_insert(0) += 1;
+
+        assert_eq!(scores.len(), 1);
+        assert_eq!(scores[&Nick::new("ALICE[M]", mapping)], 2);
+        assert_eq!(scores.keys().next().unwrap().as_str(), "Alice[m]");
+
+        assert_ne!(
+            Nick::new("a[", CaseMapping::Ascii),
+            Nick::new("a{", CaseMapping::Ascii)
+        );
+    }
+
+    #[test]
+    fn channel_name_display_keeps_spelling() {
+        let channel = ChannelName::new("#Rust", CaseMapping::Ascii);
+        assert_eq!(channel, ChannelName::new("#rust", CaseMapping::Ascii));
+        assert_eq!(channel.to_string(), "#Rust");
+        assert_eq!(channel.folded(), "#rust");
+    }
+
+    #[test]
+    fn strip_prefix() {
+        let mapping = CaseMapping::Rfc1459;
+        assert_eq!(mapping.strip_prefix("BOTTY, hi", "botty"), Some(", hi"));
+        assert_eq!(mapping.strip_prefix("bot", "botty"), None);
+        assert_eq!(mapping.strip_prefix("bötty", "botty"), None);
+    }
+}
@@ -0,0 +1,505 @@
+//! Who is in the channels we are in, and what they look like.
+//!
+//! [`Channels`] follows JOIN, PART, KICK, QUIT, NICK, MODE and TOPIC along with the
+//! NAMES, topic and mode numerics. Membership prefixes and mode classes come from
+//! ISUPPORT, so the tracker needs the current [`ServerInfo`] for every message.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Local, TimeZone};
+
+use crate::casemap::CaseMapping;
+use crate::irc_msg::{Command, Msg};
+use crate::isupport::ServerInfo;
+use crate::numeric::Numeric;
+
+/// A user in a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub nick: String,
+    /// Membership modes, highest rank first, e.g. `ov`.
+    pub modes: String,
+    /// The prefixes for `modes`, in the same order, e.g. `@+`.
+    pub prefixes: String,
+}
+
+impl Member {
+    fn new(nick: &str) -> Self {
+        Self {
+            nick: nick.to_owned(),
+            modes: String::new(),
+            prefixes: String::new(),
+        }
+    }
+
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.contains(mode)
+    }
+
+    /// The highest-ranking prefix, as shown before the nick in NAMES.
+    pub fn prefix(&self) -> Option<char> {
+        self.prefixes.chars().next()
+    }
+
+    fn set_mode(&mut self, mode: char, info: &ServerInfo) {
+        if self.has_mode(mode) {
+            return;
+        }
+        let mut modes: Vec<(char, char)> = self.modes.chars().zip(self.prefixes.chars()).collect();
+        if let Some(prefix) = info.prefix_for_mode(mode) {
+            modes.push((mode, prefix));
+        }
+        let rank = |mode: char| info.prefix.iter().position(|(m, _)| *m == mode);
+        modes.sort_by_key(|(m, _)| rank(*m));
+        self.modes = modes.iter().map(|(m, _)| m).collect();
+        self.prefixes = modes.iter().map(|(_, p)| p).collect();
+    }
+
+    fn unset_mode(&mut self, mode: char) {
+        if let Some(i) = self.modes.chars().position(|m| m == mode) {
+            self.modes = self.modes.chars().filter(|m| *m != mode).collect();
+            self.prefixes = self
+                .prefixes
+                .chars()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, p)| p)
+                .collect();
+        }
+    }
+}
+
+/// A channel topic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topic {
+    pub text: String,
+    /// Who set it: a nick or `nick!user@host`, depending on the server.
+    pub set_by: Option<String>,
+    pub set_at: Option<DateTime<Local>>,
+}
+
+/// What we know about one channel we are in.
+#[derive(Debug, Clone)]
+pub struct Channel {
+    /// The name as the server spelled it when we joined.
+    pub name: String,
+    pub topic: Option<Topic>,
+    /// Channel modes with their parameter, if any, e.g. `n` or `l` → `50`. List modes
+    /// such as bans aren't tracked, and neither are membership modes, which are on
+    /// each [`Member`].
+    pub modes: BTreeMap<char, Option<String>>,
+    mapping: CaseMapping,
+    /// Folded nick → member.
+    members: HashMap<String, Member>,
+    /// Names received since the last RPL_ENDOFNAMES, which replace `members` once
+    /// the list is complete.
+    pending_names: Option<HashMap<String, Member>>,
+}
+
+impl Channel {
+    fn new(name: &str, mapping: CaseMapping) -> Self {
+        Self {
+            name: name.to_owned(),
+            topic: None,
+            modes: BTreeMap::new(),
+            mapping,
+            members: HashMap::new(),
+            pending_names: None,
+        }
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = &Member> {
+        self.members.values()
+    }
+
+    pub fn member(&self, nick: &str) -> Option<&Member> {
+        self.members.get(&self.mapping.fold(nick))
+    }
+
+    pub fn has_member(&self, nick: &str) -> bool {
+        self.member(nick).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    fn add(&mut self, nick: &str) {
+        self.members
+            .entry(self.mapping.fold(nick))
+            .or_insert_with(|| Member::new(nick));
+    }
+
+    fn remove(&mut self, nick: &str) {
+        self.members.remove(&self.mapping.fold(nick));
+    }
+
+    fn rename(&mut self, old: &str, new: &str) {
+        if let Some(mut member) = self.members.remove(&self.mapping.fold(old)) {
+            member.nick = new.to_owned();
+            self.members.insert(self.mapping.fold(new), member);
+        }
+    }
+
+    /// Applies a MODE change, or the full mode string from RPL_CHANNELMODEIS.
+    fn apply_modes(&mut self, modes: &str, args: &[String], info: &ServerInfo) {
+        let classes = &info.chanmodes;
+        let mut args = args.iter();
+        let mut adding = true;
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ if info.prefix_for_mode(mode).is_some() => {
+                    let Some(nick) = args.next() else { continue };
+                    let key = self.mapping.fold(nick);
+                    if let Some(member) = self.members.get_mut(&key) {
+                        if adding {
+                            member.set_mode(mode, info);
+                        } else {
+                            member.unset_mode(mode);
+                        }
+                    }
+                }
+                _ if classes.list.contains(mode) => {
+                    args.next();
+                }
+                _ if classes.always.contains(mode) || classes.on_set.contains(mode) => {
+                    let takes_arg = adding || classes.always.contains(mode);
+                    let arg = if takes_arg { args.next() } else { None };
+                    if adding {
+                        self.modes.insert(mode, arg.cloned());
+                    } else {
+                        self.modes.remove(&mode);
+                    }
+                }
+                // Type D, or unknown modes, which we assume take no parameter.
+                _ => {
+                    if adding {
+                        self.modes.insert(mode, None);
+                    } else {
+                        self.modes.remove(&mode);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The channels we are in, kept up to date from the messages we receive.
+#[derive(Debug, Clone, Default)]
+pub struct Channels {
+    mapping: CaseMapping,
+    /// Folded channel name → channel.
+    channels: HashMap<String, Channel>,
+}
+
+impl Channels {
+    pub fn get(&self, channel: &str) -> Option<&Channel> {
+        self.channels.get(&self.mapping.fold(channel))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.values()
+    }
+
+    /// The channels `nick` shares with us.
+    pub fn of(&self, nick: &str) -> impl Iterator<Item = &Channel> {
+        self.iter().filter(move |c| c.has_member(nick))
+    }
+
+    /// Forgets everything, e.g. when the connection drops.
+    pub fn clear(&mut self) {
+        self.channels.clear();
+    }
+
+    /// Updates the tracker from a message. `me` is our nick as it was before the
+    /// message arrived.
+    pub fn apply(&mut self, msg: &Msg, me: &str, info: &ServerInfo) {
+        self.mapping = info.casemapping;
+        let mapping = self.mapping;
+        let from = msg.nick();
+        let from_us = from.is_some_and(|nick| mapping.eq(nick, me));
+
+        match &msg.command {
+            Command::Join { channel, .. } => {
+                let Some(nick) = from else { return };
+                if from_us {
+                    self.channels
+                        .insert(mapping.fold(channel), Channel::new(channel, mapping));
+                }
+                if let Some(chan) = self.get_mut(channel) {
+                    chan.add(nick);
+                }
+            }
+            Command::Part { channel, .. } => {
+                if from_us {
+                    self.channels.remove(&mapping.fold(channel));
+                } else if let (Some(chan), Some(nick)) = (self.get_mut(channel), from) {
+                    chan.remove(nick);
+                }
+            }
+            Command::Kick { channel, nick, .. } => {
+                if mapping.eq(nick, me) {
+                    self.channels.remove(&mapping.fold(channel));
+                } else if let Some(chan) = self.get_mut(channel) {
+                    chan.remove(nick);
+                }
+            }
+            Command::Quit { .. } => {
+                if let Some(nick) = from {
+                    for chan in self.channels.values_mut() {
+                        chan.remove(nick);
+                    }
+                }
+            }
+            Command::Nick { nick: new } => {
+                if let Some(old) = from {
+                    for chan in self.channels.values_mut() {
+                        chan.rename(old, new);
+                    }
+                }
+            }
+            Command::Mode {
+                target,
+                modes: Some(modes),
+                args,
+            } => {
+                if let Some(chan) = self.get_mut(target) {
+                    chan.apply_modes(modes, args, info);
+                }
+            }
+            Command::Topic {
+                channel,
+                topic: Some(text),
+            } => {
+                if let Some(chan) = self.get_mut(channel) {
+                    chan.topic = (!text.is_empty()).then(|| Topic {
+                        text: text.clone(),
+                        set_by: msg.source.as_ref().map(ToString::to_string),
+                        set_at: Some(msg.meta.ts),
+                    });
+                }
+            }
+            Command::Numeric { .. } => self.apply_reply(msg, info),
+            _ => {}
+        }
+    }
+
+    fn apply_reply(&mut self, msg: &Msg, info: &ServerInfo) {
+        let Some(reply) = msg.reply() else { return };
+        let Some(chan) = reply.channel().and_then(|name| self.get_mut(name)) else {
+            return;
+        };
+
+        match reply.numeric {
+            Numeric::RplNamReply => {
+                let mapping = chan.mapping;
+                let pending = chan.pending_names.get_or_insert_with(HashMap::new);
+                for entry in reply.names() {
+                    // With multi-prefix every prefix is listed, and with
+                    // userhost-in-names the nick is followed by `!user@host`.
+                    let nick = entry.trim_start_matches(|c| info.mode_for_prefix(c).is_some());
+                    let nick = nick.split('!').next().unwrap_or(nick);
+                    let mut member = Member::new(nick);
+                    let prefixes = &entry[..entry.len() - nick.len()];
+                    for mode in prefixes.chars().filter_map(|p| info.mode_for_prefix(p)) {
+                        member.set_mode(mode, info);
+                    }
+                    pending.insert(mapping.fold(nick), member);
+                }
+            }
+            Numeric::RplEndOfNames => {
+                if let Some(members) = chan.pending_names.take() {
+                    chan.members = members;
+                }
+            }
+            Numeric::RplNoTopic => chan.topic = None,
+            Numeric::RplTopic => {
+                chan.topic = reply.topic().map(|text| Topic {
+                    text: text.to_owned(),
+                    set_by: None,
+                    set_at: None,
+                });
+            }
+            Numeric::RplTopicWhoTime => {
+                if let (Some(topic), Some((setter, time))) =
+                    (chan.topic.as_mut(), reply.topic_setter())
+                {
+                    topic.set_by = Some(setter.to_owned());
+                    topic.set_at = Local.timestamp_opt(time, 0).single();
+                }
+            }
+            Numeric::RplChannelModeIs => {
+                let Some(modes) = reply.param(2) else { return };
+                let args: Vec<String> = (3..)
+                    .map_while(|i| reply.param(i))
+                    .map(str::to_owned)
+                    .collect();
+                chan.modes.clear();
+                chan.apply_modes(modes, &args, info);
+            }
+            _ => {}
+        }
+    }
+
+    fn get_mut(&mut self, channel: &str) -> Option<&mut Channel> {
+        self.channels.get_mut(&self.mapping.fold(channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    fn feed(channels: &mut Channels, info: &ServerInfo, lines: &[&str]) {
+        for line in lines {
+            let msg = Msg::parse(line, SystemTime::UNIX_EPOCH.into()).unwrap();
+            channels.apply(&msg, "botty", info);
+        }
+    }
+
+    fn nicks(channel: &Channel) -> Vec<String> {
+        let mut nicks: Vec<String> = channel
+            .members()
+            .map(|m| format!("{}{}", m.prefixes, m.nick))
+            .collect();
+        nicks.sort();
+        nicks
+    }
+
+    fn joined(info: &ServerInfo) -> Channels {
+        let mut channels = Channels::default();
+        feed(
+            &mut channels,
+            info,
+            &[
+                ":botty!b@h JOIN #rust",
+                ":srv 353 botty = #rust :botty @+alice!a@h +bob carol",
+                ":srv 366 botty #rust :End of /NAMES list.",
+            ],
+        );
+        channels
+    }
+
+    #[test]
+    fn names_replace_members_at_end_of_names() {
+        let info = ServerInfo::default();
+        let mut channels = joined(&info);
+        let rust = channels.get("#RUST").unwrap();
+        assert_eq!(nicks(rust), ["+bob", "@+alice", "botty", "carol"]);
+        assert_eq!(rust.member("Alice").unwrap().prefix(), Some('@'));
+
+        // Asking again must not duplicate anyone, and the old list stays until the
+        // new one is complete.
+        feed(
+            &mut channels,
+            &info,
+            &[
+                ":srv 353 botty = #rust :botty alice",
+                ":srv 353 botty = #rust :dave",
+            ],
+        );
+        assert_eq!(channels.get("#rust").unwrap().len(), 4);
+        feed(&mut channels, &info, &[":srv 366 botty #rust :End"]);
+        assert_eq!(
+            nicks(channels.get("#rust").unwrap()),
+            ["alice", "botty", "dave"]
+        );
+    }
+
+    #[test]
+    fn follows_joins_parts_kicks_quits_and_nicks() {
+        let info = ServerInfo::default();
+        let mut channels = joined(&info);
+        feed(
+            &mut channels,
+            &info,
+            &[
+                ":botty!b@h JOIN #go",
+                ":alice!a@h JOIN #go",
+                ":dave!d@h JOIN #rust",
+                ":bob!b@h PART #rust :bye",
+                ":alice!a@h KICK #rust carol :no",
+                ":alice!a@h NICK :Alicia",
+                ":dave!d@h QUIT :gone",
+            ],
+        );
+        assert_eq!(nicks(channels.get("#rust").unwrap()), ["@+Alicia", "botty"]);
+        assert_eq!(nicks(channels.get("#go").unwrap()), ["Alicia", "botty"]);
+
+        let mut common: Vec<_> = channels.of("alicia").map(|c| c.name.as_str()).collect();
+        common.sort();
+        assert_eq!(common, ["#go", "#rust"]);
+
+        feed(
+            &mut channels,
+            &info,
+            &[":botty!b@h PART #go", ":alicia!a@h KICK #rust botty"],
+        );
+        assert_eq!(channels.iter().count(), 0);
+    }
+
+    #[test]
+    fn modes_follow_isupport() {
+        let mut info = ServerInfo::default();
+        info.apply("PREFIX=(qohv)~@%+");
+        info.apply("CHANMODES=beI,k,l,imnst");
+        let mut channels = joined(&info);
+        feed(
+            &mut channels,
+            &info,
+            &[
+                ":srv 324 botty #rust +nl 50",
+                ":alice!a@h MODE #rust +qkb-v bob secret *!*@spam carol",
+                ":alice!a@h MODE #rust +h-l+v bob carol",
+            ],
+        );
+        let rust = channels.get("#rust").unwrap();
+        let bob = rust.member("bob").unwrap();
+        assert_eq!((bob.modes.as_str(), bob.prefixes.as_str()), ("qhv", "~%+"));
+        assert!(bob.has_mode('h'));
+        assert_eq!(rust.member("carol").unwrap().prefix(), Some('+'));
+        assert_eq!(
+            rust.modes,
+            BTreeMap::from([('k', Some("secret".into())), ('n', None)])
+        );
+
+        feed(&mut channels, &info, &[":alice!a@h MODE #rust -qk bob *"]);
+        let rust = channels.get("#rust").unwrap();
+        assert_eq!(rust.member("bob").unwrap().prefixes, "%+");
+        assert_eq!(rust.modes, BTreeMap::from([('n', None)]));
+    }
+
+    #[test]
+    fn topic_and_setter() {
+        let info = ServerInfo::default();
+        let mut channels = joined(&info);
+        feed(
+            &mut channels,
+            &info,
+            &[
+                ":srv 332 botty #rust :all about rust",
+                ":srv 333 botty #rust alice!a@h 1700000000",
+            ],
+        );
+        let topic = channels.get("#rust").unwrap().topic.clone().unwrap();
+        assert_eq!(topic.text, "all about rust");
+        assert_eq!(topic.set_by.as_deref(), Some("alice!a@h"));
+        assert_eq!(topic.set_at.unwrap().timestamp(), 1_700_000_000);
+
+        feed(&mut channels, &info, &[":bob!b@h TOPIC #rust :crabs"]);
+        let topic = channels.get("#rust").unwrap().topic.clone().unwrap();
+        assert_eq!(topic.text, "crabs");
+        assert_eq!(topic.set_by.as_deref(), Some("bob!b@h"));
+
+        feed(&mut channels, &info, &[":bob!b@h TOPIC #rust :"]);
+        assert_eq!(channels.get("#rust").unwrap().topic, None);
+    }
+}
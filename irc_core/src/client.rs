@@ -1,60 +1,997 @@
-use anyhow::Context;
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
 use tokio::sync::{
-    Mutex,
+    Mutex, Notify,
     mpsc::{Receiver, Sender},
 };
+use tracing::{debug, warn};
+
+use crate::batch::{Batch, Batches, Grouped};
+use crate::cap::{self, CapReply, Capabilities};
+use crate::casemap::CaseMapping;
+use crate::channels::Channels;
+use crate::ctcp::{CtcpReplies, ReplyLimiter};
+use crate::decode::Decoder;
+use crate::flood::FloodControl;
+use crate::history::HistorySelector;
+use crate::irc_msg::{Command, Msg};
+use crate::isupport::ServerInfo;
+use crate::keepalive::Keepalive;
+use crate::mode::{self, ModeChange};
+use crate::nick::NickRegain;
+use crate::numeric::Numeric;
+use crate::prefix::Prefix;
+use crate::query::{self, ChannelListing, ChannelModes, Queries, QueryKind, WhoEntry, WhoisInfo};
+use crate::reconnect::ReconnectPolicy;
+use crate::sasl::{SaslCredentials, SaslMechanism};
+use crate::split;
+use crate::tls::TlsConfig;
+use crate::users::{UserEvent, Users};
+
+/// How often [`Client::flush`] checks whether the queue has drained.
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How many messages to ask for at once when the server doesn't set a limit.
+const HISTORY_PAGE: usize = 100;
+
+/// Connection settings collected by [`ClientBuilder`].
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    pub server: Cow<'static, str>,
+    pub nick: Cow<'static, str>,
+    pub user: Cow<'static, str>,
+    pub caps: Vec<String>,
+    pub sasl: Option<SaslCredentials>,
+    pub tls: Option<TlsConfig>,
+    pub split_marker: Option<String>,
+    pub flood: FloodControl,
+    pub reconnect: ReconnectPolicy,
+    pub keepalive: Keepalive,
+    pub alt_nicks: Vec<String>,
+    pub regain: NickRegain,
+    pub decoder: Decoder,
+    pub ctcp: CtcpReplies,
+    pub query_timeout: Duration,
+    pub registration_timeout: Duration,
+}
+
+pub struct ClientBuilder {
+    config: Config,
+}
+
+impl ClientBuilder {
+    pub fn new<S, N, U>(server: S, nick: N, user: U) -> Self
+    where
+        S: Into<Cow<'static, str>>,
+        N: Into<Cow<'static, str>>,
+        U: Into<Cow<'static, str>>,
+    {
+        Self {
+            config: Config {
+                server: server.into(),
+                nick: nick.into(),
+                user: user.into(),
+                caps: vec![],
+                sasl: None,
+                tls: None,
+                split_marker: None,
+                flood: FloodControl::default(),
+                reconnect: ReconnectPolicy::default(),
+                keepalive: Keepalive::default(),
+                alt_nicks: vec![],
+                regain: NickRegain::default(),
+                decoder: Decoder::default(),
+                ctcp: CtcpReplies::default(),
+                query_timeout: Duration::from_secs(30),
+                registration_timeout: Duration::from_secs(60),
+            },
+        }
+    }
+
+    /// Requests an IRCv3 capability during registration, if the server offers it.
+    pub fn with_capability(mut self, cap: impl Into<String>) -> Self {
+        let cap = cap.into();
+        if !self.config.caps.contains(&cap) {
+            self.config.caps.push(cap);
+        }
+        self
+    }
+
+    pub fn with_capabilities<I, C>(self, caps: I) -> Self
+    where
+        I: IntoIterator<Item = C>,
+        C: Into<String>,
+    {
+        caps.into_iter().fold(self, Self::with_capability)
+    }
+
+    /// Authenticates with SASL before registration completes. Registration fails with a
+    /// [`SaslError`](crate::sasl::SaslError) if the server rejects the credentials.
+    pub fn with_sasl<A, P>(mut self, mechanism: SaslMechanism, account: A, password: P) -> Self
+    where
+        A: Into<Cow<'static, str>>,
+        P: Into<Cow<'static, str>>,
+    {
+        self.config.sasl = Some(SaslCredentials {
+            mechanism,
+            account: account.into(),
+            password: password.into(),
+        });
+        self.with_capability("sasl")
+    }
+
+    /// Connects over TLS instead of plain TCP.
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    /// Appends `marker` to every piece of a message that had to be split across
+    /// several lines, except the last.
+    pub fn with_split_marker(mut self, marker: impl Into<String>) -> Self {
+        self.config.split_marker = Some(marker.into());
+        self
+    }
+
+    /// Paces outgoing lines to avoid being disconnected for flooding. Defaults to
+    /// [`FloodControl::default`].
+    pub fn with_flood_control(mut self, flood: FloodControl) -> Self {
+        self.config.flood = flood;
+        self
+    }
+
+    /// Controls reconnecting after the connection drops. Defaults to
+    /// [`ReconnectPolicy::default`].
+    pub fn with_reconnect(mut self, reconnect: ReconnectPolicy) -> Self {
+        self.config.reconnect = reconnect;
+        self
+    }
+
+    pub fn with_keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.config.keepalive = keepalive;
+        self
+    }
+
+    /// Nicks to try, in order, if ours is taken. Once they run out, underscores are
+    /// appended to the primary nick instead.
+    pub fn with_alt_nicks<I, N>(mut self, nicks: I) -> Self
+    where
+        I: IntoIterator<Item = N>,
+        N: Into<String>,
+    {
+        self.config.alt_nicks = nicks.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// How to get our nick back after registering under a fallback. Defaults to
+    /// [`NickRegain::Monitor`].
+    pub fn with_nick_regain(mut self, regain: NickRegain) -> Self {
+        self.config.regain = regain;
+        self
+    }
+
+    /// How to decode lines that aren't valid UTF-8. Defaults to falling back to
+    /// Windows-1252.
+    pub fn with_decoder(mut self, decoder: Decoder) -> Self {
+        self.config.decoder = decoder;
+        self
+    }
+
+    /// How to answer CTCP queries such as VERSION. Defaults to
+    /// [`CtcpReplies::default`].
+    pub fn with_ctcp_replies(mut self, ctcp: CtcpReplies) -> Self {
+        self.config.ctcp = ctcp;
+        self
+    }
+
+    /// How long [`Client::whois`] and the other queries wait for an answer. Defaults
+    /// to 30 seconds.
+    pub fn with_query_timeout(mut self, timeout: Duration) -> Self {
+        self.config.query_timeout = timeout;
+        self
+    }
+
+    /// How long connecting, the TLS handshake and registration may take together
+    /// before the attempt is abandoned, so a server that accepts the connection and
+    /// then says nothing can't stall connecting or reconnecting. Defaults to 60
+    /// seconds.
+    pub fn with_registration_timeout(mut self, timeout: Duration) -> Self {
+        self.config.registration_timeout = timeout;
+        self
+    }
+
+    /// Connects and registers with the server. Returns once RPL_WELCOME is received.
+    pub async fn connect(self) -> anyhow::Result<Client> {
+        crate::open(self.config).await
+    }
+}
+
+/// Connection state shared by every clone of a [`Client`].
+#[derive(Debug)]
+pub(crate) struct Shared {
+    pub caps: RwLock<Capabilities>,
+    pub server_info: RwLock<ServerInfo>,
+    /// The nick we actually have, which may be a fallback.
+    pub nick: RwLock<String>,
+    /// The nick we asked for, and how to get it back.
+    pub primary_nick: String,
+    pub regain: NickRegain,
+    pub ctcp: CtcpReplies,
+    pub ctcp_limiter: std::sync::Mutex<ReplyLimiter>,
+    /// Our own `nick!user@host`, once the server has shown it to us.
+    pub source: RwLock<Option<Prefix>>,
+    /// Channels we are in, to rejoin after reconnecting.
+    pub joined: RwLock<Vec<String>>,
+    /// Keys we joined channels with, by channel, to send again when rejoining.
+    pub keys: RwLock<Vec<(String, String)>>,
+    /// Members, topics and modes of the channels we are in on this connection.
+    pub channels: RwLock<Channels>,
+    pub users: RwLock<Users>,
+    /// Events from the last message, delivered right after it.
+    pub user_events: std::sync::Mutex<VecDeque<UserEvent>>,
+    /// Batches whose messages are still arriving.
+    pub batches: std::sync::Mutex<Batches>,
+    /// WHOIS and similar queries waiting for their replies. Fed by the reader task, so
+    /// that a handler awaiting one doesn't stop the replies from arriving.
+    pub queries: std::sync::Mutex<Queries>,
+    pub query_timeout: Duration,
+    pub wanted_caps: Vec<String>,
+    pub split_marker: Option<String>,
+    /// Lines handed to the writer task but not yet written.
+    pub queued: AtomicUsize,
+    /// Set once we send QUIT, so that the supervisor doesn't reconnect.
+    pub quit_sent: AtomicBool,
+    /// Notified together with `quit_sent`, to stop a reconnect that is in progress.
+    pub quit: Notify,
+    /// True while a registered connection is up.
+    pub online: AtomicBool,
+    /// Round-trip time of the last keepalive ping on this connection.
+    pub lag: RwLock<Option<Duration>>,
+}
+
+/// What the supervisor passes to [`Client`].
+#[derive(Debug)]
+pub(crate) enum Incoming {
+    Line(String),
+    Connected,
+    Disconnected,
+}
 
-use crate::irc_msg::Msg;
+/// Something that happened on the connection.
+#[derive(Debug)]
+pub enum Event {
+    /// Registration completed, on the first connection or after reconnecting.
+    Connected,
+    /// The connection dropped. A [`Connected`](Event::Connected) follows if the
+    /// supervisor manages to reconnect.
+    Disconnected,
+    Message(Box<Msg>),
+    /// Follows the message that caused it, e.g. a QUIT, with what the message alone
+    /// doesn't say.
+    User(UserEvent),
+    /// A batch of messages to handle together, once all of them have arrived.
+    Batch(Box<Batch>),
+}
 
 #[derive(Clone)]
 pub struct Client {
     pub(crate) tx: Sender<String>,
-    pub(crate) rx: Arc<Mutex<Receiver<String>>>,
-
-    pub nick: String,
+    pub(crate) rx: Arc<Mutex<Receiver<Incoming>>>,
+    pub(crate) shared: Arc<Shared>,
 }
 
 impl Client {
+    /// The nick we are currently known by. This can differ from the one we asked for
+    /// if it was taken, and changes whenever the server confirms a `NICK`.
+    pub fn nick(&self) -> String {
+        self.shared.nick.read().unwrap().clone()
+    }
+
     pub async fn send<'a>(&self, line: impl Into<Cow<'a, str>>) -> anyhow::Result<()> {
-        let owned = line.into().to_owned();
-        self.tx.send(format!("{}\r\n", owned)).await?;
+        let owned = line.into().into_owned();
+        if owned
+            .split([' ', '\r', '\n'])
+            .next()
+            .is_some_and(|verb| verb.eq_ignore_ascii_case("QUIT"))
+        {
+            self.shared.quit_sent.store(true, Ordering::Relaxed);
+            self.shared.quit.notify_one();
+        }
+        self.shared.queued.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.tx.send(format!("{}\r\n", owned)).await {
+            self.shared.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(e.into());
+        }
         Ok(())
     }
 
+    /// True while connected and registered; false while reconnecting, and for good
+    /// once the connection is closed.
+    pub fn is_connected(&self) -> bool {
+        self.shared.online.load(Ordering::Relaxed)
+    }
+
+    /// The channels we are currently in.
+    pub fn channels(&self) -> Vec<String> {
+        self.shared.joined.read().unwrap().clone()
+    }
+
+    /// How many lines are waiting to be written, e.g. because of flood control.
+    pub fn queue_depth(&self) -> usize {
+        self.shared.queued.load(Ordering::Relaxed)
+    }
+
+    /// Waits for every queued line to be written, for at most `timeout`. Returns
+    /// false if lines were still queued when time ran out.
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while self.queue_depth() > 0 {
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(FLUSH_POLL_INTERVAL).await;
+        }
+        true
+    }
+
+    /// Sends QUIT. The connection closes once the server has seen it, and is not
+    /// reconnected. Unlike other lines, QUIT skips ahead of anything still queued;
+    /// call [`flush`](Self::flush) first to send those.
+    pub async fn quit(&self, message: Option<&str>) -> anyhow::Result<()> {
+        self.send_command(Command::Quit {
+            message: message.map(str::to_owned),
+        })
+        .await
+    }
+
+    /// Round-trip time of the most recent keepalive ping, or `None` if none has been
+    /// answered since connecting.
+    pub fn lag(&self) -> Option<Duration> {
+        *self.shared.lag.read().unwrap()
+    }
+
+    /// The next message from the server, skipping connection events and batches.
+    /// Returns `None` once the connection is closed for good.
     pub async fn recv(&self) -> anyhow::Result<Option<Msg>> {
+        loop {
+            match self.next_event().await? {
+                Some(Event::Message(msg)) => return Ok(Some(*msg)),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// The next message or connection event. Returns `None` once the connection is
+    /// closed for good: after QUIT, or when reconnecting is disabled or gives up.
+    pub async fn next_event(&self) -> anyhow::Result<Option<Event>> {
         let mut rx = self.rx.lock().await;
+        if let Some(event) = self.shared.user_events.lock().unwrap().pop_front() {
+            return Ok(Some(Event::User(event)));
+        }
 
-        match rx.recv().await {
-            Some(line) => {
-                let msg = Msg::parse(&line, chrono::Local::now())
-                    .with_context(|| format!("failed to parse IRC line: {}", line))?;
-                anyhow::Ok(Some(msg))
-            }
-            None => Ok(None),
+        loop {
+            return match rx.recv().await {
+                Some(Incoming::Line(line)) => {
+                    // One malformed line from the server shouldn't end the stream.
+                    let Some(msg) = Msg::parse(&line, chrono::Local::now()) else {
+                        warn!("skipping unparseable line: {:?}", line);
+                        continue;
+                    };
+                    let grouped = self.shared.batches.lock().unwrap().apply(msg);
+                    match grouped {
+                        Grouped::Alone(msg) => {
+                            self.track(&msg).await?;
+                            Ok(Some(Event::Message(Box::new(msg))))
+                        }
+                        Grouped::Held => continue,
+                        Grouped::Complete(batch) => {
+                            // Replayed history is about the past, not the channels now.
+                            if !batch.is_history() {
+                                for msg in &batch.messages {
+                                    self.track(msg).await?;
+                                }
+                            }
+                            Ok(Some(Event::Batch(Box::new(batch))))
+                        }
+                    }
+                }
+                Some(Incoming::Connected) => Ok(Some(Event::Connected)),
+                Some(Incoming::Disconnected) => {
+                    self.shared.channels.write().unwrap().clear();
+                    self.shared.users.write().unwrap().clear();
+                    self.shared.batches.lock().unwrap().clear();
+                    Ok(Some(Event::Disconnected))
+                }
+                None => Ok(None),
+            };
         }
     }
 
+    /// A snapshot of the capabilities negotiated with the server.
+    pub fn caps(&self) -> Capabilities {
+        self.shared.caps.read().unwrap().clone()
+    }
+
+    /// True if the server acknowledged the capability `name`.
+    pub fn has_cap(&self, name: &str) -> bool {
+        self.shared.caps.read().unwrap().is_enabled(name)
+    }
+
+    /// A snapshot of the features the server advertised in `RPL_ISUPPORT`.
+    pub fn server_info(&self) -> ServerInfo {
+        self.shared.server_info.read().unwrap().clone()
+    }
+
+    /// The server's casemapping, for building [`Nick`](crate::casemap::Nick) and
+    /// [`ChannelName`](crate::casemap::ChannelName) keys.
+    pub fn casemapping(&self) -> CaseMapping {
+        self.shared.server_info.read().unwrap().casemapping
+    }
+
+    /// Encodes and sends a command. Fails without sending anything if the command
+    /// cannot be represented as a valid line.
+    pub async fn send_command(&self, command: Command) -> anyhow::Result<()> {
+        let linelen = self.shared.server_info.read().unwrap().linelen;
+        let line = command.encode_within(linelen.saturating_sub(2))?;
+        self.send(line).await
+    }
+
+    /// Sends a message, split over as many lines as it takes for every piece to reach
+    /// the recipient intact. Line breaks in `msg` always start a new line.
     pub async fn privmsg(&self, target: &str, msg: &str) -> anyhow::Result<()> {
-        let line = format!("PRIVMSG {} :{}", target, msg);
-        self.send(&line).await
+        for message in self.split_text("PRIVMSG", target, msg) {
+            self.send_command(Command::Privmsg {
+                reply_to: target.to_owned(),
+                message,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Like [`Client::privmsg`], but sends a NOTICE.
+    pub async fn notice(&self, target: &str, msg: &str) -> anyhow::Result<()> {
+        for message in self.split_text("NOTICE", target, msg) {
+            self.send_command(Command::Notice {
+                channel: target.to_owned(),
+                message,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Sends `text` as an action, as with `/me`.
+    pub async fn action(&self, target: &str, text: &str) -> anyhow::Result<()> {
+        // "\x01ACTION " and the closing "\x01".
+        let framing = 1 + "ACTION".len() + 1 + 1;
+        for text in self.split_text_within("PRIVMSG", target, text, framing) {
+            self.ctcp(target, "ACTION", Some(&text)).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a CTCP query, e.g. `VERSION`. Replies arrive as [`Command::Ctcp`] with
+    /// `reply` set.
+    pub async fn ctcp(&self, target: &str, verb: &str, params: Option<&str>) -> anyhow::Result<()> {
+        self.send_command(Command::Ctcp {
+            target: target.to_owned(),
+            verb: verb.to_ascii_uppercase(),
+            params: params.map(str::to_owned),
+            reply: false,
+        })
+        .await
     }
 
     pub async fn join(&self, channel: &str) -> anyhow::Result<()> {
-        let line = format!("JOIN {}", channel);
-        self.send(&line).await
+        self.send_command(join_command(channel, None)).await
+    }
+
+    /// Joins a channel protected by a key (`+k`). The key is kept for rejoining after
+    /// a reconnect.
+    pub async fn join_with_key(&self, channel: &str, key: &str) -> anyhow::Result<()> {
+        self.send_command(join_command(channel, Some(key))).await?;
+        let mapping = self.casemapping();
+        let mut keys = self.shared.keys.write().unwrap();
+        keys.retain(|(c, _)| !mapping.eq(c, channel));
+        keys.push((channel.to_owned(), key.to_owned()));
+        Ok(())
+    }
+
+    pub async fn part(&self, channel: &str, message: Option<&str>) -> anyhow::Result<()> {
+        self.send_command(Command::Part {
+            channel: channel.to_owned(),
+            message: message.map(str::to_owned),
+        })
+        .await
+    }
+
+    /// Kicks everyone in `nicks` from `channel`, as few lines as the server's TARGMAX
+    /// allows.
+    pub async fn kick(
+        &self,
+        channel: &str,
+        nicks: &[&str],
+        reason: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let per_line = self.server_info().targets_per_line("KICK");
+        for chunk in nicks.chunks(per_line.unwrap_or(usize::MAX).max(1)) {
+            self.send_command(Command::Kick {
+                channel: channel.to_owned(),
+                nick: chunk.join(","),
+                message: reason.map(str::to_owned),
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Changes modes on a channel or on ourselves, split over as many lines as the
+    /// server's MODES limit requires. With no changes, asks for the current modes.
+    pub async fn mode(&self, target: &str, changes: &[ModeChange]) -> anyhow::Result<()> {
+        if changes.is_empty() {
+            return self
+                .send_command(Command::Mode {
+                    target: target.to_owned(),
+                    modes: None,
+                    args: vec![],
+                })
+                .await;
+        }
+        let per_line = self.server_info().modes_per_line();
+        for (modes, args) in mode::batch(changes, per_line) {
+            self.send_command(Command::Mode {
+                target: target.to_owned(),
+                modes: Some(modes),
+                args,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Sets the topic, or asks for it if `topic` is `None`. An empty topic clears it.
+    pub async fn topic(&self, channel: &str, topic: Option<&str>) -> anyhow::Result<()> {
+        self.send_command(Command::Topic {
+            channel: channel.to_owned(),
+            topic: topic.map(str::to_owned),
+        })
+        .await
+    }
+
+    pub async fn invite(&self, nick: &str, channel: &str) -> anyhow::Result<()> {
+        self.send_command(Command::Invite {
+            nick: nick.to_owned(),
+            channel: channel.to_owned(),
+        })
+        .await
+    }
+
+    /// Asks to change our nick. [`nick`](Self::nick) follows once the server agrees.
+    pub async fn set_nick(&self, nick: &str) -> anyhow::Result<()> {
+        self.send_command(Command::Nick {
+            nick: nick.to_owned(),
+        })
+        .await
+    }
+
+    /// Marks us away with `message`, or back if it is `None`.
+    pub async fn away(&self, message: Option<&str>) -> anyhow::Result<()> {
+        self.send_command(Command::Away {
+            message: message.map(str::to_owned),
+        })
+        .await
     }
 
     pub async fn pong(&self, token: Option<&str>) -> anyhow::Result<()> {
-        let line = match token {
-            Some(t) => format!("PONG :{}", t),
-            None => "PONG".to_string(),
-        };
-        self.send(&line).await
+        self.send_command(Command::Raw {
+            command: "PONG".into(),
+            args: token.map(str::to_owned).into_iter().collect(),
+        })
+        .await
     }
 
     pub async fn names(&self, channel: &str) -> anyhow::Result<()> {
-        let line = format!("NAMES {}", channel);
-        self.send(&line).await
+        self.send_command(Command::Raw {
+            command: "NAMES".into(),
+            args: vec![channel.to_owned()],
+        })
+        .await
+    }
+
+    /// Looks up a user, or returns `None` if there is no one by that nick.
+    pub async fn whois(&self, nick: &str) -> anyhow::Result<Option<WhoisInfo>> {
+        let replies = self
+            .query(QueryKind::Whois, nick, vec![nick.to_owned()])
+            .await?;
+        Ok(WhoisInfo::from_replies(&replies))
+    }
+
+    /// Lists the users matching `mask`, e.g. everyone in a channel.
+    pub async fn who(&self, mask: &str) -> anyhow::Result<Vec<WhoEntry>> {
+        let replies = self
+            .query(QueryKind::Who, mask, vec![mask.to_owned()])
+            .await?;
+        Ok(WhoEntry::from_replies(&replies))
+    }
+
+    /// Lists the channels on the server. This can take a while on large networks.
+    pub async fn list(&self) -> anyhow::Result<Vec<ChannelListing>> {
+        let replies = self.query(QueryKind::List, "", vec![]).await?;
+        Ok(ChannelListing::from_replies(&replies))
+    }
+
+    /// Asks for a channel's current modes.
+    pub async fn channel_modes(&self, channel: &str) -> anyhow::Result<ChannelModes> {
+        let replies = self
+            .query(QueryKind::Mode, channel, vec![channel.to_owned()])
+            .await?;
+        ChannelModes::from_replies(&replies)
+            .ok_or_else(|| anyhow::anyhow!("no modes in the reply for {}", channel))
+    }
+
+    /// Fetches messages sent to `target` from the server's history, oldest first, as
+    /// many as the server allows in one request. Needs the `draft/chathistory` and
+    /// `batch` capabilities.
+    pub async fn chathistory(
+        &self,
+        target: &str,
+        selector: HistorySelector,
+    ) -> anyhow::Result<Batch> {
+        anyhow::ensure!(
+            self.has_cap("draft/chathistory") && self.has_cap("batch"),
+            "the server hasn't enabled draft/chathistory"
+        );
+        let limit = self.server_info().history_limit().unwrap_or(HISTORY_PAGE);
+        let replies = self
+            .query(QueryKind::History, target, selector.args(target, limit))
+            .await?;
+        Ok(Batch::history(target, replies))
+    }
+
+    /// Sends a query and waits for every line of its answer. Uses `labeled-response`
+    /// if it was negotiated (with `batch`), and reply order otherwise.
+    async fn query(
+        &self,
+        kind: QueryKind,
+        target: &str,
+        args: Vec<String>,
+    ) -> anyhow::Result<Vec<Msg>> {
+        let linelen = self.shared.server_info.read().unwrap().linelen;
+        let line = Command::Raw {
+            command: kind.command().into(),
+            args,
+        }
+        .encode_within(linelen.saturating_sub(2))?;
+        let labeled = self.has_cap("labeled-response") && self.has_cap("batch");
+        let (id, label, replies) = self
+            .shared
+            .queries
+            .lock()
+            .unwrap()
+            .start(kind, target, labeled);
+        let line = match label {
+            Some(label) => format!("@label={} {}", label, line),
+            None => line,
+        };
+        if let Err(e) = self.send(line).await {
+            self.shared.queries.lock().unwrap().cancel(id);
+            return Err(e);
+        }
+
+        let timeout = self.shared.query_timeout;
+        let replies = match tokio::time::timeout(timeout, replies).await {
+            Ok(Ok(replies)) => replies,
+            Ok(Err(_)) => anyhow::bail!("disconnected before {} was answered", kind.command()),
+            Err(_) => {
+                self.shared.queries.lock().unwrap().cancel(id);
+                anyhow::bail!(
+                    "no answer to {} {} within {:?}",
+                    kind.command(),
+                    target,
+                    timeout
+                );
+            }
+        };
+        let failure = replies.iter().find_map(|msg| match msg.reply() {
+            Some(r) if r.numeric.is_error() && r.numeric != Numeric::ErrNoSuchNick => {
+                Some(r.text().or(r.numeric.name()).unwrap_or("error"))
+            }
+            Some(_) => None,
+            None => query::failure(msg),
+        });
+        if let Some(failure) = failure {
+            anyhow::bail!("{} {} failed: {}", kind.command(), target, failure);
+        }
+        Ok(replies)
+    }
+
+    fn split_text(&self, command: &str, target: &str, text: &str) -> Vec<String> {
+        self.split_text_within(command, target, text, 0)
+    }
+
+    /// Like `split_text`, leaving `framing` bytes per line for wrapping the text.
+    fn split_text_within(
+        &self,
+        command: &str,
+        target: &str,
+        text: &str,
+        framing: usize,
+    ) -> Vec<String> {
+        let linelen = self.shared.server_info.read().unwrap().linelen;
+        let source_len = match &*self.shared.source.read().unwrap() {
+            Some(source) => source.to_string().len(),
+            None => split::assumed_source_len(&self.nick()),
+        };
+        let budget =
+            split::text_budget(linelen, source_len, command, target).saturating_sub(framing);
+        split::split_message(text, budget, self.shared.split_marker.as_deref())
+    }
+
+    /// Keeps connection state up to date with messages received after registration.
+    async fn track(&self, msg: &Msg) -> anyhow::Result<()> {
+        if let Some(reply) = msg.reply() {
+            self.shared.server_info.write().unwrap().apply_reply(&reply);
+        }
+        self.track_source(msg);
+        self.track_joined(msg);
+        self.track_channels(msg);
+        self.track_nick(msg).await?;
+        self.answer_ctcp(msg).await?;
+
+        let Some(reply) = CapReply::from_msg(msg) else {
+            return Ok(());
+        };
+
+        let requests = {
+            let mut caps = self.shared.caps.write().unwrap();
+            match reply.subcommand {
+                "NEW" => {
+                    for (name, value) in &reply.caps {
+                        caps.advertise(name, *value);
+                    }
+                    cap::request_lines(&self.shared.wanted_caps, &caps)
+                }
+                "DEL" => {
+                    for (name, _) in &reply.caps {
+                        caps.remove(name);
+                    }
+                    vec![]
+                }
+                "ACK" => {
+                    for (name, _) in &reply.caps {
+                        caps.ack(name);
+                    }
+                    vec![]
+                }
+                _ => vec![],
+            }
+        };
+
+        for line in requests {
+            self.send(line).await?;
+        }
+        Ok(())
+    }
+
+    /// Follows our own nick changes, and tries to get the primary nick back if we
+    /// registered under a fallback.
+    async fn track_nick(&self, msg: &Msg) -> anyhow::Result<()> {
+        let mapping = self.casemapping();
+        let primary = &self.shared.primary_nick;
+        let monitor = self.shared.regain == NickRegain::Monitor
+            && self.shared.server_info.read().unwrap().monitor;
+
+        match &msg.command {
+            Command::Nick { nick } if msg.nick().is_some_and(|n| mapping.eq(n, &self.nick())) => {
+                *self.shared.nick.write().unwrap() = nick.clone();
+                if monitor && mapping.eq(nick, primary) {
+                    self.send_command(Command::Raw {
+                        command: "MONITOR".into(),
+                        args: vec!["-".into(), primary.clone()],
+                    })
+                    .await?;
+                }
+            }
+            // The end of the MOTD is the first point where ISUPPORT is complete.
+            Command::Numeric {
+                code: Numeric::RplEndOfMotd | Numeric::ErrNoMotd,
+                ..
+            } if !mapping.eq(&self.nick(), primary) => match &self.shared.regain {
+                NickRegain::Monitor if monitor => {
+                    self.send_command(Command::Raw {
+                        command: "MONITOR".into(),
+                        args: vec!["+".into(), primary.clone()],
+                    })
+                    .await?;
+                }
+                // A line break would make NickServ see the rest as a second command.
+                NickRegain::NickServ { password } if password.contains(['\r', '\n', '\0']) => {
+                    warn!(
+                        "not asking NickServ for {}: the password has a line break",
+                        primary
+                    );
+                }
+                NickRegain::NickServ { password } => {
+                    self.privmsg("NickServ", &format!("REGAIN {} {}", primary, password))
+                        .await?;
+                }
+                _ => {}
+            },
+            Command::Numeric {
+                code: Numeric::RplMonOffline,
+                ..
+            } if monitor && !mapping.eq(&self.nick(), primary) => {
+                let reply = msg.reply().expect("numeric");
+                let freed = reply.monitor_targets().any(|target| {
+                    let nick = target.split('!').next().unwrap_or(target);
+                    mapping.eq(nick, primary)
+                });
+                if freed {
+                    self.send_command(Command::Nick {
+                        nick: primary.clone(),
+                    })
+                    .await?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Answers CTCP queries addressed to us or a channel we are in, within the rate limit.
+    async fn answer_ctcp(&self, msg: &Msg) -> anyhow::Result<()> {
+        let Command::Ctcp {
+            verb,
+            params,
+            reply: false,
+            ..
+        } = &msg.command
+        else {
+            return Ok(());
+        };
+        let Some(nick) = msg.nick() else {
+            return Ok(());
+        };
+        if self.casemapping().eq(nick, &self.nick()) {
+            return Ok(());
+        }
+        let Some(answer) = self.shared.ctcp.reply(verb, params.as_deref()) else {
+            return Ok(());
+        };
+        let now = tokio::time::Instant::now();
+        if !self
+            .shared
+            .ctcp_limiter
+            .lock()
+            .unwrap()
+            .allow(&self.shared.ctcp, now)
+        {
+            debug!("not answering CTCP {} from {}: rate limited", verb, nick);
+            return Ok(());
+        }
+        self.send_command(Command::Ctcp {
+            target: nick.to_owned(),
+            verb: verb.clone(),
+            params: Some(answer),
+            reply: true,
+        })
+        .await
+    }
+
+    /// Remembers which channels we are in, so they can be rejoined after reconnecting.
+    fn track_joined(&self, msg: &Msg) {
+        let mapping = self.casemapping();
+        let me = self.nick();
+        let from_us = msg.nick().is_some_and(|nick| mapping.eq(nick, &me));
+        let left = match &msg.command {
+            Command::Join { channel, .. } if from_us => {
+                let mut joined = self.shared.joined.write().unwrap();
+                if !joined.iter().any(|c| mapping.eq(c, channel)) {
+                    joined.push(channel.clone());
+                }
+                return;
+            }
+            Command::Part { channel, .. } if from_us => channel.as_str(),
+            Command::Kick { channel, nick, .. } if mapping.eq(nick, &me) => channel.as_str(),
+            // A rejoin after reconnecting was refused; don't keep trying.
+            Command::Numeric {
+                code:
+                    Numeric::ErrChannelIsFull
+                    | Numeric::ErrInviteOnlyChan
+                    | Numeric::ErrBannedFromChan
+                    | Numeric::ErrBadChannelKey,
+                ..
+            } => match msg.reply().and_then(|reply| reply.channel()) {
+                Some(channel) => channel,
+                None => return,
+            },
+            _ => return,
+        };
+        self.shared
+            .joined
+            .write()
+            .unwrap()
+            .retain(|c| !mapping.eq(c, left));
+        self.shared
+            .keys
+            .write()
+            .unwrap()
+            .retain(|(c, _)| !mapping.eq(c, left));
+    }
+
+    /// Updates the channel and user trackers, queueing any user events to follow `msg`.
+    fn track_channels(&self, msg: &Msg) {
+        let info = self.shared.server_info.read().unwrap();
+        let me = self.nick();
+        let mut channels = self.shared.channels.write().unwrap();
+        let events = self
+            .shared
+            .users
+            .write()
+            .unwrap()
+            .apply(msg, &me, &info, &channels);
+        channels.apply(msg, &me, &info);
+        self.shared.user_events.lock().unwrap().extend(events);
+    }
+
+    /// Learns our own `nick!user@host` from our JOINs and host changes, which is what
+    /// the server prepends to everything we send.
+    fn track_source(&self, msg: &Msg) {
+        let me = self.nick();
+        let from_us = msg
+            .nick()
+            .is_some_and(|nick| self.casemapping().eq(nick, &me));
+        let mut source = self.shared.source.write().unwrap();
+        match &msg.command {
+            Command::Join { .. } if from_us => source.clone_from(&msg.source),
+            Command::Nick { nick } if from_us => {
+                if let Some(Prefix::User { nick: ours, .. }) = source.as_mut() {
+                    ours.clone_from(nick);
+                }
+            }
+            Command::Chghost { user, host } if from_us => {
+                *source = Some(Prefix::User {
+                    nick: me,
+                    user: Some(user.clone()),
+                    host: Some(host.clone()),
+                });
+            }
+            Command::Numeric {
+                code: Numeric::RplVisibleHost,
+                args,
+                ..
+            } => {
+                if let (Some(Prefix::User { host, .. }), Some(new_host)) =
+                    (source.as_mut(), args.get(1))
+                {
+                    *host = Some(new_host.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The JOIN for `channel`, with its key if it has one.
+pub(crate) fn join_command(channel: &str, key: Option<&str>) -> Command {
+    match key {
+        Some(key) => Command::Raw {
+            command: "JOIN".into(),
+            args: vec![channel.to_owned(), key.to_owned()],
+        },
+        None => Command::Join {
+            channel: channel.to_owned(),
+            message: None,
+        },
     }
 }
@@ -0,0 +1,267 @@
+//! Client-to-client protocol: queries and replies wrapped in `\x01` inside PRIVMSG and
+//! NOTICE, e.g. `/me` (ACTION) or `VERSION`.
+//!
+//! See <https://modern.ircdocs.horse/ctcp>.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// The byte that delimits a CTCP message.
+pub const DELIM: char = '\x01';
+
+/// Splits `text` into a CTCP verb and parameters, if it is a CTCP message. The
+/// closing delimiter is optional, as some clients leave it off. The verb is
+/// upper-cased, since clients differ in how they spell it.
+pub fn parse(text: &str) -> Option<(String, Option<String>)> {
+    let body = text.strip_prefix(DELIM)?;
+    let body = body.strip_suffix(DELIM).unwrap_or(body);
+    let (verb, params) = match body.split_once(' ') {
+        Some((verb, params)) => (verb, Some(params.to_owned())),
+        None => (body, None),
+    };
+    if verb.is_empty() {
+        return None;
+    }
+    Some((verb.to_ascii_uppercase(), params))
+}
+
+/// Wraps a verb and parameters in delimiters, ready to send as message text.
+pub fn wrap(verb: &str, params: Option<&str>) -> String {
+    match params {
+        Some(params) => format!("{DELIM}{verb} {params}{DELIM}"),
+        None => format!("{DELIM}{verb}{DELIM}"),
+    }
+}
+
+/// How the client answers CTCP queries sent to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CtcpReplies {
+    enabled: bool,
+    version: String,
+    /// Fixed replies for other verbs, keyed by upper-cased verb.
+    custom: BTreeMap<String, String>,
+    max_replies: usize,
+    per: Duration,
+}
+
+impl Default for CtcpReplies {
+    /// Answers VERSION, PING, TIME and CLIENTINFO, at most 4 replies every 10 seconds.
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            version: format!("irc_core {}", env!("CARGO_PKG_VERSION")),
+            custom: BTreeMap::new(),
+            max_replies: 4,
+            per: Duration::from_secs(10),
+        }
+    }
+}
+
+impl CtcpReplies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Never answers, leaving CTCP entirely to handlers.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::default()
+        }
+    }
+
+    /// The VERSION reply.
+    pub fn with_version(mut self, version: impl Into<String>) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    /// Answers `verb` with `reply`, e.g. `SOURCE` with a repository URL. Overrides the
+    /// built-in reply for that verb, if there is one.
+    pub fn with_reply(mut self, verb: &str, reply: impl Into<String>) -> Self {
+        self.custom.insert(verb.to_ascii_uppercase(), reply.into());
+        self
+    }
+
+    /// Answers at most `max` queries in any `per` window; the rest are ignored, so a
+    /// flood of queries can't get us disconnected for flooding in turn.
+    pub fn with_rate_limit(mut self, max: usize, per: Duration) -> Self {
+        self.max_replies = max;
+        self.per = per;
+        self
+    }
+
+    /// The reply to a query, or `None` if it isn't one we answer. ACTION is never
+    /// answered.
+    pub(crate) fn reply(&self, verb: &str, params: Option<&str>) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        if let Some(reply) = self.custom.get(verb) {
+            return Some(reply.clone());
+        }
+        match verb {
+            "VERSION" => Some(self.version.clone()),
+            "PING" => Some(params.unwrap_or_default().to_owned()),
+            "TIME" => Some(chrono::Local::now().to_rfc2822()),
+            "CLIENTINFO" => {
+                let mut verbs: Vec<&str> = ["ACTION", "CLIENTINFO", "PING", "TIME", "VERSION"]
+                    .into_iter()
+                    .chain(self.custom.keys().map(String::as_str))
+                    .collect();
+                verbs.sort();
+                verbs.dedup();
+                Some(verbs.join(" "))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Counts recent replies to enforce [`CtcpReplies::with_rate_limit`].
+#[derive(Debug, Default)]
+pub(crate) struct ReplyLimiter {
+    sent: VecDeque<Instant>,
+}
+
+impl ReplyLimiter {
+    /// Records a reply at `now` and returns true if it is within the limit.
+    pub fn allow(&mut self, config: &CtcpReplies, now: Instant) -> bool {
+        while self
+            .sent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= config.per)
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= config.max_replies {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::client::ClientBuilder;
+    use crate::flood::FloodControl;
+    use crate::irc_msg::Command;
+    use crate::testing::accept_and_register;
+
+    #[test]
+    fn parse_and_wrap() {
+        assert_eq!(
+            parse("\x01ACTION waves hello\x01"),
+            Some(("ACTION".into(), Some("waves hello".into())))
+        );
+        assert_eq!(parse("\x01version"), Some(("VERSION".into(), None)));
+        assert_eq!(parse("\x01\x01"), None);
+        assert_eq!(parse("plain text"), None);
+
+        assert_eq!(wrap("PING", Some("123")), "\x01PING 123\x01");
+        assert_eq!(wrap("VERSION", None), "\x01VERSION\x01");
+    }
+
+    #[test]
+    fn replies_to_standard_and_custom_verbs() {
+        let replies = CtcpReplies::new()
+            .with_version("botty 1.0")
+            .with_reply("source", "https://example.org/botty");
+        assert_eq!(replies.reply("VERSION", None).as_deref(), Some("botty 1.0"));
+        assert_eq!(replies.reply("PING", Some("42")).as_deref(), Some("42"));
+        assert_eq!(
+            replies.reply("SOURCE", None).as_deref(),
+            Some("https://example.org/botty")
+        );
+        assert_eq!(
+            replies.reply("CLIENTINFO", None).as_deref(),
+            Some("ACTION CLIENTINFO PING SOURCE TIME VERSION")
+        );
+        assert!(replies.reply("TIME", None).is_some());
+        assert_eq!(replies.reply("ACTION", Some("waves")), None);
+        assert_eq!(replies.reply("FINGER", None), None);
+        assert_eq!(CtcpReplies::disabled().reply("VERSION", None), None);
+    }
+
+    #[test]
+    fn limiter_allows_bursts_within_window() {
+        let config = CtcpReplies::new().with_rate_limit(2, Duration::from_secs(10));
+        let mut limiter = ReplyLimiter::default();
+        let start = Instant::now();
+
+        assert!(limiter.allow(&config, start));
+        assert!(limiter.allow(&config, start + Duration::from_secs(1)));
+        assert!(!limiter.allow(&config, start + Duration::from_secs(2)));
+        assert!(limiter.allow(&config, start + Duration::from_secs(10)));
+        assert!(!limiter.allow(&config, start + Duration::from_secs(10)));
+    }
+
+    #[tokio::test]
+    async fn client_answers_queries_within_rate_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut lines, mut write) = accept_and_register(&listener).await;
+            write
+                .write_all(
+                    b":alice!a@h PRIVMSG botty :\x01VERSION\x01\r\n\
+                      :alice!a@h PRIVMSG botty :\x01PING 1234\x01\r\n\
+                      :alice!a@h PRIVMSG botty :\x01VERSION\x01\r\n\
+                      :alice!a@h PRIVMSG botty :done\r\n",
+                )
+                .await
+                .unwrap();
+            let mut received = vec![];
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if line == "CAP END" {
+                    continue;
+                }
+                let done = line.starts_with("QUIT");
+                received.push(line);
+                if done {
+                    break;
+                }
+            }
+            received
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_flood_control(FloodControl::unlimited())
+            .with_ctcp_replies(
+                CtcpReplies::new()
+                    .with_version("botty 1.0")
+                    .with_rate_limit(2, Duration::from_secs(60)),
+            )
+            .connect()
+            .await
+            .unwrap();
+        client.action("#rust", "waves").await.unwrap();
+        while let Some(msg) = client.recv().await.unwrap() {
+            if let Command::Privmsg { message, .. } = &msg.command
+                && message == "done"
+            {
+                // QUIT skips the queue, so let the replies go out first.
+                assert!(client.flush(Duration::from_secs(5)).await);
+                client.quit(None).await.unwrap();
+                break;
+            }
+        }
+
+        assert_eq!(
+            server.await.unwrap(),
+            [
+                "PRIVMSG #rust :\x01ACTION waves\x01",
+                "NOTICE alice :\x01VERSION botty 1.0\x01",
+                "NOTICE alice :\x01PING 1234\x01",
+                "QUIT",
+            ]
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! Decoding incoming lines that aren't necessarily UTF-8.
+//!
+//! IRC is a byte protocol, and plenty of older clients still send latin-1 or CP1252.
+//! Every line is tried as UTF-8 first; only if that fails is it decoded with a legacy
+//! encoding, either the one configured for the channel it concerns or the fallback.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+
+pub use encoding_rs::Encoding;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::casemap::CaseMapping;
+use crate::encode::MAX_TAGS_LEN;
+
+/// How to decode lines that aren't valid UTF-8.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    fallback: &'static Encoding,
+    /// Folded channel name → encoding.
+    channels: HashMap<String, &'static Encoding>,
+}
+
+impl Default for Decoder {
+    /// Falls back to Windows-1252, which covers latin-1 and is what most legacy
+    /// Western clients actually send.
+    fn default() -> Self {
+        Self {
+            fallback: encoding_rs::WINDOWS_1252,
+            channels: HashMap::new(),
+        }
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The encoding to use for lines that aren't UTF-8, e.g. `encoding_rs::ISO_8859_2`.
+    pub fn with_fallback(mut self, encoding: &'static Encoding) -> Self {
+        self.fallback = encoding;
+        self
+    }
+
+    /// Uses `encoding` instead of the fallback for non-UTF-8 lines about `channel`.
+    pub fn with_channel(mut self, channel: &str, encoding: &'static Encoding) -> Self {
+        self.channels.insert(fold(channel), encoding);
+        self
+    }
+
+    /// Decodes a raw line, which may still end in CR LF. Never fails: bytes that
+    /// aren't valid in the chosen encoding become U+FFFD.
+    pub fn decode<'a>(&self, line: &'a [u8]) -> Cow<'a, str> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if let Ok(utf8) = std::str::from_utf8(line) {
+            return Cow::Borrowed(utf8);
+        }
+        let encoding = self.channel_encoding(line).unwrap_or(self.fallback);
+        encoding.decode_without_bom_handling(line).0
+    }
+
+    /// The override for the first middle parameter naming a configured channel.
+    fn channel_encoding(&self, line: &[u8]) -> Option<&'static Encoding> {
+        if self.channels.is_empty() {
+            return None;
+        }
+        let mut words = line.split(|&b| b == b' ').filter(|w| !w.is_empty());
+        let mut word = words.next()?;
+        if word.starts_with(b"@") {
+            word = words.next()?;
+        }
+        if word.starts_with(b":") {
+            words.next()?; // the command
+        }
+        words
+            .take_while(|param| !param.starts_with(b":"))
+            .filter_map(|param| std::str::from_utf8(param).ok())
+            .find_map(|param| self.channels.get(&fold(param)).copied())
+    }
+}
+
+/// Channel keys are folded once up front. ISUPPORT isn't known when the decoder is
+/// configured, so this uses the default casemapping.
+fn fold(channel: &str) -> String {
+    CaseMapping::default().fold(channel)
+}
+
+/// What [`LineReader::read`] found.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Read<'a> {
+    /// A raw line, still ending in its CR LF unless the connection closed mid-line.
+    Line(&'a [u8]),
+    /// A line longer than allowed, dropped up to and including its LF. Carries how
+    /// many bytes were dropped.
+    TooLong(usize),
+    Closed,
+}
+
+/// Splits the byte stream into lines without buffering more than one allowed line.
+///
+/// All state lives here rather than in the future, so a read cancelled by `select!`
+/// resumes where it stopped.
+#[derive(Debug, Default)]
+pub(crate) struct LineReader {
+    buf: Vec<u8>,
+    /// Bytes of an overlong line thrown away so far.
+    dropped: usize,
+}
+
+impl LineReader {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Longest line a server may send: the tags allowance plus `linelen`, which
+    /// counts the CR LF.
+    pub(crate) fn max_len(linelen: usize) -> usize {
+        MAX_TAGS_LEN + linelen
+    }
+
+    pub(crate) async fn read<R>(&mut self, reader: &mut R, max_len: usize) -> io::Result<Read<'_>>
+    where
+        R: AsyncBufRead + Unpin,
+    {
+        if self.buf.ends_with(b"\n") {
+            self.buf.clear();
+        }
+        loop {
+            let available = reader.fill_buf().await?;
+            if available.is_empty() {
+                if self.buf.is_empty() {
+                    return Ok(Read::Closed);
+                }
+                // The connection closed mid-line; hand over what there is.
+                self.buf.push(b'\n');
+                let line = &self.buf[..self.buf.len() - 1];
+                return Ok(Read::Line(line));
+            }
+            let (chunk, found) = match available.iter().position(|&b| b == b'\n') {
+                Some(i) => (&available[..=i], true),
+                None => (available, false),
+            };
+            let used = chunk.len();
+            if self.dropped == 0 && self.buf.len() + used <= max_len {
+                self.buf.extend_from_slice(chunk);
+            } else {
+                self.dropped += self.buf.len() + used;
+                self.buf.clear();
+            }
+            reader.consume(used);
+            if found {
+                if self.dropped > 0 {
+                    return Ok(Read::TooLong(std::mem::take(&mut self.dropped)));
+                }
+                return Ok(Read::Line(&self.buf));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf8_is_borrowed() {
+        let line = ":a!b@c PRIVMSG #rust :héllo\r\n".as_bytes();
+        assert!(matches!(
+            Decoder::new().decode(line),
+            Cow::Borrowed(":a!b@c PRIVMSG #rust :héllo")
+        ));
+    }
+
+    #[test]
+    fn falls_back_to_cp1252() {
+        let line = b":a!b@c PRIVMSG #rust :caf\xe9 \x80\r\n";
+        assert_eq!(Decoder::new().decode(line), ":a!b@c PRIVMSG #rust :café €");
+    }
+
+    #[test]
+    fn channel_overrides_fallback() {
+        let decoder = Decoder::new().with_channel("#Russian", encoding_rs::KOI8_R);
+        // "привет" in KOI8-R.
+        let line = b"@time=x :a!b@c PRIVMSG #russian :\xd0\xd2\xc9\xd7\xc5\xd4";
+        assert_eq!(
+            decoder.decode(line),
+            "@time=x :a!b@c PRIVMSG #russian :привет"
+        );
+
+        let elsewhere = b":a!b@c PRIVMSG #rust :caf\xe9";
+        assert_eq!(decoder.decode(elsewhere), ":a!b@c PRIVMSG #rust :café");
+    }
+
+    #[test]
+    fn numerics_find_channel_in_later_params() {
+        let decoder = Decoder::new().with_channel("#russian", encoding_rs::KOI8_R);
+        let line = b":srv 332 botty #russian :\xd0\xd2\xc9\xd7\xc5\xd4";
+        assert_eq!(decoder.decode(line), ":srv 332 botty #russian :привет");
+    }
+
+    #[tokio::test]
+    async fn overlong_lines_are_dropped_whole() {
+        let data = b"PING :a\r\nPRIVMSG #rust :0123456789\r\nPING :b\r\ntail";
+        // A tiny buffer so lines arrive in several pieces.
+        let mut stream = tokio::io::BufReader::with_capacity(4, &data[..]);
+        let mut reader = LineReader::new();
+        let mut reads = vec![];
+        loop {
+            let read = reader.read(&mut stream, 12).await.unwrap();
+            let done = read == Read::Closed;
+            reads.push(match read {
+                Read::Line(line) => String::from_utf8(line.to_vec()).unwrap(),
+                Read::TooLong(n) => format!("dropped {n}"),
+                Read::Closed => "closed".into(),
+            });
+            if done {
+                break;
+            }
+        }
+        assert_eq!(
+            reads,
+            ["PING :a\r\n", "dropped 27", "PING :b\r\n", "tail", "closed"]
+        );
+    }
+}
@@ -0,0 +1,586 @@
+//! Serializes [`Command`] and [`Msg`] back into protocol lines.
+//!
+//! The encoder refuses to produce a line the server would misread: parameters that
+//! would be split or swallowed are rejected rather than passed through, and CR, LF
+//! and NUL can never reach the wire.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use crate::ctcp;
+use crate::irc_msg::{Command, Msg, Tags};
+use crate::prefix::Prefix;
+
+/// Maximum length of a line excluding tags and the trailing CRLF (RFC 1459).
+pub const MAX_LINE_LEN: usize = 510;
+
+/// Maximum length of the tags section, including the leading `@` and trailing space.
+pub const MAX_TAGS_LEN: usize = 8191;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EncodeError {
+    /// The command verb is empty or contains characters other than letters and digits.
+    InvalidCommand(String),
+    /// A parameter that is not last is empty, starts with `:` or contains whitespace.
+    InvalidParam(String),
+    /// The source is empty or contains a space.
+    InvalidSource(String),
+    /// A tag key is empty or contains a reserved character.
+    InvalidTag(String),
+    /// The value contains CR, LF or NUL, which would end or corrupt the line.
+    ForbiddenChar(String),
+    /// The encoded line is longer than the protocol allows.
+    TooLong { len: usize, max: usize },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::InvalidCommand(c) => write!(f, "invalid command {:?}", c),
+            EncodeError::InvalidParam(p) => write!(f, "invalid middle parameter {:?}", p),
+            EncodeError::InvalidSource(s) => write!(f, "invalid source {:?}", s),
+            EncodeError::InvalidTag(t) => write!(f, "invalid tag key {:?}", t),
+            EncodeError::ForbiddenChar(v) => write!(f, "CR, LF or NUL in {:?}", v),
+            EncodeError::TooLong { len, max } => {
+                write!(f, "line is {} bytes, maximum is {}", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl Command {
+    /// Encodes the command as a protocol line without tags, source or CRLF.
+    pub fn encode(&self) -> Result<String, EncodeError> {
+        encode_line(&Tags::new(), None, self)
+    }
+
+    /// Like [`Command::encode`], but allows up to `max_len` bytes (excluding CRLF) for
+    /// servers that advertise a larger `LINELEN`.
+    pub fn encode_within(&self, max_len: usize) -> Result<String, EncodeError> {
+        encode_line_within(&Tags::new(), None, self, max_len)
+    }
+
+    /// The verb, middle parameters and trailing parameter for this command.
+    fn wire_parts(&self) -> (String, Vec<&str>, Option<Cow<'_, str>>) {
+        match self {
+            Command::Ping { token } => ("PING".into(), vec![], token.as_deref().map(Cow::from)),
+            Command::Join { channel, message } => (
+                "JOIN".into(),
+                vec![channel.as_str()],
+                message.as_deref().map(Cow::from),
+            ),
+            Command::Part { channel, message } => (
+                "PART".into(),
+                vec![channel.as_str()],
+                message.as_deref().map(Cow::from),
+            ),
+            Command::Privmsg { reply_to, message } => (
+                "PRIVMSG".into(),
+                vec![reply_to.as_str()],
+                Some(message.into()),
+            ),
+            Command::Notice { channel, message } => (
+                "NOTICE".into(),
+                vec![channel.as_str()],
+                Some(message.into()),
+            ),
+            Command::Ctcp {
+                target,
+                verb,
+                params,
+                reply,
+            } => (
+                if *reply { "NOTICE" } else { "PRIVMSG" }.into(),
+                vec![target.as_str()],
+                Some(ctcp::wrap(verb, params.as_deref()).into()),
+            ),
+            Command::Nick { nick } => last_if_needed("NICK", vec![nick]),
+            Command::Quit { message } => ("QUIT".into(), vec![], message.as_deref().map(Cow::from)),
+            Command::Kick {
+                channel,
+                nick,
+                message,
+            } => (
+                "KICK".into(),
+                vec![channel.as_str(), nick.as_str()],
+                message.as_deref().map(Cow::from),
+            ),
+            Command::Mode {
+                target,
+                modes,
+                args,
+            } => last_if_needed(
+                "MODE",
+                std::iter::once(target)
+                    .chain(modes)
+                    .chain(args)
+                    .map(String::as_str)
+                    .collect(),
+            ),
+            Command::Topic { channel, topic } => (
+                "TOPIC".into(),
+                vec![channel.as_str()],
+                topic.as_deref().map(Cow::from),
+            ),
+            Command::Invite { nick, channel } => last_if_needed("INVITE", vec![nick, channel]),
+            Command::Error { message } => ("ERROR".into(), vec![], Some(message.into())),
+            Command::Away { message } => ("AWAY".into(), vec![], message.as_deref().map(Cow::from)),
+            Command::Cap {
+                target,
+                subcommand,
+                more,
+                caps,
+            } => {
+                let mut middle: Vec<&str> = target.iter().map(String::as_str).collect();
+                middle.push(subcommand);
+                if *more {
+                    middle.push("*");
+                }
+                ("CAP".into(), middle, caps.as_deref().map(Cow::from))
+            }
+            Command::Authenticate { data } => last_if_needed("AUTHENTICATE", vec![data]),
+            Command::Account { account } => {
+                last_if_needed("ACCOUNT", vec![account.as_deref().unwrap_or("*")])
+            }
+            Command::Chghost { user, host } => last_if_needed("CHGHOST", vec![user, host]),
+            Command::Numeric {
+                code,
+                args,
+                trailing,
+            } => (
+                format!("{:03}", code.code()),
+                args.iter().map(String::as_str).collect(),
+                trailing.as_deref().map(Cow::from),
+            ),
+            Command::Raw { command, args } => {
+                last_if_needed(command, args.iter().map(String::as_str).collect())
+            }
+        }
+    }
+}
+
+/// Splits off the last parameter as trailing, but only when it needs the trailing form.
+fn last_if_needed<'a>(
+    verb: &str,
+    mut params: Vec<&'a str>,
+) -> (String, Vec<&'a str>, Option<Cow<'a, str>>) {
+    let trailing = match params.last() {
+        Some(last) if needs_trailing(last) => params.pop().map(Cow::from),
+        _ => None,
+    };
+    (verb.to_owned(), params, trailing)
+}
+
+impl Msg {
+    /// Encodes the message, including tags and source, without the trailing CRLF.
+    pub fn encode(&self) -> Result<String, EncodeError> {
+        let source = self.source.as_ref().map(Prefix::to_string);
+        encode_line(&self.tags, source.as_deref(), &self.command)
+    }
+}
+
+/// Encodes a full line from its parts.
+pub fn encode_line(
+    tags: &Tags,
+    source: Option<&str>,
+    command: &Command,
+) -> Result<String, EncodeError> {
+    encode_line_within(tags, source, command, MAX_LINE_LEN)
+}
+
+/// Encodes a full line, allowing up to `max_len` bytes excluding tags and CRLF.
+pub fn encode_line_within(
+    tags: &Tags,
+    source: Option<&str>,
+    command: &Command,
+    max_len: usize,
+) -> Result<String, EncodeError> {
+    let mut line = encode_tags(tags)?;
+    let tags_len = line.len();
+
+    if let Some(source) = source {
+        if source.is_empty() || source.contains(' ') {
+            return Err(EncodeError::InvalidSource(source.to_owned()));
+        }
+        check_forbidden(source)?;
+        line.push(':');
+        line.push_str(source);
+        line.push(' ');
+    }
+
+    let (verb, middle, trailing) = command.wire_parts();
+    if verb.is_empty() || !verb.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return Err(EncodeError::InvalidCommand(verb));
+    }
+    line.push_str(&verb);
+
+    for param in middle {
+        if needs_trailing(param) {
+            return Err(EncodeError::InvalidParam(param.to_owned()));
+        }
+        check_forbidden(param)?;
+        line.push(' ');
+        line.push_str(param);
+    }
+    if let Some(trailing) = trailing {
+        check_forbidden(&trailing)?;
+        line.push_str(" :");
+        line.push_str(&trailing);
+    }
+
+    let len = line.len() - tags_len;
+    if len > max_len {
+        return Err(EncodeError::TooLong { len, max: max_len });
+    }
+    Ok(line)
+}
+
+/// Encodes tags as `@key=value;key2 ` (with the trailing space), or nothing if empty.
+fn encode_tags(tags: &Tags) -> Result<String, EncodeError> {
+    if tags.is_empty() {
+        return Ok(String::new());
+    }
+
+    let mut out = String::from("@");
+    for (i, (key, value)) in tags.iter().enumerate() {
+        if key.is_empty() || key.contains(['=', ';', ' ', '\0', '\r', '\n']) {
+            return Err(EncodeError::InvalidTag(key.clone()));
+        }
+        if value.contains('\0') {
+            return Err(EncodeError::ForbiddenChar(value.clone()));
+        }
+        if i > 0 {
+            out.push(';');
+        }
+        out.push_str(key);
+        if !value.is_empty() {
+            out.push('=');
+            out.push_str(&escape_tag_value(value));
+        }
+    }
+    out.push(' ');
+
+    if out.len() > MAX_TAGS_LEN {
+        return Err(EncodeError::TooLong {
+            len: out.len(),
+            max: MAX_TAGS_LEN,
+        });
+    }
+    Ok(out)
+}
+
+/// Escapes a tag value per the IRCv3 message-tags spec.
+pub fn escape_tag_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => out.push_str("\\:"),
+            ' ' => out.push_str("\\s"),
+            '\\' => out.push_str("\\\\"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// True if `param` can only be sent as the trailing parameter. Any ASCII whitespace
+/// counts, not just space, since some parsers split middle parameters on tabs too.
+fn needs_trailing(param: &str) -> bool {
+    param.is_empty() || param.starts_with(':') || param.contains(|c: char| c.is_ascii_whitespace())
+}
+
+fn check_forbidden(value: &str) -> Result<(), EncodeError> {
+    if value.contains(['\r', '\n', '\0']) {
+        Err(EncodeError::ForbiddenChar(value.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::irc_msg::MsgMeta;
+    use crate::numeric::Numeric;
+
+    fn parse(line: &str) -> Msg {
+        Msg::parse(line, SystemTime::UNIX_EPOCH.into()).unwrap()
+    }
+
+    #[test]
+    fn encode_privmsg_always_uses_trailing() {
+        let cmd = Command::Privmsg {
+            reply_to: "#channel".into(),
+            message: "hello".into(),
+        };
+        assert_eq!(cmd.encode().unwrap(), "PRIVMSG #channel :hello");
+    }
+
+    #[test]
+    fn encode_raw_uses_trailing_only_when_needed() {
+        let cmd = Command::Raw {
+            command: "MODE".into(),
+            args: vec!["#channel".into(), "+o".into(), "botty".into()],
+        };
+        assert_eq!(cmd.encode().unwrap(), "MODE #channel +o botty");
+
+        let cmd = Command::Raw {
+            command: "TOPIC".into(),
+            args: vec!["#channel".into(), ":) hi there".into()],
+        };
+        assert_eq!(cmd.encode().unwrap(), "TOPIC #channel ::) hi there");
+    }
+
+    #[test]
+    fn encode_numeric_pads_code() {
+        let cmd = Command::Numeric {
+            code: Numeric::RplWelcome,
+            args: vec!["botty".into()],
+            trailing: Some("Welcome".into()),
+        };
+        assert_eq!(cmd.encode().unwrap(), "001 botty :Welcome");
+    }
+
+    #[test]
+    fn encode_msg_with_tags_and_source() {
+        let mut tags = Tags::new();
+        tags.insert("+draft/reply".into(), "abc".into());
+        tags.insert("label".into(), "a; b\\c".into());
+        tags.insert("flag".into(), String::new());
+        let msg = Msg {
+            meta: MsgMeta {
+                raw: String::new(),
+                ts: SystemTime::UNIX_EPOCH.into(),
+            },
+            tags,
+            source: Some("nick!user@host".into()),
+            command: Command::Privmsg {
+                reply_to: "#channel".into(),
+                message: "hi".into(),
+            },
+        };
+
+        assert_eq!(
+            msg.encode().unwrap(),
+            r"@+draft/reply=abc;flag;label=a\:\sb\\c :nick!user@host PRIVMSG #channel :hi"
+        );
+    }
+
+    #[test]
+    fn encode_rejects_line_injection() {
+        let cmd = Command::Privmsg {
+            reply_to: "#channel".into(),
+            message: "hi\r\nQUIT :bye".into(),
+        };
+        assert!(matches!(cmd.encode(), Err(EncodeError::ForbiddenChar(_))));
+    }
+
+    #[test]
+    fn encode_rejects_bad_middle_params() {
+        for bad in ["", ":oops", "two words"] {
+            let cmd = Command::Join {
+                channel: bad.into(),
+                message: None,
+            };
+            assert_eq!(cmd.encode(), Err(EncodeError::InvalidParam(bad.into())));
+        }
+    }
+
+    #[test]
+    fn encode_rejects_too_long() {
+        let cmd = Command::Privmsg {
+            reply_to: "#channel".into(),
+            message: "a".repeat(MAX_LINE_LEN),
+        };
+        assert!(matches!(cmd.encode(), Err(EncodeError::TooLong { .. })));
+    }
+
+    fn middle_param() -> impl Strategy<Value = String> {
+        "[!-9;-~][!-~]{0,15}"
+    }
+
+    fn trailing_param() -> impl Strategy<Value = String> {
+        "[^\r\n\0]{0,40}"
+    }
+
+    /// Message text that won't be taken for CTCP.
+    fn text() -> impl Strategy<Value = String> {
+        "([^\x01\r\n\0][^\r\n\0]{0,39})?"
+    }
+
+    fn command() -> impl Strategy<Value = Command> {
+        prop_oneof![
+            proptest::option::of(trailing_param()).prop_map(|token| Command::Ping { token }),
+            (middle_param(), proptest::option::of(trailing_param()))
+                .prop_map(|(channel, message)| Command::Join { channel, message }),
+            (middle_param(), proptest::option::of(trailing_param()))
+                .prop_map(|(channel, message)| Command::Part { channel, message }),
+            (middle_param(), text())
+                .prop_map(|(reply_to, message)| Command::Privmsg { reply_to, message }),
+            (middle_param(), text())
+                .prop_map(|(channel, message)| Command::Notice { channel, message }),
+            (
+                middle_param(),
+                "[A-Z]{1,10}",
+                proptest::option::of("[^\x01\r\n\0]{0,30}"),
+                any::<bool>(),
+            )
+                .prop_map(|(target, verb, params, reply)| Command::Ctcp {
+                    target,
+                    verb,
+                    params,
+                    reply,
+                }),
+            (
+                0u16..1000,
+                proptest::collection::vec(middle_param(), 0..4),
+                proptest::option::of(trailing_param())
+            )
+                .prop_map(|(code, args, trailing)| Command::Numeric {
+                    code: Numeric::from_code(code),
+                    args,
+                    trailing,
+                }),
+            middle_param().prop_map(|nick| Command::Nick { nick }),
+            proptest::option::of(trailing_param()).prop_map(|message| Command::Quit { message }),
+            (
+                middle_param(),
+                middle_param(),
+                proptest::option::of(trailing_param())
+            )
+                .prop_map(|(channel, nick, message)| Command::Kick {
+                    channel,
+                    nick,
+                    message
+                }),
+            (
+                middle_param(),
+                proptest::option::of((
+                    "[+-][a-zA-Z]{1,4}",
+                    proptest::collection::vec(middle_param(), 0..3)
+                )),
+            )
+                .prop_map(|(target, modes)| {
+                    let (modes, args) = match modes {
+                        Some((m, args)) => (Some(m), args),
+                        None => (None, vec![]),
+                    };
+                    Command::Mode {
+                        target,
+                        modes,
+                        args,
+                    }
+                }),
+            (middle_param(), proptest::option::of(trailing_param()))
+                .prop_map(|(channel, topic)| Command::Topic { channel, topic }),
+            (middle_param(), middle_param())
+                .prop_map(|(nick, channel)| Command::Invite { nick, channel }),
+            trailing_param().prop_map(|message| Command::Error { message }),
+            proptest::option::of(trailing_param()).prop_map(|message| Command::Away { message }),
+            (
+                proptest::option::of("[a-z*]{1,9}"),
+                "(LS|LIST|REQ|ACK|NAK|NEW|DEL)",
+                any::<bool>(),
+                "[a-z-]{1,10}( [a-z-]{1,10}){0,3}",
+            )
+                .prop_map(|(target, subcommand, more, caps)| Command::Cap {
+                    // Only server replies can be continued.
+                    more: more && target.is_some(),
+                    target,
+                    subcommand,
+                    caps: Some(caps),
+                }),
+            "[A-Za-z0-9+/=]{1,40}".prop_map(|data| Command::Authenticate { data }),
+            proptest::option::of("[a-z]{1,9}").prop_map(|account| Command::Account { account }),
+            (middle_param(), middle_param())
+                .prop_map(|(user, host)| Command::Chghost { user, host }),
+            (
+                "(WHO|WHOIS|LUSERS|MOTD|FAIL)",
+                proptest::collection::vec(middle_param(), 0..4),
+                proptest::option::of(trailing_param()),
+            )
+                .prop_map(|(command, mut args, trailing)| {
+                    args.extend(trailing);
+                    Command::Raw { command, args }
+                }),
+        ]
+    }
+
+    fn tags() -> impl Strategy<Value = Tags> {
+        proptest::collection::btree_map(
+            "\\+?[a-z][a-z0-9-]{0,8}(/[a-z]{1,5})?",
+            "[^\0]{0,20}",
+            0..4,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(
+            tags in tags(),
+            source in proptest::option::of("[a-z]{1,9}(![a-z]{1,9}@[a-z.]{1,20})?"),
+            command in command(),
+        ) {
+            let msg = Msg {
+                meta: MsgMeta { raw: String::new(), ts: SystemTime::UNIX_EPOCH.into() },
+                tags,
+                source: source.as_deref().map(Prefix::parse),
+                command,
+            };
+            let encoded = msg.encode().unwrap();
+            let parsed = parse(&encoded);
+
+            prop_assert_eq!(&parsed.tags, &msg.tags);
+            prop_assert_eq!(&parsed.source, &msg.source);
+            prop_assert_eq!(&parsed.command, &msg.command);
+            prop_assert_eq!(parsed.meta.raw, encoded);
+        }
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct JoinTest {
+        desc: String,
+        atoms: crate::testing::Atoms,
+        matches: Vec<String>,
+    }
+
+    #[test]
+    fn parser_tests_msg_join() {
+        let tests: Vec<JoinTest> =
+            crate::testing::vectors(include_str!("../testdata/parser-tests/msg-join.yaml"));
+        assert!(!tests.is_empty());
+
+        for test in tests {
+            let atoms = test.atoms;
+            let command = Command::Raw {
+                command: atoms.verb,
+                args: atoms.params.clone(),
+            };
+            let encoded = encode_line(&atoms.tags, atoms.source.as_deref(), &command);
+
+            // Stricter than the spec on purpose: a tab is legal in a middle parameter,
+            // but some servers split on it, so only the trailing one may contain one.
+            let (_, middle) = atoms.params.split_last().unwrap_or((&String::new(), &[]));
+            if let Some(tabbed) = middle.iter().find(|p| p.contains('\t')) {
+                assert_eq!(encoded, Err(EncodeError::InvalidParam(tabbed.clone())));
+                continue;
+            }
+
+            let encoded = encoded.unwrap_or_else(|e| panic!("{}: {e}", test.desc));
+            assert!(
+                test.matches.contains(&encoded),
+                "{}: got {:?}",
+                test.desc,
+                encoded
+            );
+        }
+    }
+}
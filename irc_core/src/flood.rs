@@ -0,0 +1,394 @@
+//! Outgoing flood control.
+//!
+//! Servers disconnect clients that send faster than they allow ("Excess Flood"), so
+//! the writer task paces lines with a token bucket: a full bucket allows a short burst,
+//! after which lines go out at the refill rate. Long lines cost extra, matching how
+//! most ircds charge for them.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::registration::redact;
+
+/// Rate limits for outgoing lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FloodControl {
+    burst: u32,
+    refill: Duration,
+    long_line_bytes: usize,
+}
+
+impl Default for FloodControl {
+    /// A burst of 5 lines, then one line every 2 seconds, with each 300 bytes of a
+    /// line costing one extra line. Safe on every common ircd.
+    fn default() -> Self {
+        Self {
+            burst: 5,
+            refill: Duration::from_secs(2),
+            long_line_bytes: 300,
+        }
+    }
+}
+
+impl FloodControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many lines can be sent back to back before pacing kicks in.
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    /// How long it takes to earn back one line.
+    pub fn with_refill_interval(mut self, refill: Duration) -> Self {
+        self.refill = refill;
+        self
+    }
+
+    /// Every full `bytes` of a line costs one extra line. Zero disables the penalty.
+    pub fn with_long_line_penalty(mut self, bytes: usize) -> Self {
+        self.long_line_bytes = bytes;
+        self
+    }
+
+    /// Sends lines as fast as the socket takes them.
+    pub fn unlimited() -> Self {
+        Self::default().with_refill_interval(Duration::ZERO)
+    }
+
+    fn cost(&self, line: &str) -> f64 {
+        let penalty = match self.long_line_bytes {
+            0 => 0,
+            bytes => line.len() / bytes,
+        };
+        (1 + penalty) as f64
+    }
+}
+
+/// Tokens available for sending, refilled continuously over time.
+#[derive(Debug)]
+struct TokenBucket {
+    limits: FloodControl,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limits: FloodControl, now: Instant) -> Self {
+        Self {
+            limits,
+            tokens: limits.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.updated = now;
+        if self.limits.refill.is_zero() {
+            self.tokens = self.limits.burst as f64;
+            return;
+        }
+        let earned = elapsed.as_secs_f64() / self.limits.refill.as_secs_f64();
+        self.tokens = (self.tokens + earned).min(self.limits.burst as f64);
+    }
+
+    /// How long until `line` may be sent. A line costing more than the whole burst
+    /// only waits for a full bucket, so it can't stall forever.
+    fn delay(&mut self, line: &str, now: Instant) -> Duration {
+        self.refill(now);
+        let needed = self.limits.cost(line).min(self.limits.burst as f64);
+        if self.tokens >= needed {
+            return Duration::ZERO;
+        }
+        self.limits.refill.mul_f64(needed - self.tokens)
+    }
+
+    /// Charges for `line`. Priority lines may take the bucket into debt.
+    fn take(&mut self, line: &str) {
+        self.tokens -= self.limits.cost(line);
+    }
+}
+
+/// Lines that jump the queue and are never delayed: a late PONG gets us disconnected,
+/// a keepalive PING queued behind chatter would measure the queue rather than the lag,
+/// and a QUIT should not wait either.
+fn is_priority(line: &str) -> bool {
+    // Lines usually arrive with their CRLF, which a bare verb runs straight into.
+    let verb = line.split([' ', '\r', '\n']).next().unwrap_or_default();
+    ["PING", "PONG", "QUIT"]
+        .iter()
+        .any(|priority| verb.eq_ignore_ascii_case(priority))
+}
+
+/// Why the writer stopped.
+pub(crate) enum WriterExit {
+    /// Every sender was dropped and the queue is empty.
+    Closed,
+    /// Writing to the socket failed. Lines still queued are dropped.
+    Failed,
+}
+
+/// The writer's queue. Lines still in it when it is dropped, whether the write failed
+/// or the writer was cancelled because the read side ended, are discarded and taken
+/// off `depth`.
+struct Queue<'a> {
+    lines: VecDeque<String>,
+    depth: &'a AtomicUsize,
+}
+
+impl Drop for Queue<'_> {
+    fn drop(&mut self) {
+        if !self.lines.is_empty() {
+            warn!("dropping {} unsent lines", self.lines.len());
+            self.depth.fetch_sub(self.lines.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Drains `rx` into `writer`, pacing lines according to `limits`. `initial` lines are
+/// sent before anything from `rx`. `depth` counts lines handed over but not yet
+/// written, and is decremented as they go out or are dropped, including when the
+/// writer is cancelled.
+pub(crate) async fn run_writer<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    rx: &mut Receiver<String>,
+    limits: FloodControl,
+    depth: &AtomicUsize,
+    initial: Vec<String>,
+) -> WriterExit {
+    let mut bucket = TokenBucket::new(limits, Instant::now());
+    let mut pending = Queue {
+        lines: VecDeque::new(),
+        depth,
+    };
+    let queue = &mut pending.lines;
+    let mut priority = 0;
+    let mut closed = false;
+
+    depth.fetch_add(initial.len(), Ordering::Relaxed);
+    for line in initial {
+        enqueue(queue, &mut priority, line);
+    }
+
+    loop {
+        if queue.is_empty() {
+            if closed {
+                return WriterExit::Closed;
+            }
+            match rx.recv().await {
+                Some(line) => enqueue(queue, &mut priority, line),
+                None => return WriterExit::Closed,
+            }
+        }
+        while let Ok(line) = rx.try_recv() {
+            enqueue(queue, &mut priority, line);
+        }
+
+        let Some(next) = queue.front() else {
+            continue;
+        };
+        if priority == 0 {
+            let delay = bucket.delay(next, Instant::now());
+            if !delay.is_zero() {
+                // Keep listening while we wait, so a PONG can still go out at once.
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    line = rx.recv(), if !closed => match line {
+                        Some(line) => enqueue(queue, &mut priority, line),
+                        None => closed = true,
+                    },
+                }
+                continue;
+            }
+        }
+
+        let mut line = queue.pop_front().unwrap();
+        priority = priority.saturating_sub(1);
+        bucket.take(&line);
+        depth.fetch_sub(1, Ordering::Relaxed);
+
+        if !line.ends_with("\r\n") {
+            line.push_str("\r\n");
+        }
+        if let Err(e) = writer.write_all(line.as_bytes()).await {
+            error!("writer task error: {e:?}");
+            return WriterExit::Failed;
+        }
+        info!("==> {}", redact(line.trim_end()));
+    }
+}
+
+/// Queues `line`, putting priority lines after earlier priority lines but ahead of
+/// everything else.
+fn enqueue(queue: &mut VecDeque<String>, priority: &mut usize, line: String) {
+    if is_priority(&line) {
+        queue.insert(*priority, line);
+        *priority += 1;
+    } else {
+        queue.push_back(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncBufReadExt, BufReader, duplex};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::testing::capture_logs;
+
+    #[test]
+    fn bucket_allows_burst_then_paces() {
+        let limits = FloodControl::new()
+            .with_burst(2)
+            .with_refill_interval(Duration::from_secs(2));
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(limits, start);
+
+        for _ in 0..2 {
+            assert_eq!(bucket.delay("PRIVMSG #a :hi", start), Duration::ZERO);
+            bucket.take("PRIVMSG #a :hi");
+        }
+        assert_eq!(
+            bucket.delay("PRIVMSG #a :hi", start),
+            Duration::from_secs(2)
+        );
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            bucket.delay("PRIVMSG #a :hi", later),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn long_lines_cost_more() {
+        let limits = FloodControl::new().with_long_line_penalty(100);
+        assert_eq!(limits.cost("short"), 1.0);
+        assert_eq!(limits.cost(&"x".repeat(250)), 3.0);
+        assert_eq!(limits.with_long_line_penalty(0).cost(&"x".repeat(250)), 1.0);
+    }
+
+    #[test]
+    fn priority_lines_jump_the_queue() {
+        let mut queue = VecDeque::new();
+        let mut priority = 0;
+        for line in ["PRIVMSG #a :1", "PONG :x", "PRIVMSG #a :2", "QUIT :bye"] {
+            enqueue(&mut queue, &mut priority, line.to_owned());
+        }
+        assert_eq!(
+            queue,
+            ["PONG :x", "QUIT :bye", "PRIVMSG #a :1", "PRIVMSG #a :2"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn writer_paces_lines_but_not_pongs() {
+        let (client_end, server_end) = duplex(4096);
+        let (tx, mut rx) = mpsc::channel(16);
+        let depth = Arc::new(AtomicUsize::new(0));
+        let limits = FloodControl::new()
+            .with_burst(1)
+            .with_refill_interval(Duration::from_secs(10));
+        let writer_depth = depth.clone();
+        tokio::spawn(async move {
+            run_writer(client_end, &mut rx, limits, &writer_depth, vec![]).await;
+        });
+
+        let mut lines = BufReader::new(server_end).lines();
+        let start = Instant::now();
+        for line in ["PRIVMSG #a :1", "PRIVMSG #a :2"] {
+            depth.fetch_add(1, Ordering::Relaxed);
+            tx.send(line.to_owned()).await.unwrap();
+        }
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PRIVMSG #a :1");
+
+        depth.fetch_add(1, Ordering::Relaxed);
+        tx.send("PONG :tok".to_owned()).await.unwrap();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PONG :tok");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(depth.load(Ordering::Relaxed), 1);
+
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PRIVMSG #a :2");
+        assert!(start.elapsed() >= Duration::from_secs(10));
+        assert_eq!(depth.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bare_quit_skips_a_full_bucket() {
+        let (client_end, server_end) = duplex(4096);
+        let (tx, mut rx) = mpsc::channel(16);
+        let depth = Arc::new(AtomicUsize::new(0));
+        let limits = FloodControl::new()
+            .with_burst(1)
+            .with_refill_interval(Duration::from_secs(10));
+        let writer_depth = depth.clone();
+        tokio::spawn(async move {
+            run_writer(client_end, &mut rx, limits, &writer_depth, vec![]).await;
+        });
+
+        let mut lines = BufReader::new(server_end).lines();
+        let start = Instant::now();
+        for line in [
+            "PRIVMSG #a :1\r\n",
+            "PRIVMSG #a :2\r\n",
+            "PRIVMSG #a :3\r\n",
+        ] {
+            depth.fetch_add(1, Ordering::Relaxed);
+            tx.send(line.to_owned()).await.unwrap();
+        }
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PRIVMSG #a :1");
+
+        for line in ["PONG\r\n", "QUIT\r\n"] {
+            depth.fetch_add(1, Ordering::Relaxed);
+            tx.send(line.to_owned()).await.unwrap();
+        }
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PONG");
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "QUIT");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(depth.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn writer_log_hides_nickserv_password() {
+        let (logs, _guard) = capture_logs();
+        let (client_end, server_end) = duplex(4096);
+        let (tx, mut rx) = mpsc::channel(16);
+        let depth = Arc::new(AtomicUsize::new(1));
+        let writer_depth = depth.clone();
+        tokio::spawn(async move {
+            run_writer(
+                client_end,
+                &mut rx,
+                FloodControl::new(),
+                &writer_depth,
+                vec![],
+            )
+            .await;
+        });
+
+        tx.send("PRIVMSG NickServ :REGAIN botty hunter2".to_owned())
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(server_end).lines();
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "PRIVMSG NickServ :REGAIN botty hunter2"
+        );
+
+        let logs = logs.contents();
+        assert!(logs.contains("==> PRIVMSG NickServ :REGAIN ***"), "{logs}");
+        assert!(!logs.contains("hunter2"), "{logs}");
+    }
+}
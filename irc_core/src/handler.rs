@@ -3,23 +3,31 @@ use std::{collections::HashMap, ops::ControlFlow, sync::Arc};
 
 use tokio::sync::Mutex;
 
-use crate::{client::Client, irc_msg};
+use crate::{
+    batch::Batch,
+    casemap::Nick,
+    channels::{Channel, Channels, Member, Topic},
+    client::Client,
+    irc_msg,
+    prefix::Prefix,
+    users::{User, UserEvent},
+};
 
-/// Information about when a user was last seen and what they said.
+/// Information about when a user was last seen and what they were doing.
 #[derive(Default, Clone)]
 pub struct SeenInfo {
     pub nick: String,
     pub last_seen: DateTime<Local>,
+    /// What they were doing, e.g. `saying: hello` or `leaving #rust`.
     pub message: String,
 }
 
 /// Shared mutable state for modules.
 #[derive(Default)]
 pub struct State {
-    pub seen: HashMap<String, SeenInfo>,
-    pub scores: HashMap<String, i32>,
+    pub seen: HashMap<Nick, SeenInfo>,
+    pub scores: HashMap<Nick, i32>,
     pub channels: Vec<String>,
-    pub names: Vec<String>,
 }
 
 /// Read/write context passed to handlers.
@@ -36,12 +44,82 @@ impl Context {
         let mut guard = self.state.lock().await;
         f(&mut guard)
     }
+
+    /// A snapshot of a channel we are in. Handlers see the state after the message
+    /// they are handling, so e.g. a user is already gone when their QUIT arrives.
+    pub fn channel(&self, name: &str) -> Option<Channel> {
+        self.channels(|channels| channels.get(name).cloned())
+    }
+
+    /// Everyone in `channel`, in no particular order. Empty if we aren't in it.
+    pub fn members(&self, channel: &str) -> Vec<Member> {
+        self.channels(|channels| {
+            channels
+                .get(channel)
+                .map(|c| c.members().cloned().collect())
+                .unwrap_or_default()
+        })
+    }
+
+    pub fn member(&self, channel: &str, nick: &str) -> Option<Member> {
+        self.channels(|channels| channels.get(channel)?.member(nick).cloned())
+    }
+
+    pub fn topic(&self, channel: &str) -> Option<Topic> {
+        self.channels(|channels| channels.get(channel)?.topic.clone())
+    }
+
+    /// The names of the channels `nick` shares with us.
+    pub fn common_channels(&self, nick: &str) -> Vec<String> {
+        self.channels(|channels| channels.of(nick).map(|c| c.name.clone()).collect())
+    }
+
+    /// What we know about a user we share a channel with.
+    pub fn user(&self, nick: &str) -> Option<User> {
+        self.client.shared.users.read().unwrap().get(nick).cloned()
+    }
+
+    fn channels<R>(&self, f: impl FnOnce(&Channels) -> R) -> R {
+        f(&self.client.shared.channels.read().unwrap())
+    }
 }
 
 #[async_trait::async_trait]
 pub trait Handler: Send + Sync {
     /// Return ControlFlow::Break(()) to stop processing further handlers.
     async fn handle(&self, ctx: &Context, msg: &irc_msg::Msg) -> ControlFlow<()>;
+
+    /// Called once registration completes, including after every reconnect.
+    async fn on_connect(&self, _ctx: &Context) {}
+
+    /// Called when the connection drops, before any reconnect attempt.
+    async fn on_disconnect(&self, _ctx: &Context) {}
+
+    /// Called after the message behind a [`UserEvent`] has gone through every
+    /// handler, so the trackers already reflect it.
+    async fn on_user_event(&self, _ctx: &Context, _event: &UserEvent) {}
+
+    /// Called with a batch once all of its messages have arrived. By default each
+    /// message goes to [`handle`](Self::handle) in turn, though breaking doesn't keep
+    /// it from other handlers. History goes to [`on_history`](Self::on_history)
+    /// instead.
+    async fn on_batch(&self, ctx: &Context, batch: &Batch) {
+        if batch.is_history() {
+            return self.on_history(ctx, batch).await;
+        }
+        for msg in &batch.messages {
+            let _ = self.handle(ctx, msg).await;
+        }
+    }
+
+    /// Called with messages from before we joined: playback sent by the server, or
+    /// what the bot fetched to catch up after reconnecting. Anything handlers have
+    /// already seen is left out. Ignored by default.
+    async fn on_history(&self, _ctx: &Context, _batch: &Batch) {}
+
+    /// Called once when the bot shuts down, before it sends QUIT. The place to
+    /// persist anything still held in memory.
+    async fn on_shutdown(&self, _ctx: &Context) {}
 }
 
 pub struct HandlerFn<F>(pub F);
@@ -61,10 +139,19 @@ pub trait PrivmsgHandler: Send + Sync {
     async fn handle_privmsg(
         &self,
         ctx: &Context,
-        source: &str,
+        source: &Prefix,
         channel: &str,
         message: &str,
     ) -> ControlFlow<()>;
+
+    /// See [`Handler::on_user_event`].
+    async fn on_user_event(&self, _ctx: &Context, _event: &UserEvent) {}
+
+    /// See [`Handler::on_history`].
+    async fn on_history(&self, _ctx: &Context, _batch: &Batch) {}
+
+    /// See [`Handler::on_shutdown`].
+    async fn on_shutdown(&self, _ctx: &Context) {}
 }
 
 #[async_trait::async_trait]
@@ -84,4 +171,16 @@ where
             ControlFlow::Continue(())
         }
     }
+
+    async fn on_user_event(&self, ctx: &Context, event: &UserEvent) {
+        PrivmsgHandler::on_user_event(self, ctx, event).await
+    }
+
+    async fn on_history(&self, ctx: &Context, batch: &Batch) {
+        PrivmsgHandler::on_history(self, ctx, batch).await
+    }
+
+    async fn on_shutdown(&self, ctx: &Context) {
+        PrivmsgHandler::on_shutdown(self, ctx).await
+    }
 }
@@ -0,0 +1,177 @@
+//! Fetching missed messages with the IRCv3 `draft/chathistory` extension, and
+//! keeping replayed history apart from what handlers have already seen.
+//!
+//! See <https://ircv3.net/specs/extensions/chathistory>.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+
+use crate::casemap::CaseMapping;
+use crate::irc_msg::Msg;
+
+/// A point in a conversation to page history from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageRef {
+    Timestamp(DateTime<Local>),
+    /// A message's `msgid` tag.
+    MsgId(String),
+}
+
+impl fmt::Display for MessageRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageRef::Timestamp(ts) => write!(
+                f,
+                "timestamp={}",
+                ts.with_timezone(&Utc)
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+            ),
+            MessageRef::MsgId(id) => write!(f, "msgid={id}"),
+        }
+    }
+}
+
+/// Which messages [`Client::chathistory`](crate::client::Client::chathistory) asks
+/// for. Bounds are exclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistorySelector {
+    /// The most recent messages, or only those after a point.
+    Latest(Option<MessageRef>),
+    Before(MessageRef),
+    After(MessageRef),
+    Around(MessageRef),
+    Between(MessageRef, MessageRef),
+}
+
+impl HistorySelector {
+    /// The CHATHISTORY parameters for up to `limit` messages in `target`.
+    pub(crate) fn args(&self, target: &str, limit: usize) -> Vec<String> {
+        let (subcommand, refs) = match self {
+            HistorySelector::Latest(None) => ("LATEST", vec!["*".to_owned()]),
+            HistorySelector::Latest(Some(at)) => ("LATEST", vec![at.to_string()]),
+            HistorySelector::Before(at) => ("BEFORE", vec![at.to_string()]),
+            HistorySelector::After(at) => ("AFTER", vec![at.to_string()]),
+            HistorySelector::Around(at) => ("AROUND", vec![at.to_string()]),
+            HistorySelector::Between(from, to) => {
+                ("BETWEEN", vec![from.to_string(), to.to_string()])
+            }
+        };
+        [subcommand.to_owned(), target.to_owned()]
+            .into_iter()
+            .chain(refs)
+            .chain([limit.to_string()])
+            .collect()
+    }
+}
+
+/// How many message ids [`Cursor`] remembers.
+const REMEMBERED_IDS: usize = 1000;
+
+/// What the bot has already handed to its handlers: the time of the latest message
+/// in each channel, and recent message ids.
+#[derive(Debug, Default)]
+pub(crate) struct Cursor {
+    latest: HashMap<String, DateTime<Local>>,
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Cursor {
+    /// Records a message that handlers have seen. `target` is where it was said.
+    pub fn observe(&mut self, msg: &Msg, target: &str, mapping: CaseMapping) {
+        let latest = self
+            .latest
+            .entry(mapping.fold(target))
+            .or_insert(msg.meta.ts);
+        *latest = (*latest).max(msg.meta.ts);
+        if let Some(id) = msg.tag("msgid")
+            && self.ids.insert(id.to_owned())
+        {
+            self.order.push_back(id.to_owned());
+            if self.order.len() > REMEMBERED_IDS
+                && let Some(oldest) = self.order.pop_front()
+            {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    /// True if handlers have already seen a message with this one's `msgid`.
+    pub fn is_repeat(&self, msg: &Msg) -> bool {
+        msg.tag("msgid").is_some_and(|id| self.ids.contains(id))
+    }
+
+    /// True if a replayed message from `target` is newer than what handlers have seen.
+    /// Messages without a `msgid` are judged by time alone.
+    pub fn is_new(&self, msg: &Msg, target: &str, mapping: CaseMapping) -> bool {
+        if msg.tag("msgid").is_some() {
+            return !self.is_repeat(msg);
+        }
+        self.since(target, mapping)
+            .is_none_or(|latest| msg.meta.ts > latest)
+    }
+
+    /// The time of the latest message seen in `target`, if any.
+    pub fn since(&self, target: &str, mapping: CaseMapping) -> Option<DateTime<Local>> {
+        self.latest.get(&mapping.fold(target)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(line: &str) -> Msg {
+        Msg::parse(line, Local::now()).unwrap()
+    }
+
+    #[test]
+    fn selector_args() {
+        let at = MessageRef::Timestamp(
+            DateTime::parse_from_rfc3339("2024-05-01T12:00:00.5+02:00")
+                .unwrap()
+                .into(),
+        );
+        assert_eq!(
+            HistorySelector::After(at.clone()).args("#rust", 50),
+            ["AFTER", "#rust", "timestamp=2024-05-01T10:00:00.500Z", "50"]
+        );
+        assert_eq!(
+            HistorySelector::Latest(None).args("alice", 10),
+            ["LATEST", "alice", "*", "10"]
+        );
+        assert_eq!(
+            HistorySelector::Between(MessageRef::MsgId("abc".into()), at).args("#rust", 5),
+            [
+                "BETWEEN",
+                "#rust",
+                "msgid=abc",
+                "timestamp=2024-05-01T10:00:00.500Z",
+                "5"
+            ]
+        );
+    }
+
+    #[test]
+    fn cursor_skips_what_was_already_seen() {
+        let mapping = CaseMapping::default();
+        let mut cursor = Cursor::default();
+        let live = msg("@msgid=1;time=2024-05-01T10:00:00.000Z :a!a@h PRIVMSG #rust :x++");
+        cursor.observe(&live, "#rust", mapping);
+
+        assert!(cursor.is_repeat(&live));
+        assert!(!cursor.is_new(&live, "#RUST", mapping));
+        let older = msg("@time=2024-05-01T09:00:00.000Z :b!b@h PRIVMSG #rust :old");
+        assert!(!cursor.is_new(&older, "#rust", mapping));
+        let newer = msg("@time=2024-05-01T11:00:00.000Z :b!b@h PRIVMSG #rust :new");
+        assert!(cursor.is_new(&newer, "#rust", mapping));
+        assert!(cursor.is_new(&older, "#go", mapping));
+        let unseen_id = msg("@msgid=2;time=2024-05-01T09:30:00.000Z :b!b@h PRIVMSG #rust :hi");
+        assert!(cursor.is_new(&unseen_id, "#rust", mapping));
+
+        assert_eq!(cursor.since("#rust", mapping), Some(live.meta.ts));
+        assert_eq!(cursor.since("#go", mapping), None);
+    }
+}
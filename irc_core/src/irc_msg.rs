@@ -1,5 +1,20 @@
+use std::collections::BTreeMap;
+
 use chrono::{DateTime, Local};
 
+use crate::ctcp;
+use crate::msg_ref::MsgRef;
+use crate::numeric::{Numeric, Reply};
+use crate::prefix::Prefix;
+
+/// IRCv3 message tags, keyed by tag name (including any vendor prefix).
+/// Tags sent without a value are stored with an empty string.
+pub type Tags = BTreeMap<String, String>;
+
+/// The most parameters a message can have. The last one may contain spaces even
+/// without a leading `:`.
+pub const MAX_PARAMS: usize = 15;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Command {
     Ping {
@@ -21,65 +36,224 @@ pub enum Command {
         channel: String,
         message: String,
     },
+    /// A CTCP query (sent as PRIVMSG), or a reply to one (sent as NOTICE), e.g.
+    /// `ACTION` for `/me`. The verb is always upper case.
+    Ctcp {
+        target: String,
+        verb: String,
+        params: Option<String>,
+        reply: bool,
+    },
+    Nick {
+        nick: String,
+    },
+    Quit {
+        message: Option<String>,
+    },
+    Kick {
+        channel: String,
+        nick: String,
+        message: Option<String>,
+    },
+    /// A channel or user mode change, or a mode query when `modes` is `None`.
+    Mode {
+        target: String,
+        modes: Option<String>,
+        args: Vec<String>,
+    },
+    /// A topic change, or a topic query when `topic` is `None`.
+    Topic {
+        channel: String,
+        topic: Option<String>,
+    },
+    Invite {
+        nick: String,
+        channel: String,
+    },
+    Error {
+        message: String,
+    },
+    /// IRCv3 `away-notify`, or setting our own away status. `None` means back.
+    Away {
+        message: Option<String>,
+    },
+    /// Capability negotiation. Server replies carry a `target` (our nick, or `*`);
+    /// client requests do not.
+    Cap {
+        target: Option<String>,
+        subcommand: String,
+        /// Set on all but the last line of a multi-line `LS` or `LIST` reply.
+        more: bool,
+        /// The space-separated capability list, or the version for a client `LS`.
+        caps: Option<String>,
+    },
+    Authenticate {
+        data: String,
+    },
+    /// IRCv3 `account-notify`. `None` means the user logged out.
+    Account {
+        account: Option<String>,
+    },
+    /// IRCv3 `chghost`: the user's username and/or hostname changed.
+    Chghost {
+        user: String,
+        host: String,
+    },
+    /// A numeric reply. [`Msg::reply`] gives typed access to its parameters.
     Numeric {
-        code: u16,
+        code: Numeric,
+        /// The parameters before `trailing`.
         args: Vec<String>,
+        /// The last parameter, only if it was sent as a trailing one: with a leading
+        /// `:`, or as the fifteenth. It is `None` otherwise, rather than a copy of the
+        /// first argument; [`Reply::text`] gives the last parameter either way.
+        ///
+        /// [`Reply::text`]: crate::numeric::Reply::text
         trailing: Option<String>,
     },
-    Other {},
     Raw {
         command: String,
         args: Vec<String>,
     },
 }
 
+const CAP_SUBCOMMANDS: &[&str] = &["LS", "LIST", "REQ", "ACK", "NAK", "NEW", "DEL", "END"];
+
 impl Command {
-    fn build_from_parts(parts: &CmdParts<'_>) -> Option<Command> {
+    pub(crate) fn build_from_parts(parts: &CmdParts<'_>) -> Option<Command> {
+        Self::build_typed(parts).or_else(|| {
+            Some(Command::Raw {
+                command: parts.command.into(),
+                args: parts.params().map(str::to_owned).collect(),
+            })
+        })
+    }
+
+    /// Builds a typed command, or returns None if the command is unknown or is missing
+    /// required parameters.
+    fn build_typed(parts: &CmdParts<'_>) -> Option<Command> {
+        let params: Vec<&str> = parts.params().collect();
+        let param = |i: usize| params.get(i).map(|p| (*p).to_owned());
+        // The text of a message or reason is its last parameter, whether or not the
+        // sender wrote it with a `:`.
+        let text = params.last().copied().filter(|_| params.len() > 1);
+
         match parts.command {
             "PING" => Some(Command::Ping {
                 token: parts.trailing_or_first().map(str::to_owned),
             }),
 
-            "PRIVMSG" => Some(Command::Privmsg {
-                reply_to: parts.first_arg()?.to_owned(),
-                message: parts.trailing.unwrap_or_default().to_owned(),
-            }),
+            "PRIVMSG" | "NOTICE" => {
+                let target = param(0)?;
+                let message = text.unwrap_or_default();
+                let reply = parts.command == "NOTICE";
+                Some(match ctcp::parse(message) {
+                    Some((verb, params)) => Command::Ctcp {
+                        target,
+                        verb,
+                        params,
+                        reply,
+                    },
+                    None if reply => Command::Notice {
+                        channel: target,
+                        message: message.to_owned(),
+                    },
+                    None => Command::Privmsg {
+                        reply_to: target,
+                        message: message.to_owned(),
+                    },
+                })
+            }
 
             "JOIN" => Some(Command::Join {
-                channel: parts.first_arg()?.to_owned(),
-                message: parts.trailing.map(str::to_owned),
+                channel: param(0)?,
+                message: text.map(str::to_owned),
             }),
 
             "PART" => Some(Command::Part {
-                channel: parts.first_arg()?.to_owned(),
-                message: parts.trailing.map(str::to_owned),
+                channel: param(0)?,
+                message: text.map(str::to_owned),
+            }),
+
+            "NICK" => Some(Command::Nick { nick: param(0)? }),
+
+            "QUIT" => Some(Command::Quit { message: param(0) }),
+
+            "KICK" => Some(Command::Kick {
+                channel: param(0)?,
+                nick: param(1)?,
+                message: param(2),
+            }),
+
+            "MODE" => Some(Command::Mode {
+                target: param(0)?,
+                modes: param(1),
+                args: params.iter().skip(2).map(|p| (*p).to_owned()).collect(),
+            }),
+
+            "TOPIC" => Some(Command::Topic {
+                channel: param(0)?,
+                topic: param(1),
+            }),
+
+            "INVITE" => Some(Command::Invite {
+                nick: param(0)?,
+                channel: param(1)?,
+            }),
+
+            "ERROR" => Some(Command::Error {
+                message: param(0).unwrap_or_default(),
             }),
 
-            "NOTICE" => Some(Command::Notice {
-                channel: parts.first_arg()?.to_owned(),
-                message: parts.trailing.unwrap_or_default().to_owned(),
+            "AWAY" => Some(Command::Away { message: param(0) }),
+
+            "CAP" => {
+                // Server replies look like `CAP <target> <subcommand> [*] [:caps]`, client
+                // requests like `CAP <subcommand> [:caps]`.
+                let (target, rest) = match params.get(1) {
+                    Some(sub) if CAP_SUBCOMMANDS.contains(sub) => (param(0), &params[1..]),
+                    _ => (None, &params[..]),
+                };
+                let (subcommand, rest) = rest.split_first()?;
+                let (more, rest) = match rest {
+                    ["*", caps] => (true, std::slice::from_ref(caps)),
+                    _ => (false, rest),
+                };
+                Some(Command::Cap {
+                    target,
+                    subcommand: (*subcommand).to_owned(),
+                    more,
+                    caps: rest.first().map(|c| (*c).to_owned()),
+                })
+            }
+
+            "AUTHENTICATE" => Some(Command::Authenticate { data: param(0)? }),
+
+            "ACCOUNT" => Some(Command::Account {
+                account: param(0).filter(|a| a != "*"),
+            }),
+
+            "CHGHOST" => Some(Command::Chghost {
+                user: param(0)?,
+                host: param(1)?,
             }),
 
             _ if parts.is_numeric() => Some(Command::Numeric {
-                code: parts.code()?,
+                code: Numeric::from_code(parts.code()?),
                 args: parts.args.iter().map(|s| (*s).to_owned()).collect(),
-                trailing: parts.trailing_or_first().map(str::to_owned),
+                trailing: parts.trailing.map(str::to_owned),
             }),
 
-            _ => Some(Command::Raw {
-                command: parts.command.into(),
-                args: parts.args.iter().copied().map(str::to_owned).collect(),
-            }),
+            _ => None,
         }
     }
 }
 
 #[derive(Debug)]
-struct CmdParts<'a> {
-    source: Option<&'a str>,
-    command: &'a str,
-    args: Vec<&'a str>,
-    trailing: Option<&'a str>,
+pub(crate) struct CmdParts<'a> {
+    pub command: &'a str,
+    pub args: Vec<&'a str>,
+    pub trailing: Option<&'a str>,
 }
 
 impl<'a> CmdParts<'a> {
@@ -97,9 +271,14 @@ impl<'a> CmdParts<'a> {
         self.args.first().copied()
     }
 
-    fn trailing_or_first<'t>(&'t self) -> Option<&'t str> {
+    fn trailing_or_first(&self) -> Option<&str> {
         self.trailing.or_else(|| self.first_arg())
     }
+
+    /// All parameters in order, with the trailing parameter (if any) last.
+    fn params(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.args.iter().copied().chain(self.trailing)
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -111,14 +290,15 @@ pub struct MsgMeta {
 #[derive(Debug, PartialEq)]
 pub struct Msg {
     pub meta: MsgMeta,
-    pub source: Option<String>, // entire prefix if present
+    pub tags: Tags,
+    pub source: Option<Prefix>,
     pub command: Command,
 }
 
 impl Msg {
-    pub fn nick(&self) -> Option<String> {
-        let nick = Self::source_to_nick(self.source.as_deref());
-        if nick.is_empty() { None } else { Some(nick) }
+    /// The sender's nick, or `None` if the message came from a server.
+    pub fn nick(&self) -> Option<&str> {
+        self.source.as_ref().and_then(Prefix::nick)
     }
 
     pub fn channel(&self) -> Option<String> {
@@ -129,63 +309,63 @@ impl Msg {
             Command::Join { channel, .. } => Some(channel.into()),
             Command::Part { channel, .. } => Some(channel.into()),
             Command::Notice { channel, .. } => Some(channel.into()),
+            Command::Ctcp { target, .. } => Some(target.into()),
+            Command::Kick { channel, .. } => Some(channel.into()),
+            Command::Topic { channel, .. } => Some(channel.into()),
+            Command::Invite { channel, .. } => Some(channel.into()),
             _ => None,
         }
     }
 
-    pub fn parse(line: &str, now: DateTime<Local>) -> Option<Msg> {
-        let meta = MsgMeta {
-            raw: line.to_owned(),
-            ts: now,
-        };
-
-        let parts = Self::tokenize_line(line)?;
-        let source = parts.source.map(|s| s.to_owned());
-
-        let command = Command::build_from_parts(&parts)?;
-        Some(Msg {
-            meta,
-            source,
-            command,
-        })
+    /// Returns the value of the tag `key`, if the server sent it.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(String::as_str)
     }
 
-    /// Extracts the nick from the IRC message source string.
-    /// The expected format is "nick!username@host". If the source does not contain '!',
-    /// the entire source string is returned as the nick. If the source is None, returns an empty string.
-    fn source_to_nick(source: Option<&str>) -> String {
-        source
-            .and_then(|s| s.split('!').next())
-            .unwrap_or("")
-            .to_owned()
+    /// Typed access to a numeric reply's parameters, or `None` for other commands.
+    pub fn reply(&self) -> Option<Reply<'_>> {
+        Reply::from_command(&self.command)
     }
 
-    fn tokenize_line<'a>(line: &'_ str) -> Option<CmdParts<'_>> {
-        let (before, trailing) = split_irc(line)?;
-        let mut it = before.split_ascii_whitespace();
-
-        let first = it.next()?;
-        let (source, command) = if first.starts_with(':') {
-            (Some(first.trim_start_matches(':')), it.next()?)
-        } else {
-            (None, first)
-        };
+    /// Parses a single line from the server. `now` is used as the timestamp unless the
+    /// server provided one via the `time` tag (IRCv3 `server-time`).
+    pub fn parse(line: &str, now: DateTime<Local>) -> Option<Msg> {
+        MsgRef::parse(line)?.to_msg(now)
+    }
+}
 
-        let args = it.collect();
-        Some(CmdParts {
-            source,
-            command,
-            args,
-            trailing,
-        })
+/// Splits a leading `@tags` segment off the line, if there is one.
+pub(crate) fn split_tags(line: &str) -> (Option<&str>, &str) {
+    match line.strip_prefix('@') {
+        Some(tagged) => match tagged.split_once(' ') {
+            Some((tags, rest)) => (Some(tags), rest.trim_start_matches(' ')),
+            None => (Some(tagged), ""),
+        },
+        None => (None, line),
     }
 }
 
-fn split_irc(line: &str) -> Option<(&str, Option<&str>)> {
-    let mut parts = line.splitn(2, " :");
-    let before = parts.next()?;
-    let trailing = parts.next();
-    Some((before, trailing))
+/// Unescapes a tag value per the IRCv3 message-tags spec. Unknown escapes drop the
+/// backslash, and a trailing lone backslash is dropped entirely.
+pub(crate) fn unescape_tag_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => out.push(';'),
+            Some('s') => out.push(' '),
+            Some('\\') => out.push('\\'),
+            Some('r') => out.push('\r'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
 }
 
 #[cfg(test)]
@@ -199,7 +379,6 @@ mod tests {
     #[test]
     fn from_parts_privmsg() {
         let got = Command::build_from_parts(&CmdParts {
-            source: Some("nickname!+username@host"),
             command: "PRIVMSG",
             args: vec!["#channel"],
             trailing: Some("chat chat chat"),
@@ -218,7 +397,6 @@ mod tests {
     #[test]
     fn from_parts_notice() {
         let got = Command::build_from_parts(&CmdParts {
-            source: Some("irc.example.com"),
             command: "NOTICE",
             args: vec!["*"],
             trailing: Some("*** Looking up your hostname..."),
@@ -233,6 +411,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_ctcp() {
+        let msg = Msg::parse(
+            ":nick!u@h PRIVMSG #chan :\x01ACTION waves\x01",
+            FAKE_NOW.into(),
+        );
+        assert_eq!(
+            msg.unwrap().command,
+            Command::Ctcp {
+                target: "#chan".into(),
+                verb: "ACTION".into(),
+                params: Some("waves".into()),
+                reply: false,
+            }
+        );
+
+        let msg = Msg::parse(
+            ":nick!u@h NOTICE botty :\x01version irssi\x01",
+            FAKE_NOW.into(),
+        );
+        assert_eq!(
+            msg.unwrap().command,
+            Command::Ctcp {
+                target: "botty".into(),
+                verb: "VERSION".into(),
+                params: Some("irssi".into()),
+                reply: true,
+            }
+        );
+    }
+
     #[test]
     fn parse_privmsg() {
         let raw = ":nick!username@host PRIVMSG #channel :chat chat chat";
@@ -248,6 +457,7 @@ mod tests {
                     reply_to: "#channel".into(),
                     message: "chat chat chat".into(),
                 },
+                tags: Tags::new(),
                 source: Some("nick!username@host".into()),
             },
             got
@@ -265,9 +475,10 @@ mod tests {
                     raw: raw.into(),
                     ts: FAKE_NOW.into()
                 },
+                tags: Tags::new(),
                 source: Some("irc.example.com".into()),
                 command: Command::Numeric {
-                    code: 001,
+                    code: Numeric::RplWelcome,
                     args: vec!["nickname".into()],
                     trailing: Some("Welcome to IRC you cheeky nickname!user@host".into())
                 },
@@ -287,9 +498,10 @@ mod tests {
                     raw: raw.into(),
                     ts: FAKE_NOW.into(),
                 },
+                tags: Tags::new(),
                 source: Some("irc.example.com".into()),
                 command: Command::Numeric {
-                    code: 332,
+                    code: Numeric::RplTopic,
                     args: vec!["nickname".into(), "#channel".into()],
                     trailing: Some("This is the new topic".into())
                 }
@@ -298,6 +510,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn numeric_without_trailing_param() {
+        let got = Msg::parse(":irc.example.com 366 nickname #channel", FAKE_NOW.into()).unwrap();
+        assert_eq!(
+            got.command,
+            Command::Numeric {
+                code: Numeric::RplEndOfNames,
+                args: vec!["nickname".into(), "#channel".into()],
+                trailing: None,
+            }
+        );
+        assert_eq!(got.reply().unwrap().text(), Some("#channel"));
+    }
+
     #[test]
     fn parse_join() {
         let raw = ":nick!username@host JOIN #channel :hello world";
@@ -313,6 +539,7 @@ mod tests {
                     channel: "#channel".into(),
                     message: Some("hello world".into()),
                 },
+                tags: Tags::new(),
                 source: Some("nick!username@host".into()),
             },
             got
@@ -335,18 +562,226 @@ mod tests {
                 command: Command::Ping {
                     token: Some("foo.example.com".into())
                 },
+                tags: Tags::new(),
                 source: None,
             },
             got
         )
     }
 
+    #[test]
+    fn parse_raw_keeps_trailing() {
+        let raw = ":irc.example.com FAIL CHATHISTORY INVALID_TARGET #channel :No such channel";
+        let got = Msg::parse(raw, FAKE_NOW.into()).unwrap();
+
+        assert_eq!(
+            got.command,
+            Command::Raw {
+                command: "FAIL".into(),
+                args: vec![
+                    "CHATHISTORY".into(),
+                    "INVALID_TARGET".into(),
+                    "#channel".into(),
+                    "No such channel".into()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_missing_params_falls_back_to_raw() {
+        let got = Msg::parse(":nick!user@host KICK #channel", FAKE_NOW.into()).unwrap();
+
+        assert_eq!(
+            got.command,
+            Command::Raw {
+                command: "KICK".into(),
+                args: vec!["#channel".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_nick_quit_kick() {
+        let parse = |raw| Msg::parse(raw, FAKE_NOW.into()).unwrap().command;
+
+        assert_eq!(
+            parse(":old!user@host NICK :new"),
+            Command::Nick { nick: "new".into() }
+        );
+        assert_eq!(
+            parse(":nick!user@host QUIT :Quit: bye"),
+            Command::Quit {
+                message: Some("Quit: bye".into())
+            }
+        );
+        assert_eq!(
+            parse(":nick!user@host QUIT"),
+            Command::Quit { message: None }
+        );
+        assert_eq!(
+            parse(":op!user@host KICK #channel victim :behave"),
+            Command::Kick {
+                channel: "#channel".into(),
+                nick: "victim".into(),
+                message: Some("behave".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_mode_topic_invite() {
+        let parse = |raw| Msg::parse(raw, FAKE_NOW.into()).unwrap().command;
+
+        assert_eq!(
+            parse(":op!user@host MODE #channel +ov alice bob"),
+            Command::Mode {
+                target: "#channel".into(),
+                modes: Some("+ov".into()),
+                args: vec!["alice".into(), "bob".into()],
+            }
+        );
+        assert_eq!(
+            parse(":botty MODE botty :+i"),
+            Command::Mode {
+                target: "botty".into(),
+                modes: Some("+i".into()),
+                args: vec![],
+            }
+        );
+        assert_eq!(
+            parse(":nick!user@host TOPIC #channel :new topic"),
+            Command::Topic {
+                channel: "#channel".into(),
+                topic: Some("new topic".into()),
+            }
+        );
+        assert_eq!(
+            parse(":nick!user@host INVITE botty :#channel"),
+            Command::Invite {
+                nick: "botty".into(),
+                channel: "#channel".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_ircv3_commands() {
+        let parse = |raw| Msg::parse(raw, FAKE_NOW.into()).unwrap().command;
+
+        assert_eq!(
+            parse(":irc.example.com CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL"),
+            Command::Cap {
+                target: Some("*".into()),
+                subcommand: "LS".into(),
+                more: true,
+                caps: Some("multi-prefix sasl=PLAIN,EXTERNAL".into()),
+            }
+        );
+        assert_eq!(
+            parse("CAP LS 302"),
+            Command::Cap {
+                target: None,
+                subcommand: "LS".into(),
+                more: false,
+                caps: Some("302".into()),
+            }
+        );
+        assert_eq!(
+            parse("AUTHENTICATE +"),
+            Command::Authenticate { data: "+".into() }
+        );
+        assert_eq!(
+            parse(":nick!user@host ACCOUNT *"),
+            Command::Account { account: None }
+        );
+        assert_eq!(
+            parse(":nick!user@host ACCOUNT alice"),
+            Command::Account {
+                account: Some("alice".into())
+            }
+        );
+        assert_eq!(
+            parse(":nick!user@host AWAY"),
+            Command::Away { message: None }
+        );
+        assert_eq!(
+            parse(":nick!user@host CHGHOST newuser new.host"),
+            Command::Chghost {
+                user: "newuser".into(),
+                host: "new.host".into(),
+            }
+        );
+        assert_eq!(
+            parse("ERROR :Closing link"),
+            Command::Error {
+                message: "Closing link".into()
+            }
+        );
+    }
+
     #[test]
     fn parse_notice() {
         let raw = ":irc.example.com NOTICE * :*** Looking up your hostname...";
         let got = Msg::parse(raw, FAKE_NOW.into());
 
-        assert!(!got.is_none());
+        assert!(got.is_some());
+    }
+
+    #[test]
+    fn parse_tags_server_time() {
+        let raw =
+            "@time=2023-01-02T03:04:05.678Z;msgid=abc123 :nick!username@host PRIVMSG #channel :hi";
+        let got = Msg::parse(raw, FAKE_NOW.into()).unwrap();
+
+        assert_eq!(got.tag("msgid"), Some("abc123"));
+        assert_eq!(
+            got.meta.ts,
+            DateTime::parse_from_rfc3339("2023-01-02T03:04:05.678Z").unwrap()
+        );
+        assert_eq!(got.nick(), Some("nick"));
+        assert_eq!(
+            got.command,
+            Command::Privmsg {
+                reply_to: "#channel".into(),
+                message: "hi".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_tags_bad_time_falls_back_to_now() {
+        let raw = "@time=yesterday PING :token";
+        let got = Msg::parse(raw, FAKE_NOW.into()).unwrap();
+
+        assert_eq!(got.meta.ts, DateTime::<Local>::from(FAKE_NOW));
+        assert_eq!(got.tag("time"), Some("yesterday"));
+    }
+
+    #[test]
+    fn parse_tags_unescapes_values() {
+        let raw = r"@a=semi\:colon;b=sp\sace;c=back\\slash;d=cr\rlf\n;e=unknown\x;f=trailing\ PING";
+        let got = Msg::parse(raw, FAKE_NOW.into()).unwrap();
+
+        assert_eq!(got.tag("a"), Some("semi;colon"));
+        assert_eq!(got.tag("b"), Some("sp ace"));
+        assert_eq!(got.tag("c"), Some("back\\slash"));
+        assert_eq!(got.tag("d"), Some("cr\rlf\n"));
+        assert_eq!(got.tag("e"), Some("unknownx"));
+        assert_eq!(got.tag("f"), Some("trailing"));
+        assert_eq!(got.command, Command::Ping { token: None });
+    }
+
+    #[test]
+    fn parse_tags_without_values() {
+        let raw = "@+draft/reply=;account;+example.com/foo=bar :nick!u@h JOIN #channel";
+        let got = Msg::parse(raw, FAKE_NOW.into()).unwrap();
+
+        assert_eq!(got.tag("+draft/reply"), Some(""));
+        assert_eq!(got.tag("account"), Some(""));
+        assert_eq!(got.tag("+example.com/foo"), Some("bar"));
+        assert_eq!(got.tag("missing"), None);
+        assert_eq!(got.channel().as_deref(), Some("#channel"));
     }
 
     #[test]
@@ -356,10 +791,11 @@ mod tests {
                 raw: String::new(),
                 ts: FAKE_NOW.into(),
             },
+            tags: Tags::new(),
             source: Some("nickname!username@host".into()),
-            command: Command::Other {},
+            command: Command::Quit { message: None },
         };
-        assert_eq!(msg.nick(), Some("nickname".into()));
+        assert_eq!(msg.nick(), Some("nickname"));
     }
 
     #[test]
@@ -369,21 +805,23 @@ mod tests {
                 raw: String::new(),
                 ts: FAKE_NOW.into(),
             },
+            tags: Tags::new(),
             source: None,
-            command: Command::Other {},
+            command: Command::Quit { message: None },
         };
         assert_eq!(msg.nick(), None);
     }
 
     #[test]
-    fn msg_nick_extraction_no_nick() {
+    fn msg_nick_extraction_server_prefix() {
         let msg = Msg {
             meta: MsgMeta {
                 raw: String::new(),
                 ts: FAKE_NOW.into(),
             },
-            source: Some("".into()),
-            command: Command::Other {},
+            tags: Tags::new(),
+            source: Some("irc.example.com".into()),
+            command: Command::Quit { message: None },
         };
         assert_eq!(msg.nick(), None);
     }
@@ -395,6 +833,7 @@ mod tests {
                 raw: String::new(),
                 ts: FAKE_NOW.into(),
             },
+            tags: Tags::new(),
             source: Some("nickname!username@host".into()),
             command: Command::Privmsg {
                 reply_to: "#channel".into(),
@@ -411,6 +850,7 @@ mod tests {
                 raw: String::new(),
                 ts: FAKE_NOW.into(),
             },
+            tags: Tags::new(),
             source: Some("nickname!username@host".into()),
             command: Command::Ping { token: None },
         };
@@ -0,0 +1,405 @@
+//! Server features advertised with `RPL_ISUPPORT` (005).
+//!
+//! See <https://modern.ircdocs.horse/#rplisupport-parameters>.
+
+use std::collections::BTreeMap;
+
+use crate::casemap::CaseMapping;
+use crate::numeric::{Numeric, Reply};
+
+/// The four classes of channel modes from `CHANMODES`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChanModes {
+    /// Type A: list modes that always take a parameter, e.g. `b`.
+    pub list: String,
+    /// Type B: modes that always take a parameter, e.g. `k`.
+    pub always: String,
+    /// Type C: modes that take a parameter only when set, e.g. `l`.
+    pub on_set: String,
+    /// Type D: flags without a parameter, e.g. `m`.
+    pub flags: String,
+}
+
+impl Default for ChanModes {
+    fn default() -> Self {
+        Self {
+            list: "beI".into(),
+            always: "k".into(),
+            on_set: "l".into(),
+            flags: "imnpst".into(),
+        }
+    }
+}
+
+/// Everything the server told us in `RPL_ISUPPORT`, with the documented defaults for
+/// anything it left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    pub network: Option<String>,
+    pub casemapping: CaseMapping,
+    /// Characters that start a channel name.
+    pub chantypes: String,
+    /// Channel membership modes and their prefixes, highest rank first, e.g. `(o, @)`.
+    pub prefix: Vec<(char, char)>,
+    pub chanmodes: ChanModes,
+    /// Membership prefixes that can be put before a channel to message only those
+    /// members, e.g. `@#channel`.
+    pub statusmsg: String,
+    /// Maximum targets per command (`None` means unlimited), keyed by command.
+    pub targmax: BTreeMap<String, Option<usize>>,
+    /// Maximum line length in bytes, including the CRLF.
+    pub linelen: usize,
+    pub nicklen: Option<usize>,
+    pub channellen: Option<usize>,
+    pub topiclen: Option<usize>,
+    pub kicklen: Option<usize>,
+    pub awaylen: Option<usize>,
+    /// Maximum parameterised mode changes per `MODE` command.
+    pub modes: Option<usize>,
+    /// `MONITOR` support; the limit is `None` when the server sets none.
+    pub monitor: bool,
+    pub monitor_limit: Option<usize>,
+    /// The ban-exception and invite-exception modes, if supported.
+    pub excepts: Option<char>,
+    pub invex: Option<char>,
+    /// The user mode marking bots, if the server has one.
+    pub bot: Option<char>,
+    pub whox: bool,
+    pub utf8only: bool,
+    tokens: BTreeMap<String, String>,
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        Self {
+            network: None,
+            casemapping: CaseMapping::default(),
+            chantypes: "#&".into(),
+            prefix: vec![('o', '@'), ('v', '+')],
+            chanmodes: ChanModes::default(),
+            statusmsg: String::new(),
+            targmax: BTreeMap::new(),
+            linelen: 512,
+            nicklen: None,
+            channellen: None,
+            topiclen: None,
+            kicklen: None,
+            awaylen: None,
+            modes: None,
+            monitor: false,
+            monitor_limit: None,
+            excepts: None,
+            invex: None,
+            bot: None,
+            whox: false,
+            utf8only: false,
+            tokens: BTreeMap::new(),
+        }
+    }
+}
+
+impl ServerInfo {
+    /// The raw value of any token, including ones without a typed field. Tokens sent
+    /// without a value have an empty one.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tokens.get(key).map(String::as_str)
+    }
+
+    /// True if `name` starts with one of the server's channel types.
+    pub fn is_channel(&self, name: &str) -> bool {
+        name.chars()
+            .next()
+            .is_some_and(|c| self.chantypes.contains(c))
+    }
+
+    /// The membership prefix for a channel mode, e.g. `@` for `o`.
+    pub fn prefix_for_mode(&self, mode: char) -> Option<char> {
+        self.prefix
+            .iter()
+            .find(|(m, _)| *m == mode)
+            .map(|(_, p)| *p)
+    }
+
+    /// The channel mode for a membership prefix, e.g. `o` for `@`.
+    pub fn mode_for_prefix(&self, prefix: char) -> Option<char> {
+        self.prefix
+            .iter()
+            .find(|(_, p)| *p == prefix)
+            .map(|(m, _)| *m)
+    }
+
+    /// The target limit for `command`, `None` if unlimited or unknown.
+    pub fn max_targets(&self, command: &str) -> Option<usize> {
+        self.targmax.get(command).copied().flatten()
+    }
+
+    /// How many targets to put in one `command`, `None` if unlimited. Commands that
+    /// TARGMAX doesn't mention get one target each, which every server accepts.
+    pub fn targets_per_line(&self, command: &str) -> Option<usize> {
+        self.targmax.get(command).copied().unwrap_or(Some(1))
+    }
+
+    /// How many mode changes with a parameter to put in one MODE, `None` if unlimited.
+    /// `MODES` without a value means no limit; without the token at all, the limit is
+    /// the traditional 3.
+    pub fn modes_per_line(&self) -> Option<usize> {
+        match (self.modes, self.get("MODES")) {
+            (Some(limit), _) => Some(limit),
+            (None, Some(_)) => None,
+            (None, None) => Some(3),
+        }
+    }
+
+    /// The most messages one CHATHISTORY request may ask for, `None` if the server
+    /// sets no limit.
+    pub fn history_limit(&self) -> Option<usize> {
+        self.get("CHATHISTORY")?
+            .parse()
+            .ok()
+            .filter(|limit| *limit > 0)
+    }
+
+    /// Applies every token in an `RPL_ISUPPORT` reply. Other replies are ignored.
+    pub fn apply_reply(&mut self, reply: &Reply<'_>) {
+        if reply.numeric != Numeric::RplISupport {
+            return;
+        }
+        for token in reply.isupport_tokens() {
+            self.apply(token);
+        }
+    }
+
+    /// Applies a single `KEY`, `KEY=VALUE` or `-KEY` token.
+    pub fn apply(&mut self, token: &str) {
+        if let Some(key) = token.strip_prefix('-') {
+            self.reset(key);
+            return;
+        }
+
+        let (key, value) = match token.split_once('=') {
+            Some((key, value)) => (key, unescape_value(value)),
+            None => (token, String::new()),
+        };
+        let number = value.parse::<usize>().ok();
+
+        match key {
+            "NETWORK" => self.network = Some(value.clone()),
+            "CASEMAPPING" => {
+                if let Some(mapping) = CaseMapping::from_token(&value) {
+                    self.casemapping = mapping;
+                }
+            }
+            "CHANTYPES" => self.chantypes = value.clone(),
+            "PREFIX" => {
+                if let Some(prefix) = parse_prefix(&value) {
+                    self.prefix = prefix;
+                }
+            }
+            "CHANMODES" => {
+                let mut classes = value.split(',').map(str::to_owned);
+                self.chanmodes = ChanModes {
+                    list: classes.next().unwrap_or_default(),
+                    always: classes.next().unwrap_or_default(),
+                    on_set: classes.next().unwrap_or_default(),
+                    flags: classes.next().unwrap_or_default(),
+                };
+            }
+            "STATUSMSG" => self.statusmsg = value.clone(),
+            "TARGMAX" => {
+                self.targmax = value
+                    .split(',')
+                    .filter_map(|t| t.split_once(':'))
+                    .map(|(cmd, max)| (cmd.to_ascii_uppercase(), max.parse().ok()))
+                    .collect();
+            }
+            "LINELEN" => self.linelen = number.unwrap_or(512),
+            "NICKLEN" => self.nicklen = number,
+            "CHANNELLEN" => self.channellen = number,
+            "TOPICLEN" => self.topiclen = number,
+            "KICKLEN" => self.kicklen = number,
+            "AWAYLEN" => self.awaylen = number,
+            "MODES" => self.modes = number,
+            "MONITOR" => {
+                self.monitor = true;
+                self.monitor_limit = number;
+            }
+            "EXCEPTS" => self.excepts = Some(value.chars().next().unwrap_or('e')),
+            "INVEX" => self.invex = Some(value.chars().next().unwrap_or('I')),
+            "BOT" => self.bot = value.chars().next(),
+            "WHOX" => self.whox = true,
+            "UTF8ONLY" => self.utf8only = true,
+            _ => {}
+        }
+        self.tokens.insert(key.to_owned(), value);
+    }
+
+    /// Handles `-KEY`: the server withdrew the token, so fall back to the default.
+    fn reset(&mut self, key: &str) {
+        let default = ServerInfo::default();
+        match key {
+            "NETWORK" => self.network = default.network,
+            "CASEMAPPING" => self.casemapping = default.casemapping,
+            "CHANTYPES" => self.chantypes = default.chantypes,
+            "PREFIX" => self.prefix = default.prefix,
+            "CHANMODES" => self.chanmodes = default.chanmodes,
+            "STATUSMSG" => self.statusmsg = default.statusmsg,
+            "TARGMAX" => self.targmax = default.targmax,
+            "LINELEN" => self.linelen = default.linelen,
+            "NICKLEN" => self.nicklen = default.nicklen,
+            "CHANNELLEN" => self.channellen = default.channellen,
+            "TOPICLEN" => self.topiclen = default.topiclen,
+            "KICKLEN" => self.kicklen = default.kicklen,
+            "AWAYLEN" => self.awaylen = default.awaylen,
+            "MODES" => self.modes = default.modes,
+            "MONITOR" => {
+                self.monitor = default.monitor;
+                self.monitor_limit = default.monitor_limit;
+            }
+            "EXCEPTS" => self.excepts = default.excepts,
+            "INVEX" => self.invex = default.invex,
+            "BOT" => self.bot = default.bot,
+            "WHOX" => self.whox = default.whox,
+            "UTF8ONLY" => self.utf8only = default.utf8only,
+            _ => {}
+        }
+        self.tokens.remove(key);
+    }
+}
+
+/// Parses `PREFIX=(ov)@+`. An empty value means no membership prefixes at all.
+fn parse_prefix(value: &str) -> Option<Vec<(char, char)>> {
+    if value.is_empty() {
+        return Some(vec![]);
+    }
+    let (modes, prefixes) = value.strip_prefix('(')?.split_once(')')?;
+    if modes.chars().count() != prefixes.chars().count() {
+        return None;
+    }
+    Some(modes.chars().zip(prefixes.chars()).collect())
+}
+
+/// Decodes the `\xHH` escapes allowed in ISUPPORT values.
+fn unescape_value(value: &str) -> String {
+    let mut out = Vec::with_capacity(value.len());
+    let bytes = value.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && bytes.get(i + 1) == Some(&b'x')
+            && let Some(byte) = value
+                .get(i + 2..i + 4)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            out.push(byte);
+            i += 4;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::irc_msg::Msg;
+
+    fn apply_line(info: &mut ServerInfo, line: &str) {
+        let msg = Msg::parse(line, SystemTime::UNIX_EPOCH.into()).unwrap();
+        info.apply_reply(&msg.reply().unwrap());
+    }
+
+    #[test]
+    fn defaults() {
+        let info = ServerInfo::default();
+        assert!(info.is_channel("#rust"));
+        assert!(!info.is_channel("alice"));
+        assert_eq!(info.casemapping, CaseMapping::Rfc1459);
+        assert_eq!(info.prefix_for_mode('o'), Some('@'));
+        assert_eq!(info.linelen, 512);
+    }
+
+    #[test]
+    fn applies_typed_tokens() {
+        let mut info = ServerInfo::default();
+        apply_line(
+            &mut info,
+            ":irc.example.com 005 botty NETWORK=Example\\x20Net CASEMAPPING=ascii \
+             CHANTYPES=#! PREFIX=(qaohv)~&@%+ CHANMODES=beI,k,l,imnst NICKLEN=30 \
+             :are supported by this server",
+        );
+        apply_line(
+            &mut info,
+            ":irc.example.com 005 botty TARGMAX=PRIVMSG:4,JOIN:,NAMES:1 MONITOR=100 \
+             STATUSMSG=@+ EXCEPTS INVEX BOT=B WHOX LINELEN=2048 FOO=bar \
+             :are supported by this server",
+        );
+
+        assert_eq!(info.network.as_deref(), Some("Example Net"));
+        assert_eq!(info.casemapping, CaseMapping::Ascii);
+        assert!(info.is_channel("!chan"));
+        assert!(!info.is_channel("&local"));
+        assert_eq!(info.prefix.len(), 5);
+        assert_eq!(info.mode_for_prefix('~'), Some('q'));
+        assert_eq!(info.chanmodes.flags, "imnst");
+        assert_eq!(info.nicklen, Some(30));
+        assert_eq!(info.max_targets("PRIVMSG"), Some(4));
+        assert_eq!(info.max_targets("JOIN"), None);
+        assert!(info.monitor);
+        assert_eq!(info.monitor_limit, Some(100));
+        assert_eq!(info.statusmsg, "@+");
+        assert_eq!(info.excepts, Some('e'));
+        assert_eq!(info.invex, Some('I'));
+        assert_eq!(info.bot, Some('B'));
+        assert!(info.whox);
+        assert_eq!(info.linelen, 2048);
+        assert_eq!(info.get("FOO"), Some("bar"));
+        assert_eq!(info.get("WHOX"), Some(""));
+    }
+
+    #[test]
+    fn per_line_limits() {
+        let mut info = ServerInfo::default();
+        assert_eq!(info.modes_per_line(), Some(3));
+        assert_eq!(info.targets_per_line("KICK"), Some(1));
+
+        info.apply("MODES");
+        info.apply("TARGMAX=KICK:4,PRIVMSG:");
+        assert_eq!(info.modes_per_line(), None);
+        assert_eq!(info.targets_per_line("KICK"), Some(4));
+        assert_eq!(info.targets_per_line("PRIVMSG"), None);
+
+        info.apply("MODES=6");
+        assert_eq!(info.modes_per_line(), Some(6));
+
+        assert_eq!(info.history_limit(), None);
+        info.apply("CHATHISTORY=50");
+        assert_eq!(info.history_limit(), Some(50));
+    }
+
+    #[test]
+    fn negated_token_restores_default() {
+        let mut info = ServerInfo::default();
+        info.apply("NICKLEN=9");
+        info.apply("CHANTYPES=#");
+        info.apply("-NICKLEN");
+        info.apply("-CHANTYPES");
+
+        assert_eq!(info.nicklen, None);
+        assert_eq!(info.chantypes, "#&");
+        assert_eq!(info.get("NICKLEN"), None);
+    }
+
+    #[test]
+    fn empty_prefix_means_no_membership_modes() {
+        let mut info = ServerInfo::default();
+        info.apply("PREFIX=");
+        assert!(info.prefix.is_empty());
+
+        info.apply("PREFIX=garbage");
+        assert!(info.prefix.is_empty());
+    }
+}
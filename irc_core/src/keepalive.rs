@@ -0,0 +1,195 @@
+//! Client-side keepalive.
+//!
+//! A half-open TCP connection never errors on read, it just goes quiet. When nothing
+//! has arrived for a while we send a timestamped `PING`; the matching `PONG` gives the
+//! round-trip lag, and continued silence means the connection is dead.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::irc_msg::{Command, Msg};
+
+/// Prefix of the tokens we send, so our PONGs can't be confused with anyone else's.
+const TOKEN_PREFIX: &str = "keepalive-";
+
+/// When to ping the server, and how long to wait before giving up on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keepalive {
+    enabled: bool,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Default for Keepalive {
+    /// Pings after a minute of silence, and drops the connection after another
+    /// minute without hearing anything back.
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Keepalive {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Never pings, relying on the server's own PINGs and the OS to notice a dead link.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::default()
+        }
+    }
+
+    /// How long the connection may be idle before we ping.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long to wait after a ping, without receiving anything, before the
+    /// connection is considered dead.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// What to do when the reader reaches [`Pinger::deadline`].
+#[derive(Debug, PartialEq)]
+pub(crate) enum Tick {
+    /// Send this line to the server.
+    Ping(String),
+    /// Nothing arrived in time; the connection is dead.
+    Dead,
+}
+
+/// Keepalive state for a single connection, driven by the reader task.
+#[derive(Debug)]
+pub(crate) struct Pinger {
+    config: Keepalive,
+    last_seen: Instant,
+    /// The token of the ping we're waiting on, and when it was sent.
+    pending: Option<(String, Instant)>,
+}
+
+impl Pinger {
+    pub fn new(config: Keepalive, now: Instant) -> Self {
+        Self {
+            config,
+            last_seen: now,
+            pending: None,
+        }
+    }
+
+    /// When to call [`on_deadline`](Self::on_deadline) if no line arrives first, or
+    /// `None` if keepalive is disabled.
+    pub fn deadline(&self) -> Option<Instant> {
+        if !self.config.enabled {
+            return None;
+        }
+        Some(match &self.pending {
+            Some((_, sent)) => self.last_seen.max(*sent) + self.config.timeout,
+            None => self.last_seen + self.config.interval,
+        })
+    }
+
+    /// Records a line from the server. Returns the round-trip lag if it is the PONG
+    /// to our outstanding ping.
+    pub fn on_line(&mut self, line: &str, now: Instant) -> Option<Duration> {
+        self.last_seen = now;
+        let (token, sent) = self.pending.as_ref()?;
+        let msg = Msg::parse(line, chrono::Local::now())?;
+        let Command::Raw { command, args } = msg.command else {
+            return None;
+        };
+        // "PONG <server> :<token>"
+        if command.eq_ignore_ascii_case("PONG") && args.last() == Some(token) {
+            let lag = now.saturating_duration_since(*sent);
+            self.pending = None;
+            return Some(lag);
+        }
+        None
+    }
+
+    pub fn on_deadline(&mut self, now: Instant) -> Tick {
+        if self.pending.is_some() {
+            return Tick::Dead;
+        }
+        let token = format!("{}{}", TOKEN_PREFIX, chrono::Utc::now().timestamp_millis());
+        let line = format!("PING :{}", token);
+        self.pending = Some((token, now));
+        Tick::Ping(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pinger(start: Instant) -> Pinger {
+        let config = Keepalive::new()
+            .with_interval(Duration::from_secs(30))
+            .with_timeout(Duration::from_secs(10));
+        Pinger::new(config, start)
+    }
+
+    fn token_of(tick: Tick) -> String {
+        match tick {
+            Tick::Ping(line) => line.strip_prefix("PING :").unwrap().to_owned(),
+            Tick::Dead => panic!("expected a ping"),
+        }
+    }
+
+    #[test]
+    fn pings_when_idle_and_measures_lag() {
+        let start = Instant::now();
+        let mut pinger = pinger(start);
+        assert_eq!(pinger.deadline(), Some(start + Duration::from_secs(30)));
+
+        let later = start + Duration::from_secs(5);
+        assert_eq!(pinger.on_line(":srv NOTICE * :hi", later), None);
+        assert_eq!(pinger.deadline(), Some(later + Duration::from_secs(30)));
+
+        let ping_at = later + Duration::from_secs(30);
+        let token = token_of(pinger.on_deadline(ping_at));
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(pinger.deadline(), Some(ping_at + Duration::from_secs(10)));
+
+        let pong = format!(":srv PONG srv :{}", token);
+        let pong_at = ping_at + Duration::from_millis(250);
+        assert_eq!(
+            pinger.on_line(&pong, pong_at),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(pinger.deadline(), Some(pong_at + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn silence_after_ping_is_dead() {
+        let start = Instant::now();
+        let mut pinger = pinger(start);
+        token_of(pinger.on_deadline(start));
+
+        // Other traffic proves the link is alive, but isn't our PONG.
+        let busy = start + Duration::from_secs(8);
+        assert_eq!(pinger.on_line(":srv PONG srv :someone-else", busy), None);
+        assert_eq!(pinger.deadline(), Some(busy + Duration::from_secs(10)));
+
+        assert_eq!(
+            pinger.on_deadline(busy + Duration::from_secs(10)),
+            Tick::Dead
+        );
+    }
+
+    #[test]
+    fn disabled_never_wakes() {
+        let pinger = Pinger::new(Keepalive::disabled(), Instant::now());
+        assert_eq!(pinger.deadline(), None);
+    }
+}
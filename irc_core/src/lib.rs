@@ -1,100 +1,169 @@
+pub mod batch;
 pub mod bot;
+pub mod cap;
+pub mod casemap;
+pub mod channels;
 pub mod client;
+pub mod ctcp;
+pub mod decode;
+pub mod encode;
+pub mod flood;
 pub mod handler;
+pub mod history;
 pub mod irc_msg;
+pub mod isupport;
+pub mod keepalive;
+pub mod mode;
+pub mod msg_ref;
+pub mod nick;
+pub mod numeric;
+pub mod prefix;
+pub mod query;
+pub mod reconnect;
+mod registration;
+pub mod sasl;
+pub mod split;
+#[cfg(test)]
+mod testing;
+pub mod tls;
+pub mod users;
 
 use std::borrow::Cow;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock};
 
-use tokio::io::AsyncWriteExt;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{BufReader, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::Notify;
 
 use anyhow::Context as _;
-use tracing::{error, info};
+use tracing::info;
 
-use crate::client::Client;
+use crate::cap::Capabilities;
+use crate::client::{Client, ClientBuilder, Config, Incoming, Shared};
 
+/// Connects with default settings. Use [`ClientBuilder`] to request capabilities.
 pub async fn connect<S, N, U>(server: S, nick: N, user: U) -> anyhow::Result<Client>
 where
     S: Send + Debug + Into<Cow<'static, str>>,
     N: Send + Debug + Into<Cow<'static, str>>,
     U: Send + Debug + Into<Cow<'static, str>>,
 {
-    let server: Cow<'static, str> = server.into();
-    let nick: Cow<'static, str> = nick.into();
-    let user: Cow<'static, str> = user.into();
+    ClientBuilder::new(server, nick, user).connect().await
+}
+
+/// A connected byte stream to the server, either plain TCP or TLS.
+pub(crate) trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
 
+/// A registered connection, ready to hand to the socket tasks.
+pub(crate) struct Connection {
+    pub reader: BufReader<ReadHalf<Box<dyn Transport>>>,
+    pub writer: WriteHalf<Box<dyn Transport>>,
+    pub caps: Capabilities,
+    /// The nick we registered under.
+    pub nick: String,
+    /// Lines received during registration, to be replayed to handlers.
+    pub backlog: Vec<String>,
+}
+
+/// Connects to the server and registers, giving up after the registration timeout.
+pub(crate) async fn establish(config: &Config) -> anyhow::Result<Connection> {
     info!(
         "Connecting to IRC server {} as {}",
-        server.as_ref(),
-        nick.as_ref()
+        config.server.as_ref(),
+        config.nick.as_ref()
     );
 
-    let stream = TcpStream::connect(server.as_ref())
+    tokio::time::timeout(config.registration_timeout, connect_and_register(config))
+        .await
+        .with_context(|| {
+            format!(
+                "no welcome from {} within {:?}",
+                config.server.as_ref(),
+                config.registration_timeout
+            )
+        })?
+}
+
+async fn connect_and_register(config: &Config) -> anyhow::Result<Connection> {
+    let stream = TcpStream::connect(config.server.as_ref())
+        .await
+        .with_context(|| format!("failed to connect to server {}", config.server.as_ref()))?;
+
+    let stream: Box<dyn Transport> = match &config.tls {
+        Some(tls) => Box::new(tls::handshake(tls, config.server.as_ref(), stream).await?),
+        None => Box::new(stream),
+    };
+
+    let (read_half, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(read_half);
+
+    let mut caps = Capabilities::default();
+    let registered = registration::register(&mut reader, &mut writer, config, &mut caps)
         .await
-        .with_context(|| format!("failed to connect to server {}", server.as_ref()))?;
+        .context("registration failed")?;
+
+    Ok(Connection {
+        reader,
+        writer,
+        caps,
+        nick: registered.nick,
+        backlog: registered.backlog,
+    })
+}
 
-    let (read_half, mut write_half) = stream.into_split();
+/// Makes the first connection, failing if it can't be established, then leaves the
+/// supervisor to keep it alive.
+pub(crate) async fn open(config: Config) -> anyhow::Result<Client> {
+    let conn = establish(&config).await?;
 
-    // Channels between socket tasks and BotClient.
+    // Channels between the supervisor and Client, which outlive any one connection.
     // Outgoing: app → socket
-    let (outgoing_tx, mut outgoing_rx) = tokio::sync::mpsc::channel::<String>(100);
+    let (outgoing_tx, outgoing_rx) = tokio::sync::mpsc::channel::<String>(100);
     // Incoming: socket → app
-    let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel::<String>(100);
-
-    let nick_ = nick.as_ref().to_string();
-
-    // Writer task: drains outgoing_rx and writes to the TCP socket.
-    tokio::spawn(async move {
-        // IRC registration first.
-        // `BotClient::send` already appends CRLF; here we write raw lines.
-        // Registration
-        if let Err(e) = write_half
-            .write_all(format!("NICK {}\r\n", nick_).as_bytes())
-            .await
-        {
-            error!("failed to write NICK: {e:?}");
-            return;
-        }
-        if let Err(e) = write_half
-            .write_all(format!("USER {} 0 * :{}\r\n", nick_, user.as_ref()).as_bytes())
-            .await
-        {
-            error!("failed to write USER: {e:?}");
-            return;
-        }
-
-        while let Some(mut line) = outgoing_rx.recv().await {
-            if !line.ends_with("\r\n") {
-                line.push_str("\r\n");
-            }
-            if let Err(e) = write_half.write_all(line.as_bytes()).await {
-                error!("writer task error: {e:?}");
-                break;
-            }
-            info!("==> {}", line.trim_end());
-        }
-    });
+    let (incoming_tx, incoming_rx) = tokio::sync::mpsc::channel::<Incoming>(100);
 
-    // Reader task: reads lines from the TCP socket and forwards to incoming_tx.
-    tokio::spawn(async move {
-        let mut lines = BufReader::new(read_half).lines();
-        while let Ok(Some(line)) = lines.next_line().await {
-            info!("<== {}", line.trim_end());
-            if incoming_tx.send(line).await.is_err() {
-                break; // receiver dropped; end the task
-            }
-        }
-        // dropping incoming_tx closes the channel
+    let shared = Arc::new(Shared {
+        caps: RwLock::new(conn.caps.clone()),
+        nick: RwLock::new(conn.nick.clone()),
+        primary_nick: config.nick.clone().into_owned(),
+        regain: config.regain.clone(),
+        ctcp: config.ctcp.clone(),
+        ctcp_limiter: Mutex::default(),
+        server_info: RwLock::default(),
+        source: RwLock::default(),
+        joined: RwLock::default(),
+        keys: RwLock::default(),
+        channels: RwLock::default(),
+        users: RwLock::default(),
+        user_events: Mutex::default(),
+        batches: Mutex::default(),
+        queries: Mutex::default(),
+        query_timeout: config.query_timeout,
+        wanted_caps: config.caps.clone(),
+        split_marker: config.split_marker.clone(),
+        queued: AtomicUsize::new(0),
+        quit_sent: AtomicBool::new(false),
+        quit: Notify::new(),
+        online: AtomicBool::new(true),
+        lag: RwLock::default(),
     });
 
-    let client = Client {
+    tokio::spawn(reconnect::supervise(
+        config,
+        conn,
+        outgoing_tx.downgrade(),
+        outgoing_rx,
+        incoming_tx,
+        shared.clone(),
+    ));
+
+    Ok(Client {
         tx: outgoing_tx,
         rx: Arc::new(tokio::sync::Mutex::new(incoming_rx)),
-        nick: nick.into_owned(),
-    };
-
-    Ok(client)
+        shared,
+    })
 }
@@ -0,0 +1,125 @@
+//! Mode changes to send with [`Client::mode`](crate::client::Client::mode).
+
+/// A single mode being set or unset, with its parameter if it takes one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeChange {
+    pub adding: bool,
+    pub mode: char,
+    pub arg: Option<String>,
+}
+
+impl ModeChange {
+    pub fn add(mode: char, arg: Option<&str>) -> Self {
+        Self {
+            adding: true,
+            mode,
+            arg: arg.map(str::to_owned),
+        }
+    }
+
+    pub fn remove(mode: char, arg: Option<&str>) -> Self {
+        Self {
+            adding: false,
+            mode,
+            arg: arg.map(str::to_owned),
+        }
+    }
+
+    pub fn op(nick: &str) -> Self {
+        Self::add('o', Some(nick))
+    }
+
+    pub fn deop(nick: &str) -> Self {
+        Self::remove('o', Some(nick))
+    }
+
+    pub fn voice(nick: &str) -> Self {
+        Self::add('v', Some(nick))
+    }
+
+    pub fn devoice(nick: &str) -> Self {
+        Self::remove('v', Some(nick))
+    }
+
+    pub fn ban(mask: &str) -> Self {
+        Self::add('b', Some(mask))
+    }
+
+    pub fn unban(mask: &str) -> Self {
+        Self::remove('b', Some(mask))
+    }
+}
+
+/// Groups `changes` into mode strings and their parameters, one per MODE line, with at
+/// most `max_args` parameters on each. Changes without a parameter don't count
+/// towards the limit. `None` means no limit.
+pub(crate) fn batch(changes: &[ModeChange], max_args: Option<usize>) -> Vec<(String, Vec<String>)> {
+    let max_args = max_args.unwrap_or(usize::MAX).max(1);
+    let mut lines = vec![];
+    let mut modes = String::new();
+    let mut args: Vec<String> = vec![];
+    let mut sign = None;
+
+    for change in changes {
+        if change.arg.is_some() && args.len() == max_args {
+            lines.push((std::mem::take(&mut modes), std::mem::take(&mut args)));
+            sign = None;
+        }
+        if sign != Some(change.adding) {
+            modes.push(if change.adding { '+' } else { '-' });
+            sign = Some(change.adding);
+        }
+        modes.push(change.mode);
+        args.extend(change.arg.clone());
+    }
+    if !modes.is_empty() {
+        lines.push((modes, args));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(lines: Vec<(String, Vec<String>)>) -> Vec<String> {
+        lines
+            .into_iter()
+            .map(|(modes, args)| [vec![modes], args].concat().join(" "))
+            .collect()
+    }
+
+    #[test]
+    fn batches_by_parameter_count() {
+        let changes = [
+            ModeChange::op("alice"),
+            ModeChange::op("bob"),
+            ModeChange::add('m', None),
+            ModeChange::devoice("carol"),
+            ModeChange::ban("*!*@spam"),
+        ];
+        assert_eq!(
+            strings(batch(&changes, Some(3))),
+            ["+oom-v alice bob carol", "+b *!*@spam"]
+        );
+        assert_eq!(
+            strings(batch(&changes, None)),
+            ["+oom-v+b alice bob carol *!*@spam"]
+        );
+    }
+
+    #[test]
+    fn flags_never_start_a_new_line() {
+        let changes = [
+            ModeChange::op("alice"),
+            ModeChange::remove('t', None),
+            ModeChange::add('n', None),
+            ModeChange::op("bob"),
+        ];
+        assert_eq!(
+            strings(batch(&changes, Some(1))),
+            ["+o-t+n alice", "+o bob"]
+        );
+        assert!(batch(&[], Some(3)).is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+//! A borrowed view of a protocol line, parsed without allocating.
+//!
+//! [`Msg::parse`] builds owned strings for everything it finds, which is right for
+//! handlers but wasteful when scanning millions of logged lines for a few that matter.
+//! [`MsgRef`] only records where each part of the line is; tag values are unescaped
+//! on demand, and [`MsgRef::to_msg`] builds the owned message when one is wanted.
+
+use std::borrow::Cow;
+
+use chrono::{DateTime, Local};
+
+use crate::irc_msg::{
+    CmdParts, Command, MAX_PARAMS, Msg, MsgMeta, Tags, split_tags, unescape_tag_value,
+};
+use crate::prefix::Prefix;
+
+/// A parsed line that borrows from its input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MsgRef<'a> {
+    raw: &'a str,
+    tags: Option<&'a str>,
+    source: Option<&'a str>,
+    command: &'a str,
+    params: [&'a str; MAX_PARAMS],
+    len: usize,
+    /// Whether the last parameter was sent in trailing form (or was the fifteenth).
+    trailing: bool,
+}
+
+impl<'a> MsgRef<'a> {
+    /// Splits a line into tags, source, command and parameters.
+    ///
+    /// Follows the modern grammar: only spaces separate words, and runs of them count
+    /// as one. A parameter starting with `:` takes the rest of the line, spaces and all,
+    /// even if that is empty. So does the fifteenth parameter, colon or not.
+    pub fn parse(line: &'a str) -> Option<Self> {
+        let (tags, rest) = split_tags(line);
+        let mut rest = rest.trim_start_matches(' ');
+
+        let source = match rest.strip_prefix(':') {
+            Some(prefixed) => {
+                let (source, after) = prefixed.split_once(' ')?;
+                rest = after.trim_start_matches(' ');
+                Some(source)
+            }
+            None => None,
+        };
+
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+
+        let mut params = [""; MAX_PARAMS];
+        let mut len = 0;
+        let mut trailing = false;
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(last) = rest.strip_prefix(':') {
+                params[len] = last;
+                len += 1;
+                trailing = true;
+                break;
+            }
+            if len == MAX_PARAMS - 1 {
+                params[len] = rest;
+                len += 1;
+                trailing = true;
+                break;
+            }
+            let (param, after) = rest.split_once(' ').unwrap_or((rest, ""));
+            params[len] = param;
+            len += 1;
+            rest = after;
+        }
+
+        Some(Self {
+            raw: line,
+            tags,
+            source,
+            command,
+            params,
+            len,
+            trailing,
+        })
+    }
+
+    /// The whole line, as passed to [`parse`](Self::parse).
+    pub fn raw(&self) -> &'a str {
+        self.raw
+    }
+
+    /// The source without its leading `:`, e.g. `nick!user@host` or a server name.
+    pub fn source(&self) -> Option<&'a str> {
+        self.source
+    }
+
+    /// The sender's nick, or `None` for servers. Agrees with [`Prefix::nick`].
+    pub fn nick(&self) -> Option<&'a str> {
+        let source = self.source?;
+        let nick = source.split(['!', '@']).next().unwrap_or_default();
+        let is_server = nick.len() == source.len() && nick.contains('.');
+        (!nick.is_empty() && !is_server).then_some(nick)
+    }
+
+    /// The command exactly as sent, e.g. `PRIVMSG` or `001`.
+    pub fn command(&self) -> &'a str {
+        self.command
+    }
+
+    /// All parameters in order, with the trailing parameter (if any) last.
+    pub fn params(&self) -> &[&'a str] {
+        &self.params[..self.len]
+    }
+
+    pub fn param(&self, i: usize) -> Option<&'a str> {
+        self.params().get(i).copied()
+    }
+
+    /// Tags in the order sent, with values unescaped. Tags without a value yield an
+    /// empty string. Only values containing escapes allocate.
+    pub fn tags(&self) -> impl Iterator<Item = (&'a str, Cow<'a, str>)> + 'a {
+        self.tags
+            .unwrap_or_default()
+            .split(';')
+            .filter(|t| !t.is_empty())
+            .map(|t| {
+                let (key, value) = t.split_once('=').unwrap_or((t, ""));
+                let value = if value.contains('\\') {
+                    Cow::Owned(unescape_tag_value(value))
+                } else {
+                    Cow::Borrowed(value)
+                };
+                (key, value)
+            })
+    }
+
+    /// The value of the tag `key`. If it was sent more than once, the last one wins.
+    pub fn tag(&self, key: &str) -> Option<Cow<'a, str>> {
+        self.tags()
+            .filter(|(k, _)| *k == key)
+            .last()
+            .map(|(_, v)| v)
+    }
+
+    /// Builds the owned message. `now` is used as the timestamp unless the line has a
+    /// `time` tag. Returns `None` in the same cases as [`Msg::parse`].
+    pub fn to_msg(&self, now: DateTime<Local>) -> Option<Msg> {
+        let tags: Tags = self
+            .tags()
+            .map(|(k, v)| (k.to_owned(), v.into_owned()))
+            .collect();
+        let ts = tags
+            .get("time")
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Local))
+            .unwrap_or(now);
+
+        let (args, trailing) = match self.params().split_last() {
+            Some((last, middle)) if self.trailing => (middle.to_vec(), Some(*last)),
+            _ => (self.params().to_vec(), None),
+        };
+        let command = Command::build_from_parts(&CmdParts {
+            command: self.command,
+            args,
+            trailing,
+        })?;
+
+        Some(Msg {
+            meta: MsgMeta {
+                raw: self.raw.to_owned(),
+                ts,
+            },
+            tags,
+            source: self.source.map(Prefix::parse),
+            command,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::testing::{self, Atoms};
+
+    #[derive(Debug, serde::Deserialize)]
+    struct SplitTest {
+        input: String,
+        atoms: Atoms,
+    }
+
+    #[test]
+    fn parser_tests_msg_split() {
+        let tests: Vec<SplitTest> =
+            testing::vectors(include_str!("../testdata/parser-tests/msg-split.yaml"));
+        assert!(!tests.is_empty());
+
+        for test in tests {
+            let msg = MsgRef::parse(&test.input).expect(&test.input);
+            let tags: Tags = msg
+                .tags()
+                .map(|(k, v)| (k.to_owned(), v.into_owned()))
+                .collect();
+            assert_eq!(tags, test.atoms.tags, "tags of {:?}", test.input);
+            assert_eq!(
+                msg.source(),
+                test.atoms.source.as_deref(),
+                "source of {:?}",
+                test.input
+            );
+            assert_eq!(msg.command(), test.atoms.verb, "verb of {:?}", test.input);
+            assert_eq!(
+                msg.params(),
+                test.atoms.params,
+                "params of {:?}",
+                test.input
+            );
+            assert!(msg.to_msg(SystemTime::UNIX_EPOCH.into()).is_some());
+        }
+    }
+
+    #[test]
+    fn fifteenth_param_takes_the_rest() {
+        let middle: Vec<String> = (1..=14).map(|i| i.to_string()).collect();
+        let line = format!("FOO {} last one", middle.join(" "));
+        let msg = MsgRef::parse(&line).unwrap();
+        assert_eq!(msg.params().len(), 15);
+        assert_eq!(msg.param(14), Some("last one"));
+    }
+
+    #[test]
+    fn runs_of_spaces_separate_once() {
+        let msg = MsgRef::parse(":src   MODE   #chan  +o   nick  ").unwrap();
+        assert_eq!(msg.source(), Some("src"));
+        assert_eq!(msg.params(), ["#chan", "+o", "nick"]);
+        assert!(MsgRef::parse(":src").is_none());
+        assert!(MsgRef::parse("").is_none());
+    }
+
+    #[test]
+    fn last_param_is_the_text_with_or_without_a_colon() {
+        let now = SystemTime::UNIX_EPOCH.into();
+        for (line, expected) in [
+            (
+                ":n!u@h PRIVMSG #chan hello",
+                Command::Privmsg {
+                    reply_to: "#chan".into(),
+                    message: "hello".into(),
+                },
+            ),
+            (
+                ":n!u@h PART #chan bye",
+                Command::Part {
+                    channel: "#chan".into(),
+                    message: Some("bye".into()),
+                },
+            ),
+            (
+                ":n!u@h PART #chan",
+                Command::Part {
+                    channel: "#chan".into(),
+                    message: None,
+                },
+            ),
+        ] {
+            let msg = MsgRef::parse(line).unwrap();
+            assert_eq!(msg.to_msg(now).unwrap().command, expected, "{line}");
+            assert_eq!(Msg::parse(line, now).unwrap().command, expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn tags_borrow_unless_escaped() {
+        let msg = MsgRef::parse("@a=plain;b=with\\sspace;a=again :n!u@h PING").unwrap();
+        let tags: Vec<_> = msg.tags().collect();
+        assert!(matches!(tags[0].1, Cow::Borrowed("plain")));
+        assert!(matches!(&tags[1].1, Cow::Owned(v) if v == "with space"));
+        assert_eq!(msg.tag("a").as_deref(), Some("again"));
+        assert_eq!(msg.tag("missing"), None);
+    }
+
+    #[test]
+    fn nick_agrees_with_prefix() {
+        for source in [
+            "nick!user@host",
+            "nick@host",
+            "nick",
+            "irc.example.com",
+            "a.b!c@d",
+        ] {
+            let line = format!(":{} PING", source);
+            let msg = MsgRef::parse(&line).unwrap();
+            assert_eq!(msg.nick(), Prefix::parse(source).nick(), "{source}");
+        }
+    }
+
+    #[test]
+    fn to_msg_builds_typed_command() {
+        let line = "@time=2024-01-02T03:04:05.000Z :nick!u@h PRIVMSG #chan :hello there";
+        let msg = MsgRef::parse(line)
+            .unwrap()
+            .to_msg(SystemTime::UNIX_EPOCH.into())
+            .unwrap();
+        assert_eq!(msg.meta.raw, line);
+        assert_eq!(msg.meta.ts.timestamp(), 1704164645);
+        assert_eq!(msg.nick(), Some("nick"));
+        assert_eq!(
+            msg.command,
+            Command::Privmsg {
+                reply_to: "#chan".into(),
+                message: "hello there".into(),
+            }
+        );
+    }
+}
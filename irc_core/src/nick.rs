@@ -0,0 +1,194 @@
+//! Picking another nickname when ours is taken, and getting the real one back later.
+
+use std::fmt;
+
+use crate::numeric::Numeric;
+
+/// How many underscores to try appending once the alternates run out.
+const MAX_UNDERSCORES: usize = 5;
+
+/// How to get the configured nick back after registering under a fallback.
+#[derive(Clone, Default, PartialEq)]
+pub enum NickRegain {
+    /// Keep whatever nick we ended up with.
+    Never,
+    /// Watch the nick with `MONITOR` (where the server supports it) and take it as
+    /// soon as it goes offline.
+    #[default]
+    Monitor,
+    /// Ask NickServ to `REGAIN` the nick, disconnecting whoever holds it. Needs the
+    /// account password; supported by Atheme and Anope.
+    NickServ { password: String },
+}
+
+impl fmt::Debug for NickRegain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NickRegain::Never => f.write_str("Never"),
+            NickRegain::Monitor => f.write_str("Monitor"),
+            NickRegain::NickServ { .. } => f
+                .debug_struct("NickServ")
+                .field("password", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
+/// True for the replies that reject a `NICK`: erroneous, in use, collision, or
+/// temporarily unavailable.
+pub fn is_rejection(numeric: Numeric) -> bool {
+    matches!(
+        numeric,
+        Numeric::ErrErroneusNickname
+            | Numeric::ErrNicknameInUse
+            | Numeric::ErrNickCollision
+            | Numeric::ErrUnavailResource
+    )
+}
+
+/// The nicks to try in turn during registration: each alternate, then the primary
+/// nick with more and more underscores.
+#[derive(Debug)]
+pub(crate) struct Fallbacks<'a> {
+    primary: &'a str,
+    alternates: &'a [String],
+    tried: usize,
+}
+
+impl<'a> Fallbacks<'a> {
+    pub fn new(primary: &'a str, alternates: &'a [String]) -> Self {
+        Self {
+            primary,
+            alternates,
+            tried: 0,
+        }
+    }
+
+    /// The next nick to try, or `None` once we've run out.
+    pub fn next(&mut self) -> Option<String> {
+        let nick = match self.alternates.get(self.tried) {
+            Some(alternate) => alternate.clone(),
+            None => {
+                let underscores = self.tried - self.alternates.len() + 1;
+                if underscores > MAX_UNDERSCORES {
+                    return None;
+                }
+                format!("{}{}", self.primary, "_".repeat(underscores))
+            }
+        };
+        self.tried += 1;
+        Some(nick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::client::ClientBuilder;
+    use crate::flood::FloodControl;
+    use crate::testing::wait_for;
+
+    #[test]
+    fn alternates_then_underscores() {
+        let alternates = vec!["botty2".to_owned(), "bot_ty".to_owned()];
+        let mut fallbacks = Fallbacks::new("botty", &alternates);
+        let tried: Vec<_> = std::iter::from_fn(|| fallbacks.next()).collect();
+        assert_eq!(
+            tried,
+            [
+                "botty2",
+                "bot_ty",
+                "botty_",
+                "botty__",
+                "botty___",
+                "botty____",
+                "botty_____"
+            ]
+        );
+    }
+
+    #[test]
+    fn debug_hides_the_password() {
+        let regain = NickRegain::NickServ {
+            password: "hunter2".into(),
+        };
+        assert_eq!(
+            format!("{:?}", regain),
+            r#"NickServ { password: "<redacted>" }"#
+        );
+    }
+
+    #[test]
+    fn rejections() {
+        assert!(is_rejection(Numeric::ErrNicknameInUse));
+        assert!(is_rejection(Numeric::ErrUnavailResource));
+        assert!(!is_rejection(Numeric::RplWelcome));
+    }
+
+    async fn regain_script(password: &str) -> Vec<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            wait_for(&mut lines, "USER botty 0 * :bot").await;
+            write
+                .write_all(
+                    b":srv CAP * LS :\r\n:srv 001 botty_ :Welcome\r\n:srv 376 botty_ :End of MOTD\r\n",
+                )
+                .await
+                .unwrap();
+            let mut sent = vec![];
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if line == "CAP END" {
+                    continue;
+                }
+                if line == "QUIT" {
+                    return sent;
+                }
+                sent.push(line);
+            }
+            sent
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_flood_control(FloodControl::unlimited())
+            .with_nick_regain(NickRegain::NickServ {
+                password: password.into(),
+            })
+            .connect()
+            .await
+            .unwrap();
+        loop {
+            let msg = client.recv().await.unwrap().unwrap();
+            if msg
+                .reply()
+                .is_some_and(|r| r.numeric == Numeric::RplEndOfMotd)
+            {
+                break;
+            }
+        }
+        assert!(client.flush(Duration::from_secs(5)).await);
+        client.quit(None).await.unwrap();
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn regains_through_nickserv() {
+        assert_eq!(
+            regain_script("hunter2").await,
+            ["PRIVMSG NickServ :REGAIN botty hunter2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn password_with_line_break_is_not_sent() {
+        assert!(regain_script("hunter2\r\nQUIT").await.is_empty());
+    }
+}
@@ -0,0 +1,451 @@
+//! Named numeric replies.
+//!
+//! Covers RFC 1459/2812 plus the modern replies catalogued at
+//! <https://modern.ircdocs.horse/#numerics>. Codes without a name here are still
+//! representable as [`Numeric::Unknown`].
+
+use crate::irc_msg::Command;
+
+macro_rules! numerics {
+    ($($(#[$doc:meta])* $variant:ident = $code:literal, $name:literal;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Numeric {
+            $($(#[$doc])* #[doc = concat!("`", $name, "` (", stringify!($code), ")")] $variant,)*
+            /// A code we have no name for.
+            Unknown(u16),
+        }
+
+        impl Numeric {
+            pub fn from_code(code: u16) -> Self {
+                match code {
+                    $($code => Numeric::$variant,)*
+                    other => Numeric::Unknown(other),
+                }
+            }
+
+            pub fn code(&self) -> u16 {
+                match self {
+                    $(Numeric::$variant => $code,)*
+                    Numeric::Unknown(code) => *code,
+                }
+            }
+
+            /// The conventional name, e.g. `RPL_WELCOME`.
+            pub fn name(&self) -> Option<&'static str> {
+                match self {
+                    $(Numeric::$variant => Some($name),)*
+                    Numeric::Unknown(_) => None,
+                }
+            }
+        }
+    };
+}
+
+numerics! {
+    RplWelcome = 1, "RPL_WELCOME";
+    RplYourHost = 2, "RPL_YOURHOST";
+    RplCreated = 3, "RPL_CREATED";
+    RplMyInfo = 4, "RPL_MYINFO";
+    RplISupport = 5, "RPL_ISUPPORT";
+    RplBounce = 10, "RPL_BOUNCE";
+    RplTraceLink = 200, "RPL_TRACELINK";
+    RplTraceConnecting = 201, "RPL_TRACECONNECTING";
+    RplTraceHandshake = 202, "RPL_TRACEHANDSHAKE";
+    RplTraceUnknown = 203, "RPL_TRACEUNKNOWN";
+    RplTraceOperator = 204, "RPL_TRACEOPERATOR";
+    RplTraceUser = 205, "RPL_TRACEUSER";
+    RplTraceServer = 206, "RPL_TRACESERVER";
+    RplTraceService = 207, "RPL_TRACESERVICE";
+    RplTraceNewType = 208, "RPL_TRACENEWTYPE";
+    RplTraceClass = 209, "RPL_TRACECLASS";
+    RplStatsLinkInfo = 211, "RPL_STATSLINKINFO";
+    RplStatsCommands = 212, "RPL_STATSCOMMANDS";
+    RplEndOfStats = 219, "RPL_ENDOFSTATS";
+    RplUmodeIs = 221, "RPL_UMODEIS";
+    RplServList = 234, "RPL_SERVLIST";
+    RplServListEnd = 235, "RPL_SERVLISTEND";
+    RplStatsUptime = 242, "RPL_STATSUPTIME";
+    RplStatsOLine = 243, "RPL_STATSOLINE";
+    RplLuserClient = 251, "RPL_LUSERCLIENT";
+    RplLuserOp = 252, "RPL_LUSEROP";
+    RplLuserUnknown = 253, "RPL_LUSERUNKNOWN";
+    RplLuserChannels = 254, "RPL_LUSERCHANNELS";
+    RplLuserMe = 255, "RPL_LUSERME";
+    RplAdminMe = 256, "RPL_ADMINME";
+    RplAdminLoc1 = 257, "RPL_ADMINLOC1";
+    RplAdminLoc2 = 258, "RPL_ADMINLOC2";
+    RplAdminEmail = 259, "RPL_ADMINEMAIL";
+    RplTraceLog = 261, "RPL_TRACELOG";
+    RplTraceEnd = 262, "RPL_TRACEEND";
+    RplTryAgain = 263, "RPL_TRYAGAIN";
+    RplLocalUsers = 265, "RPL_LOCALUSERS";
+    RplGlobalUsers = 266, "RPL_GLOBALUSERS";
+    RplWhoisCertFp = 276, "RPL_WHOISCERTFP";
+    RplNone = 300, "RPL_NONE";
+    RplAway = 301, "RPL_AWAY";
+    RplUserHost = 302, "RPL_USERHOST";
+    RplIsOn = 303, "RPL_ISON";
+    RplUnaway = 305, "RPL_UNAWAY";
+    RplNowAway = 306, "RPL_NOWAWAY";
+    RplWhoisRegNick = 307, "RPL_WHOISREGNICK";
+    RplWhoisUser = 311, "RPL_WHOISUSER";
+    RplWhoisServer = 312, "RPL_WHOISSERVER";
+    RplWhoisOperator = 313, "RPL_WHOISOPERATOR";
+    RplWhowasUser = 314, "RPL_WHOWASUSER";
+    RplEndOfWho = 315, "RPL_ENDOFWHO";
+    RplWhoisIdle = 317, "RPL_WHOISIDLE";
+    RplEndOfWhois = 318, "RPL_ENDOFWHOIS";
+    RplWhoisChannels = 319, "RPL_WHOISCHANNELS";
+    RplWhoisSpecial = 320, "RPL_WHOISSPECIAL";
+    RplListStart = 321, "RPL_LISTSTART";
+    RplList = 322, "RPL_LIST";
+    RplListEnd = 323, "RPL_LISTEND";
+    RplChannelModeIs = 324, "RPL_CHANNELMODEIS";
+    RplCreationTime = 329, "RPL_CREATIONTIME";
+    RplWhoisAccount = 330, "RPL_WHOISACCOUNT";
+    RplNoTopic = 331, "RPL_NOTOPIC";
+    RplTopic = 332, "RPL_TOPIC";
+    RplTopicWhoTime = 333, "RPL_TOPICWHOTIME";
+    RplInviteList = 336, "RPL_INVITELIST";
+    RplEndOfInviteList = 337, "RPL_ENDOFINVITELIST";
+    RplWhoisActually = 338, "RPL_WHOISACTUALLY";
+    RplInviting = 341, "RPL_INVITING";
+    RplInvexList = 346, "RPL_INVEXLIST";
+    RplEndOfInvexList = 347, "RPL_ENDOFINVEXLIST";
+    RplExceptList = 348, "RPL_EXCEPTLIST";
+    RplEndOfExceptList = 349, "RPL_ENDOFEXCEPTLIST";
+    RplVersion = 351, "RPL_VERSION";
+    RplWhoReply = 352, "RPL_WHOREPLY";
+    RplNamReply = 353, "RPL_NAMREPLY";
+    RplWhoSpcRpl = 354, "RPL_WHOSPCRPL";
+    RplLinks = 364, "RPL_LINKS";
+    RplEndOfLinks = 365, "RPL_ENDOFLINKS";
+    RplEndOfNames = 366, "RPL_ENDOFNAMES";
+    RplBanList = 367, "RPL_BANLIST";
+    RplEndOfBanList = 368, "RPL_ENDOFBANLIST";
+    RplEndOfWhowas = 369, "RPL_ENDOFWHOWAS";
+    RplInfo = 371, "RPL_INFO";
+    RplMotd = 372, "RPL_MOTD";
+    RplEndOfInfo = 374, "RPL_ENDOFINFO";
+    RplMotdStart = 375, "RPL_MOTDSTART";
+    RplEndOfMotd = 376, "RPL_ENDOFMOTD";
+    RplWhoisHost = 378, "RPL_WHOISHOST";
+    RplWhoisModes = 379, "RPL_WHOISMODES";
+    RplYoureOper = 381, "RPL_YOUREOPER";
+    RplRehashing = 382, "RPL_REHASHING";
+    RplTime = 391, "RPL_TIME";
+    RplVisibleHost = 396, "RPL_VISIBLEHOST";
+    ErrUnknownError = 400, "ERR_UNKNOWNERROR";
+    ErrNoSuchNick = 401, "ERR_NOSUCHNICK";
+    ErrNoSuchServer = 402, "ERR_NOSUCHSERVER";
+    ErrNoSuchChannel = 403, "ERR_NOSUCHCHANNEL";
+    ErrCannotSendToChan = 404, "ERR_CANNOTSENDTOCHAN";
+    ErrTooManyChannels = 405, "ERR_TOOMANYCHANNELS";
+    ErrWasNoSuchNick = 406, "ERR_WASNOSUCHNICK";
+    ErrTooManyTargets = 407, "ERR_TOOMANYTARGETS";
+    ErrNoOrigin = 409, "ERR_NOORIGIN";
+    ErrNoRecipient = 411, "ERR_NORECIPIENT";
+    ErrNoTextToSend = 412, "ERR_NOTEXTTOSEND";
+    ErrInputTooLong = 417, "ERR_INPUTTOOLONG";
+    ErrUnknownCommand = 421, "ERR_UNKNOWNCOMMAND";
+    ErrNoMotd = 422, "ERR_NOMOTD";
+    ErrNoNicknameGiven = 431, "ERR_NONICKNAMEGIVEN";
+    ErrErroneusNickname = 432, "ERR_ERRONEUSNICKNAME";
+    ErrNicknameInUse = 433, "ERR_NICKNAMEINUSE";
+    ErrNickCollision = 436, "ERR_NICKCOLLISION";
+    ErrUnavailResource = 437, "ERR_UNAVAILRESOURCE";
+    ErrUserNotInChannel = 441, "ERR_USERNOTINCHANNEL";
+    ErrNotOnChannel = 442, "ERR_NOTONCHANNEL";
+    ErrUserOnChannel = 443, "ERR_USERONCHANNEL";
+    ErrNotRegistered = 451, "ERR_NOTREGISTERED";
+    ErrNeedMoreParams = 461, "ERR_NEEDMOREPARAMS";
+    ErrAlreadyRegistered = 462, "ERR_ALREADYREGISTERED";
+    ErrPasswdMismatch = 464, "ERR_PASSWDMISMATCH";
+    ErrYoureBannedCreep = 465, "ERR_YOUREBANNEDCREEP";
+    ErrChannelIsFull = 471, "ERR_CHANNELISFULL";
+    ErrUnknownMode = 472, "ERR_UNKNOWNMODE";
+    ErrInviteOnlyChan = 473, "ERR_INVITEONLYCHAN";
+    ErrBannedFromChan = 474, "ERR_BANNEDFROMCHAN";
+    ErrBadChannelKey = 475, "ERR_BADCHANNELKEY";
+    ErrBadChanMask = 476, "ERR_BADCHANMASK";
+    ErrNoPrivileges = 481, "ERR_NOPRIVILEGES";
+    ErrChanOPrivsNeeded = 482, "ERR_CHANOPRIVSNEEDED";
+    ErrCantKillServer = 483, "ERR_CANTKILLSERVER";
+    ErrNoOperHost = 491, "ERR_NOOPERHOST";
+    ErrUmodeUnknownFlag = 501, "ERR_UMODEUNKNOWNFLAG";
+    ErrUsersDontMatch = 502, "ERR_USERSDONTMATCH";
+    ErrHelpNotFound = 524, "ERR_HELPNOTFOUND";
+    ErrInvalidKey = 525, "ERR_INVALIDKEY";
+    RplStartTls = 670, "RPL_STARTTLS";
+    RplWhoisSecure = 671, "RPL_WHOISSECURE";
+    ErrStartTls = 691, "ERR_STARTTLS";
+    ErrInvalidModeParam = 696, "ERR_INVALIDMODEPARAM";
+    RplHelpStart = 704, "RPL_HELPSTART";
+    RplHelpTxt = 705, "RPL_HELPTXT";
+    RplEndOfHelp = 706, "RPL_ENDOFHELP";
+    ErrNoPrivs = 723, "ERR_NOPRIVS";
+    RplMonOnline = 730, "RPL_MONONLINE";
+    RplMonOffline = 731, "RPL_MONOFFLINE";
+    RplMonList = 732, "RPL_MONLIST";
+    RplEndOfMonList = 733, "RPL_ENDOFMONLIST";
+    ErrMonListFull = 734, "ERR_MONLISTFULL";
+    RplLoggedIn = 900, "RPL_LOGGEDIN";
+    RplLoggedOut = 901, "RPL_LOGGEDOUT";
+    ErrNickLocked = 902, "ERR_NICKLOCKED";
+    RplSaslSuccess = 903, "RPL_SASLSUCCESS";
+    ErrSaslFail = 904, "ERR_SASLFAIL";
+    ErrSaslTooLong = 905, "ERR_SASLTOOLONG";
+    ErrSaslAborted = 906, "ERR_SASLABORTED";
+    ErrSaslAlready = 907, "ERR_SASLALREADY";
+    RplSaslMechs = 908, "RPL_SASLMECHS";
+}
+
+impl Numeric {
+    /// True for error replies (400-599, plus the named `ERR_*` codes above 600).
+    pub fn is_error(&self) -> bool {
+        (400..600).contains(&self.code()) || self.name().is_some_and(|n| n.starts_with("ERR_"))
+    }
+}
+
+impl From<u16> for Numeric {
+    fn from(code: u16) -> Self {
+        Numeric::from_code(code)
+    }
+}
+
+/// A numeric reply with typed access to its well-known parameters.
+///
+/// Every numeric starts with the client's own nick (the target); accessors index past
+/// it. Accessors return `None` for numerics that don't carry the parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply<'a> {
+    pub numeric: Numeric,
+    params: Vec<&'a str>,
+}
+
+impl<'a> Reply<'a> {
+    pub fn from_command(command: &'a Command) -> Option<Self> {
+        let Command::Numeric {
+            code,
+            args,
+            trailing,
+        } = command
+        else {
+            return None;
+        };
+        Some(Reply {
+            numeric: *code,
+            params: args
+                .iter()
+                .map(String::as_str)
+                .chain(trailing.as_deref())
+                .collect(),
+        })
+    }
+
+    /// The nick the reply is addressed to (`*` before registration).
+    pub fn target(&self) -> Option<&'a str> {
+        self.params.first().copied()
+    }
+
+    /// The parameter at `index`, counting the target as 0.
+    pub fn param(&self, index: usize) -> Option<&'a str> {
+        self.params.get(index).copied()
+    }
+
+    /// The last parameter, which for most numerics is human-readable text.
+    pub fn text(&self) -> Option<&'a str> {
+        self.params
+            .last()
+            .copied()
+            .filter(|_| self.params.len() > 1)
+    }
+
+    /// The channel the reply is about.
+    pub fn channel(&self) -> Option<&'a str> {
+        use Numeric::*;
+        match self.numeric {
+            RplNamReply | RplInviting | ErrUserNotInChannel | ErrUserOnChannel => self.param(2),
+            RplList | RplChannelModeIs | RplCreationTime | RplNoTopic | RplTopic
+            | RplTopicWhoTime | RplInviteList | RplEndOfInviteList | RplInvexList
+            | RplEndOfInvexList | RplExceptList | RplEndOfExceptList | RplEndOfNames
+            | RplBanList | RplEndOfBanList | RplWhoReply | ErrNoSuchChannel
+            | ErrCannotSendToChan | ErrTooManyChannels | ErrNotOnChannel | ErrChannelIsFull
+            | ErrInviteOnlyChan | ErrBannedFromChan | ErrBadChannelKey | ErrBadChanMask
+            | ErrChanOPrivsNeeded => self.param(1),
+            _ => None,
+        }
+    }
+
+    /// The nick the reply is about, e.g. the one that is already in use.
+    pub fn nick(&self) -> Option<&'a str> {
+        use Numeric::*;
+        match self.numeric {
+            RplAway | RplWhoisUser | RplWhoisServer | RplWhoisOperator | RplWhowasUser
+            | RplWhoisIdle | RplEndOfWhois | RplWhoisChannels | RplWhoisSpecial
+            | RplWhoisAccount | RplWhoisActually | RplInviting | RplWhoisHost | RplWhoisModes
+            | RplWhoisCertFp | RplWhoisRegNick | RplWhoisSecure | RplEndOfWhowas
+            | ErrNoSuchNick | ErrWasNoSuchNick | ErrErroneusNickname | ErrNicknameInUse
+            | ErrNickCollision | ErrUnavailResource | ErrUserNotInChannel | ErrUserOnChannel
+            | ErrNickLocked => self.param(1),
+            RplWhoReply => self.param(5),
+            _ => None,
+        }
+    }
+
+    /// RPL_TOPIC: the channel topic.
+    pub fn topic(&self) -> Option<&'a str> {
+        match self.numeric {
+            Numeric::RplTopic => self.param(2),
+            Numeric::RplList => self.param(3),
+            _ => None,
+        }
+    }
+
+    /// RPL_TOPICWHOTIME: who set the topic, and when (as a Unix timestamp).
+    pub fn topic_setter(&self) -> Option<(&'a str, i64)> {
+        match self.numeric {
+            Numeric::RplTopicWhoTime => Some((self.param(2)?, self.param(3)?.parse().ok()?)),
+            _ => None,
+        }
+    }
+
+    /// RPL_NAMREPLY: the channel status symbol (`=` public, `*` private, `@` secret).
+    pub fn channel_status(&self) -> Option<char> {
+        match self.numeric {
+            Numeric::RplNamReply => self.param(1)?.chars().next(),
+            _ => None,
+        }
+    }
+
+    /// RPL_NAMREPLY: the listed nicks, each still carrying its prefix modes (`@`, `+`).
+    pub fn names(&self) -> impl Iterator<Item = &'a str> {
+        let names = match self.numeric {
+            Numeric::RplNamReply => self.param(3),
+            _ => None,
+        };
+        names.unwrap_or_default().split_ascii_whitespace()
+    }
+
+    /// RPL_ISUPPORT: the `KEY[=VALUE]` tokens, without the trailing text.
+    pub fn isupport_tokens(&self) -> &[&'a str] {
+        match self.numeric {
+            Numeric::RplISupport if self.params.len() > 2 => &self.params[1..self.params.len() - 1],
+            _ => &[],
+        }
+    }
+
+    /// RPL_MONONLINE / RPL_MONOFFLINE: the targets (`nick!user@host` or `nick`).
+    pub fn monitor_targets(&self) -> impl Iterator<Item = &'a str> {
+        let targets = match self.numeric {
+            Numeric::RplMonOnline | Numeric::RplMonOffline | Numeric::RplMonList => self.param(1),
+            _ => None,
+        };
+        targets
+            .unwrap_or_default()
+            .split(',')
+            .filter(|t| !t.is_empty())
+    }
+
+    /// RPL_SASLMECHS: the mechanisms the server accepts.
+    pub fn sasl_mechanisms(&self) -> impl Iterator<Item = &'a str> {
+        let mechs = match self.numeric {
+            Numeric::RplSaslMechs => self.param(1),
+            _ => None,
+        };
+        mechs
+            .unwrap_or_default()
+            .split(',')
+            .filter(|m| !m.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::irc_msg::Msg;
+
+    fn parse(line: &str) -> Msg {
+        Msg::parse(line, SystemTime::UNIX_EPOCH.into()).unwrap()
+    }
+
+    #[test]
+    fn numeric_codes_roundtrip() {
+        for code in 0..1000 {
+            assert_eq!(Numeric::from_code(code).code(), code);
+        }
+        assert_eq!(Numeric::from_code(433), Numeric::ErrNicknameInUse);
+        assert_eq!(Numeric::ErrNicknameInUse.name(), Some("ERR_NICKNAMEINUSE"));
+        assert_eq!(Numeric::from_code(999), Numeric::Unknown(999));
+        assert_eq!(Numeric::Unknown(999).name(), None);
+    }
+
+    #[test]
+    fn numeric_is_error() {
+        assert!(Numeric::ErrNoMotd.is_error());
+        assert!(Numeric::ErrSaslFail.is_error());
+        assert!(!Numeric::RplEndOfMotd.is_error());
+        assert!(!Numeric::RplSaslSuccess.is_error());
+    }
+
+    #[test]
+    fn reply_names() {
+        let msg = parse(":irc.example.com 353 botty = #channel :@alice +bob carol");
+        let reply = Reply::from_command(&msg.command).unwrap();
+
+        assert_eq!(reply.numeric, Numeric::RplNamReply);
+        assert_eq!(reply.target(), Some("botty"));
+        assert_eq!(reply.channel(), Some("#channel"));
+        assert_eq!(reply.channel_status(), Some('='));
+        assert_eq!(
+            reply.names().collect::<Vec<_>>(),
+            vec!["@alice", "+bob", "carol"]
+        );
+    }
+
+    #[test]
+    fn reply_topic() {
+        let msg = parse(":irc.example.com 332 botty #channel :all about rust");
+        let reply = Reply::from_command(&msg.command).unwrap();
+        assert_eq!(reply.channel(), Some("#channel"));
+        assert_eq!(reply.topic(), Some("all about rust"));
+
+        let msg = parse(":irc.example.com 333 botty #channel alice!a@host 1700000000");
+        let reply = Reply::from_command(&msg.command).unwrap();
+        assert_eq!(reply.topic_setter(), Some(("alice!a@host", 1_700_000_000)));
+    }
+
+    #[test]
+    fn reply_nick_in_use() {
+        let msg = parse(":irc.example.com 433 * botty :Nickname is already in use");
+        let reply = Reply::from_command(&msg.command).unwrap();
+
+        assert_eq!(reply.numeric, Numeric::ErrNicknameInUse);
+        assert_eq!(reply.nick(), Some("botty"));
+        assert_eq!(reply.channel(), None);
+        assert_eq!(reply.text(), Some("Nickname is already in use"));
+    }
+
+    #[test]
+    fn reply_isupport_tokens() {
+        let msg = parse(
+            ":irc.example.com 005 botty CHANTYPES=# NICKLEN=30 :are supported by this server",
+        );
+        let reply = Reply::from_command(&msg.command).unwrap();
+
+        assert_eq!(reply.isupport_tokens(), &["CHANTYPES=#", "NICKLEN=30"]);
+    }
+
+    #[test]
+    fn reply_monitor_targets() {
+        let msg = parse(":irc.example.com 730 botty :alice!a@host,bob!b@host");
+        let reply = Reply::from_command(&msg.command).unwrap();
+
+        assert_eq!(
+            reply.monitor_targets().collect::<Vec<_>>(),
+            vec!["alice!a@host", "bob!b@host"]
+        );
+    }
+}
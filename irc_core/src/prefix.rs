@@ -0,0 +1,180 @@
+//! Message sources (`:nick!user@host` or `:irc.example.com`).
+
+use std::fmt;
+
+/// Where a message came from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Prefix {
+    /// A server name, e.g. `irc.example.com`.
+    Server(String),
+    /// A user. `user` and `host` are omitted by some servers, e.g. in `:nick MODE nick +i`.
+    User {
+        nick: String,
+        user: Option<String>,
+        host: Option<String>,
+    },
+}
+
+impl Prefix {
+    /// Parses a source without its leading `:`.
+    ///
+    /// A bare name containing a `.` is taken to be a server, as nicks cannot contain one.
+    pub fn parse(source: &str) -> Self {
+        let (rest, host) = match source.split_once('@') {
+            Some((rest, host)) => (rest, Some(host)),
+            None => (source, None),
+        };
+        let (nick, user) = match rest.split_once('!') {
+            Some((nick, user)) => (nick, Some(user)),
+            None => (rest, None),
+        };
+
+        if user.is_none() && host.is_none() && nick.contains('.') {
+            return Prefix::Server(nick.to_owned());
+        }
+        Prefix::User {
+            nick: nick.to_owned(),
+            user: user.map(str::to_owned),
+            host: host.map(str::to_owned),
+        }
+    }
+
+    /// The nick, or `None` if the message came from a server.
+    pub fn nick(&self) -> Option<&str> {
+        match self {
+            Prefix::User { nick, .. } if !nick.is_empty() => Some(nick),
+            _ => None,
+        }
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            Prefix::User { user, .. } => user.as_deref(),
+            Prefix::Server(_) => None,
+        }
+    }
+
+    /// The user's host, or the server name for a server prefix.
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            Prefix::User { host, .. } => host.as_deref(),
+            Prefix::Server(name) => Some(name),
+        }
+    }
+
+    /// The nick for a user, or the server name.
+    pub fn name(&self) -> &str {
+        match self {
+            Prefix::User { nick, .. } => nick,
+            Prefix::Server(name) => name,
+        }
+    }
+}
+
+impl From<&str> for Prefix {
+    fn from(source: &str) -> Self {
+        Prefix::parse(source)
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prefix::Server(name) => f.write_str(name),
+            Prefix::User { nick, user, host } => {
+                f.write_str(nick)?;
+                if let Some(user) = user {
+                    write!(f, "!{}", user)?;
+                }
+                if let Some(host) = host {
+                    write!(f, "@{}", host)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_full_user() {
+        let prefix = Prefix::parse("alice!~a@example.org");
+        assert_eq!(prefix.nick(), Some("alice"));
+        assert_eq!(prefix.user(), Some("~a"));
+        assert_eq!(prefix.host(), Some("example.org"));
+        assert_eq!(prefix.to_string(), "alice!~a@example.org");
+    }
+
+    #[test]
+    fn parse_partial_user() {
+        let prefix = Prefix::parse("alice@example.org");
+        assert_eq!(prefix.nick(), Some("alice"));
+        assert_eq!(prefix.user(), None);
+        assert_eq!(prefix.host(), Some("example.org"));
+
+        let prefix = Prefix::parse("alice");
+        assert_eq!(
+            prefix,
+            Prefix::User {
+                nick: "alice".into(),
+                user: None,
+                host: None,
+            }
+        );
+        assert_eq!(prefix.to_string(), "alice");
+    }
+
+    #[test]
+    fn parse_server() {
+        let prefix = Prefix::parse("irc.example.com");
+        assert_eq!(prefix, Prefix::Server("irc.example.com".into()));
+        assert_eq!(prefix.nick(), None);
+        assert_eq!(prefix.name(), "irc.example.com");
+        assert_eq!(prefix.to_string(), "irc.example.com");
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct UserhostTest {
+        source: String,
+        atoms: UserhostAtoms,
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    struct UserhostAtoms {
+        nick: Option<String>,
+        user: Option<String>,
+        host: Option<String>,
+    }
+
+    #[test]
+    fn parser_tests_userhost_split() {
+        let tests: Vec<UserhostTest> =
+            crate::testing::vectors(include_str!("../testdata/parser-tests/userhost-split.yaml"));
+        assert!(!tests.is_empty());
+
+        for test in tests {
+            let prefix = Prefix::parse(&test.source);
+            assert_eq!(
+                prefix.nick(),
+                test.atoms.nick.as_deref(),
+                "{:?}",
+                test.source
+            );
+            assert_eq!(
+                prefix.user(),
+                test.atoms.user.as_deref(),
+                "{:?}",
+                test.source
+            );
+            assert_eq!(
+                prefix.host(),
+                test.atoms.host.as_deref(),
+                "{:?}",
+                test.source
+            );
+        }
+    }
+}
@@ -0,0 +1,706 @@
+//! Queries whose answers span several lines: WHOIS, WHO, LIST, channel MODE and
+//! CHATHISTORY.
+//!
+//! [`Queries`] matches incoming replies to the query that asked for them. With IRCv3
+//! `labeled-response` each query carries a `label` tag that the server echoes back,
+//! usually on a batch wrapping the whole answer. Without it, a reply goes to the
+//! oldest pending query it could belong to, since servers answer in order.
+
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Local};
+use tokio::sync::oneshot;
+
+use crate::casemap::CaseMapping;
+use crate::irc_msg::{Command, Msg};
+use crate::numeric::{Numeric, Reply};
+
+/// What [`Client::whois`](crate::client::Client::whois) found out about a user.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhoisInfo {
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub realname: String,
+    /// The server they are connected to, and its description.
+    pub server: Option<(String, String)>,
+    /// The services account they are logged in to.
+    pub account: Option<String>,
+    /// Channels as the server lists them, each with the user's prefix (`@#rust`).
+    pub channels: Vec<String>,
+    pub away: Option<String>,
+    pub idle: Option<Duration>,
+    pub signed_on: Option<DateTime<Local>>,
+    pub operator: bool,
+    /// Connected over TLS.
+    pub secure: bool,
+}
+
+impl WhoisInfo {
+    /// Collects the replies to a WHOIS, or `None` if there is no such user.
+    pub(crate) fn from_replies(replies: &[Msg]) -> Option<Self> {
+        let mut info = None;
+        for reply in replies.iter().filter_map(Msg::reply) {
+            if reply.numeric == Numeric::RplWhoisUser {
+                info = Some(WhoisInfo {
+                    nick: reply.param(1)?.to_owned(),
+                    user: reply.param(2)?.to_owned(),
+                    host: reply.param(3)?.to_owned(),
+                    realname: reply.param(5).unwrap_or_default().to_owned(),
+                    ..Default::default()
+                });
+            }
+        }
+        let mut info = info?;
+
+        for reply in replies.iter().filter_map(Msg::reply) {
+            let param = |i| reply.param(i).map(str::to_owned);
+            match reply.numeric {
+                Numeric::RplWhoisServer => info.server = param(2).zip(param(3)),
+                Numeric::RplWhoisAccount => info.account = param(2),
+                Numeric::RplWhoisChannels => info.channels.extend(
+                    reply
+                        .param(2)
+                        .unwrap_or_default()
+                        .split_ascii_whitespace()
+                        .map(str::to_owned),
+                ),
+                Numeric::RplAway => info.away = param(2),
+                Numeric::RplWhoisIdle => {
+                    info.idle = reply
+                        .param(2)
+                        .and_then(|s| s.parse().ok())
+                        .map(Duration::from_secs);
+                    info.signed_on = reply
+                        .param(3)
+                        .and_then(|s| s.parse().ok())
+                        .map(|secs| (SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).into());
+                }
+                Numeric::RplWhoisOperator => info.operator = true,
+                Numeric::RplWhoisSecure => info.secure = true,
+                _ => {}
+            }
+        }
+        Some(info)
+    }
+}
+
+/// One RPL_WHOREPLY.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoEntry {
+    /// A channel the user is in, if the server picked one.
+    pub channel: Option<String>,
+    pub nick: String,
+    pub user: String,
+    pub host: String,
+    pub server: String,
+    /// `H` (here) or `G` (gone), then `*` for operators and the user's prefixes.
+    pub flags: String,
+    pub hops: u32,
+    pub realname: String,
+}
+
+impl WhoEntry {
+    pub fn is_away(&self) -> bool {
+        self.flags.starts_with('G')
+    }
+
+    pub fn is_operator(&self) -> bool {
+        self.flags.contains('*')
+    }
+
+    fn from_reply(reply: &Reply<'_>) -> Option<Self> {
+        let (hops, realname) = reply
+            .param(7)?
+            .split_once(' ')
+            .unwrap_or((reply.param(7)?, ""));
+        Some(WhoEntry {
+            channel: reply.param(1).filter(|c| *c != "*").map(str::to_owned),
+            user: reply.param(2)?.to_owned(),
+            host: reply.param(3)?.to_owned(),
+            server: reply.param(4)?.to_owned(),
+            nick: reply.param(5)?.to_owned(),
+            flags: reply.param(6)?.to_owned(),
+            hops: hops.parse().unwrap_or_default(),
+            realname: realname.to_owned(),
+        })
+    }
+
+    pub(crate) fn from_replies(replies: &[Msg]) -> Vec<Self> {
+        replies
+            .iter()
+            .filter_map(Msg::reply)
+            .filter(|r| r.numeric == Numeric::RplWhoReply)
+            .filter_map(|r| Self::from_reply(&r))
+            .collect()
+    }
+}
+
+/// One channel from a LIST.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelListing {
+    pub channel: String,
+    pub users: usize,
+    pub topic: String,
+}
+
+impl ChannelListing {
+    pub(crate) fn from_replies(replies: &[Msg]) -> Vec<Self> {
+        replies
+            .iter()
+            .filter_map(Msg::reply)
+            .filter(|r| r.numeric == Numeric::RplList)
+            .filter_map(|r| {
+                Some(ChannelListing {
+                    channel: r.channel()?.to_owned(),
+                    users: r.param(2)?.parse().unwrap_or_default(),
+                    topic: r.topic().unwrap_or_default().to_owned(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// A channel's modes as RPL_CHANNELMODEIS reports them, e.g. `+nt` or `+lk` with
+/// `["50", "secret"]`. Servers may hide parameters such as the key from non-members.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelModes {
+    pub modes: String,
+    pub args: Vec<String>,
+}
+
+impl ChannelModes {
+    pub(crate) fn from_replies(replies: &[Msg]) -> Option<Self> {
+        let reply = replies
+            .iter()
+            .filter_map(Msg::reply)
+            .find(|r| r.numeric == Numeric::RplChannelModeIs)?;
+        Some(ChannelModes {
+            modes: reply.param(2)?.to_owned(),
+            args: (3..)
+                .map_while(|i| reply.param(i))
+                .map(str::to_owned)
+                .collect(),
+        })
+    }
+}
+
+/// The kinds of query that [`Queries`] can follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QueryKind {
+    Whois,
+    Who,
+    List,
+    Mode,
+    History,
+}
+
+impl QueryKind {
+    pub fn command(self) -> &'static str {
+        match self {
+            QueryKind::Whois => "WHOIS",
+            QueryKind::Who => "WHO",
+            QueryKind::List => "LIST",
+            QueryKind::Mode => "MODE",
+            QueryKind::History => "CHATHISTORY",
+        }
+    }
+
+    /// True if an unlabeled `reply` can be part of the answer to this kind of query
+    /// about `target`, given the replies collected `so_far`. Errors are only claimed
+    /// where nothing else could have caused them, so that handlers still see those
+    /// from other commands.
+    fn accepts(
+        self,
+        reply: &Reply<'_>,
+        target: &str,
+        mapping: CaseMapping,
+        so_far: &[Msg],
+    ) -> bool {
+        use Numeric::*;
+        let about = |name: Option<&str>| name.is_some_and(|name| mapping.eq(name, target));
+        match self {
+            // Once the nick turned out to exist, a 401 is about something else, e.g. a
+            // PRIVMSG to them.
+            QueryKind::Whois if reply.numeric == ErrNoSuchNick => {
+                about(reply.nick())
+                    && !so_far
+                        .iter()
+                        .filter_map(Msg::reply)
+                        .any(|r| r.numeric == RplWhoisUser)
+            }
+            QueryKind::Whois => {
+                matches!(
+                    reply.numeric,
+                    RplAway
+                        | RplWhoisUser
+                        | RplWhoisServer
+                        | RplWhoisOperator
+                        | RplWhoisIdle
+                        | RplEndOfWhois
+                        | RplWhoisChannels
+                        | RplWhoisSpecial
+                        | RplWhoisAccount
+                        | RplWhoisActually
+                        | RplWhoisHost
+                        | RplWhoisModes
+                        | RplWhoisCertFp
+                        | RplWhoisRegNick
+                        | RplWhoisSecure
+                ) && about(reply.nick())
+            }
+            // Entries don't say which mask they matched, only the end marker does.
+            QueryKind::Who => match reply.numeric {
+                RplWhoReply => true,
+                RplEndOfWho => about(reply.param(1)),
+                _ => false,
+            },
+            QueryKind::List => matches!(reply.numeric, RplListStart | RplList | RplListEnd),
+            QueryKind::Mode => {
+                matches!(
+                    reply.numeric,
+                    RplChannelModeIs | ErrNoSuchChannel | ErrNotOnChannel | ErrBadChanMask
+                ) && about(reply.channel())
+            }
+            // History comes in a batch, not as numerics.
+            QueryKind::History => false,
+        }
+    }
+
+    /// True if `reply` is the last one of the answer.
+    fn ends(self, reply: &Reply<'_>) -> bool {
+        match self {
+            QueryKind::Whois => reply.numeric == Numeric::RplEndOfWhois,
+            QueryKind::Who => reply.numeric == Numeric::RplEndOfWho,
+            QueryKind::List => reply.numeric == Numeric::RplListEnd,
+            // RPL_CREATIONTIME often follows, but not always.
+            QueryKind::Mode | QueryKind::History => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    id: u64,
+    kind: QueryKind,
+    target: String,
+    label: Option<String>,
+    /// References of the batch carrying the answer, once opened, then of any batches
+    /// nested inside it.
+    batches: Vec<String>,
+    replies: Vec<Msg>,
+    done: oneshot::Sender<Vec<Msg>>,
+}
+
+/// Queries sent but not yet fully answered, oldest first.
+#[derive(Debug, Default)]
+pub(crate) struct Queries {
+    pending: Vec<Pending>,
+    next_id: u64,
+}
+
+impl Queries {
+    /// Registers a query about `target`, returning an id for [`cancel`](Self::cancel),
+    /// the label to send it with if `labeled`, and where its replies will arrive.
+    pub fn start(
+        &mut self,
+        kind: QueryKind,
+        target: &str,
+        labeled: bool,
+    ) -> (u64, Option<String>, oneshot::Receiver<Vec<Msg>>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let label = labeled.then(|| format!("q{id}"));
+        let (done, replies) = oneshot::channel();
+        self.pending.push(Pending {
+            id,
+            kind,
+            target: target.to_owned(),
+            label: label.clone(),
+            batches: vec![],
+            replies: vec![],
+            done,
+        });
+        (id, label, replies)
+    }
+
+    /// Forgets a query, e.g. because it timed out.
+    pub fn cancel(&mut self, id: u64) {
+        self.pending.retain(|p| p.id != id);
+    }
+
+    /// Forgets every query, failing them. Called when the connection drops.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Routes a line from the server to the query it answers, if any. Returns true if
+    /// the line was part of an answer, and so is not for anyone else.
+    pub fn on_line(&mut self, line: &str, mapping: CaseMapping) -> bool {
+        if self.pending.is_empty() {
+            return false;
+        }
+        Msg::parse(line, Local::now()).is_some_and(|msg| self.on_msg(msg, mapping))
+    }
+
+    fn on_msg(&mut self, msg: Msg, mapping: CaseMapping) -> bool {
+        let batch = match &msg.command {
+            Command::Raw { command, args } if command == "BATCH" => Some(args.as_slice()),
+            _ => None,
+        };
+        let opened = batch.and_then(|args| args.first()?.strip_prefix('+'));
+        let closed = batch.and_then(|args| args.first()?.strip_prefix('-'));
+
+        if let Some(label) = msg.tag("label") {
+            let Some(i) = self.position(|p| p.label.as_deref() == Some(label)) else {
+                return false;
+            };
+            // A multi-line answer opens a batch; anything else is the whole answer,
+            // or an ACK standing in for an empty one.
+            if let Some(reference) = opened {
+                self.pending[i].batches.push(reference.to_owned());
+                return true;
+            }
+            if !is_verb(&msg, "ACK") {
+                self.pending[i].replies.push(msg);
+            }
+            self.finish(i);
+            return true;
+        }
+
+        if let Some(reference) = closed {
+            let Some(i) = self.position(|p| p.batches.iter().any(|b| b == reference)) else {
+                return false;
+            };
+            if self.pending[i].batches[0] == reference {
+                self.finish(i);
+            }
+            return true;
+        }
+        if let Some(reference) = msg.tag("batch")
+            && let Some(i) = self.position(|p| p.batches.iter().any(|b| b == reference))
+        {
+            match opened {
+                Some(nested) => self.pending[i].batches.push(nested.to_owned()),
+                None => self.pending[i].replies.push(msg),
+            }
+            return true;
+        }
+
+        // Without labels, history is recognised by the batch type and target, and
+        // failures by the command they name.
+        if let (Some(reference), Some(args)) = (opened, batch)
+            && args
+                .get(1)
+                .is_some_and(|kind| kind == "chathistory" || kind == "draft/chathistory")
+        {
+            let target = args.get(2).map(String::as_str).unwrap_or_default();
+            let Some(i) = self.position(|p| {
+                p.label.is_none()
+                    && p.kind == QueryKind::History
+                    && p.batches.is_empty()
+                    && mapping.eq(&p.target, target)
+            }) else {
+                return false;
+            };
+            self.pending[i].batches.push(reference.to_owned());
+            return true;
+        }
+        if let Command::Raw { command, args } = &msg.command
+            && command == "FAIL"
+            && let Some(failed) = args.first()
+        {
+            let Some(i) = self.position(|p| p.label.is_none() && p.kind.command() == failed) else {
+                return false;
+            };
+            self.pending[i].replies.push(msg);
+            self.finish(i);
+            return true;
+        }
+
+        let Some(reply) = msg.reply() else {
+            return false;
+        };
+        let Some(i) = self.position(|p| {
+            p.label.is_none() && p.kind.accepts(&reply, &p.target, mapping, &p.replies)
+        }) else {
+            return false;
+        };
+        let ends = self.pending[i].kind.ends(&reply);
+        self.pending[i].replies.push(msg);
+        if ends {
+            self.finish(i);
+        }
+        true
+    }
+
+    fn position(&self, f: impl Fn(&Pending) -> bool) -> Option<usize> {
+        self.pending.iter().position(f)
+    }
+
+    fn finish(&mut self, i: usize) {
+        let pending = self.pending.remove(i);
+        // The caller may have given up waiting already.
+        let _ = pending.done.send(pending.replies);
+    }
+}
+
+fn is_verb(msg: &Msg, verb: &str) -> bool {
+    matches!(&msg.command, Command::Raw { command, .. } if command == verb)
+}
+
+/// The description from a `FAIL` standard reply, e.g. to CHATHISTORY.
+pub(crate) fn failure(msg: &Msg) -> Option<&str> {
+    match &msg.command {
+        Command::Raw { command, args } if command == "FAIL" => args.last().map(String::as_str),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::client::ClientBuilder;
+    use crate::flood::FloodControl;
+    use crate::testing::{accept_and_register, wait_for};
+
+    /// Feeds lines to `queries`, returning those that weren't claimed.
+    fn feed<'a>(queries: &mut Queries, lines: &[&'a str]) -> Vec<&'a str> {
+        lines
+            .iter()
+            .copied()
+            .filter(|line| !queries.on_line(line, CaseMapping::default()))
+            .collect()
+    }
+
+    #[test]
+    fn unlabeled_replies_go_to_the_matching_query() {
+        let mut queries = Queries::default();
+        let (_, label, mut alice) = queries.start(QueryKind::Whois, "alice", false);
+        assert_eq!(label, None);
+        let (_, _, mut bob) = queries.start(QueryKind::Whois, "bob", false);
+        let (_, _, mut list) = queries.start(QueryKind::List, "", false);
+
+        feed(
+            &mut queries,
+            &[
+                ":srv 401 botty bob :No such nick",
+                ":srv 311 botty Alice ali alice.host * :Alice Liddell",
+                ":srv 319 botty Alice :@#rust #go",
+                ":srv 330 botty Alice alice :is logged in as",
+                ":srv 317 botty Alice 42 1700000000 :seconds idle, signon time",
+                ":srv 318 botty bob :End of /WHOIS list.",
+                ":srv 322 botty #rust 12 :[+nt] Rust talk",
+                ":srv 318 botty Alice :End of /WHOIS list.",
+            ],
+        );
+
+        assert_eq!(WhoisInfo::from_replies(&bob.try_recv().unwrap()), None);
+        let info = WhoisInfo::from_replies(&alice.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            (
+                info.nick.as_str(),
+                info.host.as_str(),
+                info.realname.as_str()
+            ),
+            ("Alice", "alice.host", "Alice Liddell")
+        );
+        assert_eq!(info.channels, ["@#rust", "#go"]);
+        assert_eq!(info.account.as_deref(), Some("alice"));
+        assert_eq!(info.idle, Some(Duration::from_secs(42)));
+        assert!(info.signed_on.is_some());
+        assert!(list.try_recv().is_err(), "LIST hasn't ended yet");
+
+        feed(&mut queries, &[":srv 323 botty :End of /LIST"]);
+        assert_eq!(
+            ChannelListing::from_replies(&list.try_recv().unwrap()),
+            [ChannelListing {
+                channel: "#rust".into(),
+                users: 12,
+                topic: "[+nt] Rust talk".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn errors_from_other_commands_are_left_alone() {
+        let mut queries = Queries::default();
+        let (_, _, mut modes) = queries.start(QueryKind::Mode, "#rust", false);
+        let (_, _, mut alice) = queries.start(QueryKind::Whois, "alice", false);
+
+        let unclaimed = feed(
+            &mut queries,
+            &[
+                // A KICK sent while the MODE query was pending.
+                ":srv 482 botty #rust :You're not channel operator",
+                ":srv 311 botty alice a alice.host * :Alice",
+                // A PRIVMSG to alice, who quit in the meantime.
+                ":srv 401 botty alice :No such nick/channel",
+                ":srv 324 botty #rust +nt",
+                ":srv 318 botty alice :End of /WHOIS list.",
+            ],
+        );
+        assert_eq!(
+            unclaimed,
+            [
+                ":srv 482 botty #rust :You're not channel operator",
+                ":srv 401 botty alice :No such nick/channel",
+            ]
+        );
+
+        assert_eq!(
+            ChannelModes::from_replies(&modes.try_recv().unwrap()),
+            Some(ChannelModes {
+                modes: "+nt".into(),
+                args: vec![],
+            })
+        );
+        let info = WhoisInfo::from_replies(&alice.try_recv().unwrap()).unwrap();
+        assert_eq!(info.realname, "Alice");
+    }
+
+    #[test]
+    fn labeled_replies_are_collected_from_their_batch() {
+        let mut queries = Queries::default();
+        let (_, mode_label, mut modes) = queries.start(QueryKind::Mode, "#rust", true);
+        let (_, who_label, mut who) = queries.start(QueryKind::Who, "#rust", true);
+        let (_, _, mut list) = queries.start(QueryKind::List, "", true);
+        assert_eq!(
+            (mode_label.as_deref(), who_label.as_deref()),
+            (Some("q0"), Some("q1"))
+        );
+
+        let unclaimed = feed(
+            &mut queries,
+            &[
+                "@label=q1 :srv BATCH +w1 labeled-response",
+                // An unlabeled reply to someone else's WHO isn't ours.
+                ":srv 352 botty #go x y srv zed H :0 Zed",
+                "@batch=w1 :srv 352 botty #rust a alice.host srv alice G*@ :0 Alice Liddell",
+                "@label=q0 :srv 324 botty #rust +lk 50 secret",
+                "@batch=w1 :srv 315 botty #rust :End of WHO list",
+                ":srv BATCH -w1",
+                "@label=q2 :srv ACK",
+            ],
+        );
+        assert_eq!(unclaimed, [":srv 352 botty #go x y srv zed H :0 Zed"]);
+
+        assert_eq!(
+            ChannelModes::from_replies(&modes.try_recv().unwrap()),
+            Some(ChannelModes {
+                modes: "+lk".into(),
+                args: vec!["50".into(), "secret".into()],
+            })
+        );
+        let entries = WhoEntry::from_replies(&who.try_recv().unwrap());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].nick, "alice");
+        assert_eq!(entries[0].realname, "Alice Liddell");
+        assert!(entries[0].is_away() && entries[0].is_operator());
+        assert!(list.try_recv().unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_batches_and_failures_find_their_query() {
+        let mut queries = Queries::default();
+        let (_, _, mut rust) = queries.start(QueryKind::History, "#rust", false);
+        let (_, _, mut go) = queries.start(QueryKind::History, "#go", false);
+        let (_, label, mut nested) = queries.start(QueryKind::History, "#c", true);
+        assert_eq!(label.as_deref(), Some("q2"));
+
+        let unclaimed = feed(
+            &mut queries,
+            &[
+                ":srv BATCH +h1 chathistory #RUST",
+                "@batch=h1 :alice!a@h PRIVMSG #rust :one",
+                ":srv BATCH +ns netsplit a b",
+                "@batch=ns :bob!b@h QUIT :a b",
+                "@batch=h1 :alice!a@h PRIVMSG #rust :two",
+                ":srv BATCH -h1",
+                ":srv FAIL CHATHISTORY INVALID_TARGET #go :No such channel",
+                "@label=q2 :srv BATCH +l labeled-response",
+                "@batch=l :srv BATCH +h2 chathistory #c",
+                "@batch=h2 :carol!c@h PRIVMSG #c :three",
+                ":srv BATCH -h2",
+                ":srv BATCH -l",
+            ],
+        );
+        assert_eq!(
+            unclaimed,
+            [
+                ":srv BATCH +ns netsplit a b",
+                "@batch=ns :bob!b@h QUIT :a b"
+            ]
+        );
+
+        assert_eq!(rust.try_recv().unwrap().len(), 2);
+        let go = go.try_recv().unwrap();
+        assert_eq!(failure(&go[0]), Some("No such channel"));
+        assert_eq!(nested.try_recv().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn cancelled_and_cleared_queries_fail() {
+        let mut queries = Queries::default();
+        let (id, _, mut cancelled) = queries.start(QueryKind::Mode, "#rust", false);
+        let (_, _, mut cleared) = queries.start(QueryKind::Mode, "#go", false);
+
+        queries.cancel(id);
+        feed(&mut queries, &[":srv 324 botty #rust +nt"]);
+        assert!(cancelled.try_recv().is_err());
+
+        queries.clear();
+        assert!(matches!(
+            cleared.try_recv(),
+            Err(oneshot::error::TryRecvError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn client_awaits_answers_without_handlers_seeing_them() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut lines, mut write) = accept_and_register(&listener).await;
+            wait_for(&mut lines, "WHOIS alice").await;
+            write
+                .write_all(
+                    b":srv 311 botty alice a alice.host * :Alice\r\n\
+                      :alice!a@alice.host PRIVMSG botty :hi\r\n\
+                      :srv 318 botty alice :End of /WHOIS list.\r\n",
+                )
+                .await
+                .unwrap();
+            // Never answered.
+            wait_for(&mut lines, "WHO #rust").await;
+            wait_for(&mut lines, "QUIT").await;
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_flood_control(FloodControl::unlimited())
+            .with_query_timeout(Duration::from_millis(200))
+            .connect()
+            .await
+            .unwrap();
+        let info = client.whois("alice").await.unwrap().unwrap();
+        assert_eq!((info.user.as_str(), info.realname.as_str()), ("a", "Alice"));
+        let err = client.who("#rust").await.unwrap_err();
+        assert!(err.to_string().contains("no answer to WHO #rust"), "{err}");
+
+        // The PRIVMSG in the middle of the answer still reaches the message stream.
+        loop {
+            let msg = client.recv().await.unwrap().unwrap();
+            if let Some(reply) = msg.reply() {
+                assert!(!QueryKind::Whois.accepts(&reply, "alice", CaseMapping::default(), &[]));
+            }
+            if let Command::Privmsg { .. } = msg.command {
+                break;
+            }
+        }
+        client.quit(None).await.unwrap();
+        server.await.unwrap();
+    }
+}
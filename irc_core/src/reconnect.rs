@@ -0,0 +1,654 @@
+//! Keeps a [`Client`](crate::client::Client) connected: when the socket drops, the
+//! supervisor reconnects with exponential backoff, re-registers and rejoins channels,
+//! while the client handle keeps the same channels throughout.
+
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::sync::mpsc::{Receiver, Sender, WeakSender};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
+
+use crate::client::{Config, Incoming, Shared, join_command};
+use crate::decode::{LineReader, Read};
+use crate::flood::{self, WriterExit};
+use crate::isupport::ServerInfo;
+use crate::keepalive::{Pinger, Tick};
+use crate::{Connection, establish};
+
+/// When and how often to reconnect after losing the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    enabled: bool,
+    initial_delay: Duration,
+    max_delay: Duration,
+    max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    /// Reconnects forever, starting after about a second and backing off to at most
+    /// five minutes between attempts.
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(300),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Never reconnects: the client's event stream ends when the connection does.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    pub fn with_max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Gives up after `attempts` failed reconnects in a row.
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// The delay before the 0-based `attempt`: doubling each time up to the maximum,
+    /// with the upper half scaled by `jitter` (in `0.0..1.0`) so that many clients
+    /// dropped at once don't all come back in lockstep.
+    fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.min(31));
+        let capped = self
+            .initial_delay
+            .saturating_mul(factor)
+            .min(self.max_delay);
+        capped.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+/// Why a connection ended.
+enum Exit {
+    /// Every [`Client`](crate::client::Client) handle was dropped; nothing left to do.
+    ClientGone,
+    Disconnected,
+}
+
+/// Runs `conn` until it drops, then reconnects according to the policy, for as long
+/// as anyone holds the client. Dropping `incoming` is what ends the client's stream.
+///
+/// `pings` feeds keepalive pings into the outgoing queue. It is weak so that the
+/// outgoing channel still closes once every client handle is gone.
+pub(crate) async fn supervise(
+    config: Config,
+    mut conn: Connection,
+    pings: WeakSender<String>,
+    mut outgoing: Receiver<String>,
+    incoming: Sender<Incoming>,
+    shared: Arc<Shared>,
+) {
+    loop {
+        match run_connection(&config, conn, &pings, &mut outgoing, &incoming, &shared).await {
+            Exit::ClientGone => return,
+            Exit::Disconnected => {}
+        }
+        if incoming.send(Incoming::Disconnected).await.is_err() {
+            return;
+        }
+        if shared.quit_sent.load(Ordering::Relaxed) {
+            info!("connection closed after QUIT");
+            return;
+        }
+        if !config.reconnect.enabled {
+            return;
+        }
+        conn = match reconnect(&config, &incoming, &shared).await {
+            Some(conn) => conn,
+            None => return,
+        };
+    }
+}
+
+/// Waits out the backoff and connects again, until it works, the policy gives up, or
+/// QUIT is sent in the meantime.
+async fn reconnect(
+    config: &Config,
+    incoming: &Sender<Incoming>,
+    shared: &Shared,
+) -> Option<Connection> {
+    let policy = config.reconnect;
+    let mut attempt = 0;
+    loop {
+        if policy.max_attempts.is_some_and(|max| attempt >= max) {
+            error!("giving up after {} reconnect attempts", attempt);
+            return None;
+        }
+        let delay = policy.delay(attempt, rand::rng().random());
+        warn!("reconnecting in {:.1?} (attempt {})", delay, attempt + 1);
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = incoming.closed() => return None,
+            _ = shared.quit.notified() => {
+                info!("QUIT sent while disconnected, not reconnecting");
+                return None;
+            }
+        }
+        let established = tokio::select! {
+            established = establish(config) => established,
+            _ = shared.quit.notified() => {
+                info!("QUIT sent while reconnecting, giving up");
+                return None;
+            }
+        };
+        match established {
+            Ok(conn) => return Some(conn),
+            Err(e) => warn!("reconnect failed: {e:#}"),
+        }
+        attempt += 1;
+    }
+}
+
+async fn run_connection(
+    config: &Config,
+    conn: Connection,
+    pings: &WeakSender<String>,
+    outgoing: &mut Receiver<String>,
+    incoming: &Sender<Incoming>,
+    shared: &Shared,
+) -> Exit {
+    let Connection {
+        reader,
+        writer,
+        caps,
+        nick,
+        backlog,
+    } = conn;
+
+    // Everything we knew about the last server may have changed. The casemapping is
+    // kept until the new server's 005 arrives, so that names folded in the meantime
+    // still match the ones stored under the old mapping.
+    *shared.caps.write().unwrap() = caps;
+    *shared.nick.write().unwrap() = nick;
+    {
+        let mut info = shared.server_info.write().unwrap();
+        let casemapping = info.casemapping;
+        *info = ServerInfo::default();
+        info.casemapping = casemapping;
+    }
+    *shared.source.write().unwrap() = None;
+    *shared.lag.write().unwrap() = None;
+    shared.online.store(true, Ordering::Relaxed);
+
+    if incoming.send(Incoming::Connected).await.is_err() {
+        return Exit::ClientGone;
+    }
+
+    let rejoin = {
+        let mapping = shared.server_info.read().unwrap().casemapping;
+        let keys = shared.keys.read().unwrap();
+        shared
+            .joined
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|channel| {
+                let key = keys
+                    .iter()
+                    .find(|(c, _)| mapping.eq(c, channel))
+                    .map(|(_, key)| key.as_str());
+                join_command(channel, key).encode().ok()
+            })
+            .collect()
+    };
+
+    let read = async {
+        // Lines seen during registration are replayed first so handlers still see them.
+        for line in backlog {
+            if incoming.send(Incoming::Line(line)).await.is_err() {
+                return Exit::ClientGone;
+            }
+        }
+
+        let mut reader = reader;
+        // Kept across iterations: a read interrupted by the keepalive timer leaves its
+        // partial line here and picks up where it left off.
+        let mut lines = LineReader::new();
+        let mut pinger = Pinger::new(config.keepalive, Instant::now());
+        loop {
+            let deadline = pinger.deadline();
+            let idle = async move {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let linelen = shared.server_info.read().unwrap().linelen;
+            tokio::select! {
+                read = lines.read(&mut reader, LineReader::max_len(linelen)) => match read {
+                    Ok(Read::Closed) => {
+                        warn!("server closed the connection");
+                        return Exit::Disconnected;
+                    }
+                    Ok(Read::TooLong(len)) => {
+                        warn!("dropped a {} byte line, longer than the server may send", len);
+                    }
+                    Ok(Read::Line(buf)) => {
+                        let line = config.decoder.decode(buf).into_owned();
+                        info!("<== {}", line);
+                        if let Some(lag) = pinger.on_line(&line, Instant::now()) {
+                            debug!("lag is {:?}", lag);
+                            *shared.lag.write().unwrap() = Some(lag);
+                        }
+                        // Answers to queries go to whoever asked, not to handlers.
+                        let mapping = shared.server_info.read().unwrap().casemapping;
+                        if shared.queries.lock().unwrap().on_line(&line, mapping) {
+                            continue;
+                        }
+                        if incoming.send(Incoming::Line(line)).await.is_err() {
+                            return Exit::ClientGone;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("read error: {e}");
+                        return Exit::Disconnected;
+                    }
+                },
+                _ = idle => match pinger.on_deadline(Instant::now()) {
+                    Tick::Ping(line) => {
+                        let Some(tx) = pings.upgrade() else {
+                            return Exit::ClientGone;
+                        };
+                        shared.queued.fetch_add(1, Ordering::Relaxed);
+                        if tx.send(line).await.is_err() {
+                            shared.queued.fetch_sub(1, Ordering::Relaxed);
+                            return Exit::ClientGone;
+                        }
+                    }
+                    Tick::Dead => {
+                        warn!("no reply to keepalive ping, assuming the connection is dead");
+                        return Exit::Disconnected;
+                    }
+                },
+            }
+        }
+    };
+
+    let write = async {
+        match flood::run_writer(writer, outgoing, config.flood, &shared.queued, rejoin).await {
+            WriterExit::Closed => Exit::ClientGone,
+            WriterExit::Failed => Exit::Disconnected,
+        }
+    };
+
+    let exit = tokio::select! {
+        exit = read => exit,
+        exit = write => exit,
+    };
+    shared.online.store(false, Ordering::Relaxed);
+    // Nothing sent on this connection will be answered now.
+    shared.queries.lock().unwrap().clear();
+    exit
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::casemap::CaseMapping;
+    use crate::client::{ClientBuilder, Event};
+    use crate::flood::FloodControl;
+    use crate::irc_msg::Command;
+    use crate::keepalive::Keepalive;
+    use crate::numeric::Numeric;
+    use crate::testing::{accept_and_register, wait_for};
+
+    #[tokio::test]
+    async fn reconnects_and_rejoins() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut lines, mut write) = accept_and_register(&listener).await;
+            wait_for(&mut lines, "JOIN #rust").await;
+            write
+                .write_all(b":botty!b@host JOIN #rust\r\n")
+                .await
+                .unwrap();
+            wait_for(&mut lines, "PRIVMSG #rust :joined").await;
+            drop((lines, write));
+
+            let (mut lines, _write) = accept_and_register(&listener).await;
+            wait_for(&mut lines, "JOIN #rust").await;
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_flood_control(FloodControl::unlimited())
+            .with_reconnect(ReconnectPolicy::new().with_initial_delay(Duration::from_millis(10)))
+            .connect()
+            .await
+            .unwrap();
+
+        let mut events = vec![];
+        while let Some(event) = client.next_event().await.unwrap() {
+            match event {
+                Event::Message(msg) => {
+                    if matches!(msg.command, Command::Join { .. }) {
+                        client.privmsg("#rust", "joined").await.unwrap();
+                    }
+                    if msg.reply().is_some() && events.len() == 1 {
+                        client.join("#rust").await.unwrap();
+                        events.push("welcomed");
+                    }
+                }
+                Event::Connected => events.push("connected"),
+                Event::Disconnected => events.push("disconnected"),
+                Event::User(_) | Event::Batch(_) => {}
+            }
+            if events.len() == 4 {
+                break;
+            }
+        }
+        assert_eq!(
+            events,
+            ["connected", "welcomed", "disconnected", "connected"]
+        );
+        assert_eq!(client.channels(), ["#rust"]);
+
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("client never rejoined")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejoins_with_the_key_and_gives_up_when_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut lines, mut write) = accept_and_register(&listener).await;
+            wait_for(&mut lines, "JOIN #secret hunter2").await;
+            write
+                .write_all(b":botty!b@host JOIN #secret\r\n")
+                .await
+                .unwrap();
+            wait_for(&mut lines, "PRIVMSG #secret :joined").await;
+            drop((lines, write));
+
+            let (mut lines, mut write) = accept_and_register(&listener).await;
+            wait_for(&mut lines, "JOIN #secret hunter2").await;
+            write
+                .write_all(b":srv 475 botty #secret :Cannot join channel (+k)\r\n")
+                .await
+                .unwrap();
+            (lines, write)
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_flood_control(FloodControl::unlimited())
+            .with_reconnect(ReconnectPolicy::new().with_initial_delay(Duration::from_millis(10)))
+            .connect()
+            .await
+            .unwrap();
+        client.join_with_key("#secret", "hunter2").await.unwrap();
+
+        while let Some(event) = client.next_event().await.unwrap() {
+            let Event::Message(msg) = event else {
+                continue;
+            };
+            match msg.command {
+                Command::Join { .. } => {
+                    assert_eq!(client.channels(), ["#secret"]);
+                    client.privmsg("#secret", "joined").await.unwrap();
+                }
+                Command::Numeric {
+                    code: Numeric::ErrBadChannelKey,
+                    ..
+                } => break,
+                _ => {}
+            }
+        }
+        assert!(client.channels().is_empty());
+
+        let (_lines, _write) = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("client never rejoined with the key")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn silent_server_during_registration_is_given_up_on() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            drop(accept_and_register(&listener).await);
+            // Accept, then never answer.
+            let (_silent, _) = listener.accept().await.unwrap();
+            accept_and_register(&listener).await
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_reconnect(ReconnectPolicy::new().with_initial_delay(Duration::from_millis(10)))
+            .with_registration_timeout(Duration::from_millis(200))
+            .connect()
+            .await
+            .unwrap();
+
+        let reconnected = async {
+            let mut connections = 0;
+            while let Some(event) = client.next_event().await.unwrap() {
+                if matches!(event, Event::Connected) {
+                    connections += 1;
+                    if connections == 2 {
+                        break;
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), reconnected)
+            .await
+            .expect("client never reconnected");
+        let (_lines, _write) = server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lines_queued_when_the_connection_drops_are_discarded() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut lines, write) = accept_and_register(&listener).await;
+            wait_for(&mut lines, "PRIVMSG #rust :0").await;
+            drop((lines, write));
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_flood_control(
+                FloodControl::new()
+                    .with_burst(1)
+                    .with_refill_interval(Duration::from_secs(60)),
+            )
+            .with_reconnect(ReconnectPolicy::disabled())
+            .connect()
+            .await
+            .unwrap();
+
+        for i in 0..5 {
+            client.privmsg("#rust", &i.to_string()).await.unwrap();
+        }
+        assert!(client.queue_depth() > 0);
+
+        while client.next_event().await.unwrap().is_some() {}
+        assert_eq!(client.queue_depth(), 0);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn casemapping_survives_until_the_next_005() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (lines, mut write) = accept_and_register(&listener).await;
+            write
+                .write_all(b":srv 005 botty CASEMAPPING=ascii :are supported\r\n")
+                .await
+                .unwrap();
+            drop((lines, write));
+            accept_and_register(&listener).await
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_reconnect(ReconnectPolicy::new().with_initial_delay(Duration::from_millis(10)))
+            .connect()
+            .await
+            .unwrap();
+
+        let mut connections = 0;
+        while let Some(event) = client.next_event().await.unwrap() {
+            if matches!(event, Event::Connected) {
+                connections += 1;
+                if connections == 2 {
+                    break;
+                }
+            }
+        }
+        assert_eq!(client.casemapping(), CaseMapping::Ascii);
+        let (_lines, _write) = server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn silent_server_is_pinged_then_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut lines, _write) = accept_and_register(&listener).await;
+            let line = loop {
+                let line = lines.next_line().await.unwrap().unwrap();
+                if line.starts_with("PING") {
+                    break line;
+                }
+            };
+            // Hold the socket open without answering, like a half-open connection.
+            while lines.next_line().await.unwrap().is_some() {}
+            line
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_reconnect(ReconnectPolicy::disabled())
+            .with_keepalive(
+                Keepalive::new()
+                    .with_interval(Duration::from_millis(50))
+                    .with_timeout(Duration::from_millis(50)),
+            )
+            .connect()
+            .await
+            .unwrap();
+
+        let mut disconnected = false;
+        while let Some(event) = client.next_event().await.unwrap() {
+            disconnected |= matches!(event, Event::Disconnected);
+        }
+        assert!(disconnected);
+        assert_eq!(client.lag(), None);
+        drop(client);
+
+        let ping = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(ping.starts_with("PING :keepalive-"), "{ping}");
+    }
+
+    #[tokio::test]
+    async fn survives_legacy_encodings_and_garbage() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (lines, mut write) = accept_and_register(&listener).await;
+            write
+                .write_all(b":a!b@c PRIVMSG #rust :caf\xe9\r\n:srv\r\n")
+                .await
+                .unwrap();
+            write
+                .write_all(b":a!b@c PRIVMSG #rust :still here\r\n")
+                .await
+                .unwrap();
+            (lines, write)
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_reconnect(ReconnectPolicy::disabled())
+            .connect()
+            .await
+            .unwrap();
+
+        let mut said = vec![];
+        while said.len() < 2 {
+            let msg = client.recv().await.unwrap().expect("connection closed");
+            if let Command::Privmsg { message, .. } = msg.command {
+                said.push(message);
+            }
+        }
+        assert_eq!(said, ["café", "still here"]);
+        drop(server);
+    }
+
+    #[tokio::test]
+    async fn endless_line_is_dropped_without_buffering_it() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (lines, mut write) = accept_and_register(&listener).await;
+            write.write_all(b":a!b@c PRIVMSG #rust :").await.unwrap();
+            write.write_all(&[b'x'; 1 << 20]).await.unwrap();
+            write
+                .write_all(b"\r\n:a!b@c PRIVMSG #rust :still here\r\n")
+                .await
+                .unwrap();
+            (lines, write)
+        });
+
+        let client = ClientBuilder::new(addr, "botty", "bot")
+            .with_reconnect(ReconnectPolicy::disabled())
+            .connect()
+            .await
+            .unwrap();
+
+        loop {
+            let msg = client.recv().await.unwrap().expect("connection closed");
+            if let Command::Privmsg { message, .. } = msg.command {
+                assert_eq!(message, "still here");
+                break;
+            }
+        }
+        drop(server);
+    }
+
+    #[test]
+    fn delay_backs_off_exponentially_with_jitter() {
+        let policy = ReconnectPolicy::new()
+            .with_initial_delay(Duration::from_secs(1))
+            .with_max_delay(Duration::from_secs(60));
+
+        assert_eq!(policy.delay(0, 1.0), Duration::from_secs(1));
+        assert_eq!(policy.delay(0, 0.0), Duration::from_millis(500));
+        assert_eq!(policy.delay(3, 1.0), Duration::from_secs(8));
+        assert_eq!(policy.delay(10, 1.0), Duration::from_secs(60));
+        assert_eq!(policy.delay(100, 0.0), Duration::from_secs(30));
+    }
+}
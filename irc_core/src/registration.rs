@@ -0,0 +1,517 @@
+//! Connection registration: everything that happens between opening the socket and the
+//! server's RPL_WELCOME (001).
+
+use std::borrow::Cow;
+
+use anyhow::bail;
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::cap::{self, CapReply, Capabilities, Negotiation};
+use crate::client::Config;
+use crate::decode::{LineReader, Read};
+use crate::irc_msg::{Command, Msg};
+use crate::nick::{self, Fallbacks};
+use crate::numeric::{Numeric, Reply};
+use crate::sasl::{SaslError, SaslSession};
+
+/// Writes a single protocol line, appending CRLF.
+pub(crate) async fn write_line<W>(writer: &mut W, line: &str) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    info!("==> {}", redact(line));
+    Ok(())
+}
+
+/// `line` as it may appear in the log. SASL payloads are masked: for PLAIN, the
+/// payload is just the password in base64. So are the arguments of NickServ
+/// commands that carry the account password.
+pub(crate) fn redact(line: &str) -> Cow<'_, str> {
+    match line.split_once(' ') {
+        Some((verb, _)) if verb.eq_ignore_ascii_case("AUTHENTICATE") => {
+            Cow::Owned(format!("{} ***", verb))
+        }
+        Some((verb, rest)) if verb.eq_ignore_ascii_case("PRIVMSG") => {
+            let Some((target, text)) = rest.split_once(" :") else {
+                return Cow::Borrowed(line);
+            };
+            match text.split_once(' ') {
+                Some((command, _))
+                    if target.eq_ignore_ascii_case("NickServ")
+                        && ["REGAIN", "IDENTIFY", "GHOST"]
+                            .iter()
+                            .any(|c| command.eq_ignore_ascii_case(c)) =>
+                {
+                    Cow::Owned(format!("{} {} :{} ***", verb, target, command))
+                }
+                _ => Cow::Borrowed(line),
+            }
+        }
+        _ => Cow::Borrowed(line),
+    }
+}
+
+/// The outcome of a successful registration.
+#[derive(Debug)]
+pub(crate) struct Registered {
+    /// The nick the server registered us under, which may be a fallback.
+    pub nick: String,
+    /// Every line received up to and including RPL_WELCOME, except PINGs (which are
+    /// answered during registration), so that handlers still get to see them.
+    pub backlog: Vec<String>,
+}
+
+/// Registers with the server, negotiating capabilities and authenticating along the way,
+/// and falling back to other nicks if ours is rejected.
+pub(crate) async fn register<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    config: &Config,
+    caps: &mut Capabilities,
+) -> anyhow::Result<Registered>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut negotiation = Negotiation::new(&config.caps);
+    let mut cap_ended = false;
+    let mut sasl = config.sasl.clone().map(SaslSession::new);
+    let mut sasl_started = false;
+    let mut sasl_done = sasl.is_none();
+    let mut fallbacks = Fallbacks::new(&config.nick, &config.alt_nicks);
+    let mut nick = config.nick.to_string();
+
+    write_line(writer, &format!("CAP LS {}", cap::CAP_VERSION)).await?;
+    write_line(writer, &format!("NICK {}", config.nick)).await?;
+    write_line(
+        writer,
+        &format!("USER {} 0 * :{}", config.nick, config.user),
+    )
+    .await?;
+
+    let mut backlog = vec![];
+    let mut lines = LineReader::new();
+    loop {
+        let buf = match lines.read(reader, LineReader::max_len(512)).await? {
+            Read::Line(buf) => buf,
+            Read::TooLong(len) => {
+                warn!(
+                    "dropped a {} byte line, longer than any server may send",
+                    len
+                );
+                continue;
+            }
+            Read::Closed => bail!("connection closed during registration"),
+        };
+        let line = config.decoder.decode(buf);
+        let line = line.as_ref();
+        info!("<== {}", line);
+
+        let Some(msg) = Msg::parse(line, chrono::Local::now()) else {
+            continue;
+        };
+
+        match &msg.command {
+            Command::Ping { token } => {
+                let pong = match token {
+                    Some(t) => format!("PONG :{}", t),
+                    None => "PONG".to_string(),
+                };
+                write_line(writer, &pong).await?;
+                continue;
+            }
+            Command::Error { message } => {
+                bail!("server closed the connection: {}", message);
+            }
+            // ERR_UNKNOWNCOMMAND for CAP: the server predates capability negotiation.
+            Command::Numeric {
+                code: Numeric::ErrUnknownCommand,
+                args,
+                ..
+            } if args.get(1).is_some_and(|c| c.eq_ignore_ascii_case("CAP")) => {
+                negotiation.abandon();
+                cap_ended = true;
+            }
+            Command::Authenticate { data } => {
+                if let Some(session) = sasl.as_mut() {
+                    for out in session.on_authenticate(data).await? {
+                        write_line(writer, &out).await?;
+                    }
+                }
+                continue;
+            }
+            Command::Numeric { code, .. } if (900..=908).contains(&code.code()) => {
+                if let (Some(session), Some(reply)) =
+                    (sasl.as_mut(), Reply::from_command(&msg.command))
+                {
+                    sasl_done |= on_sasl_numeric(session, &reply)?;
+                }
+            }
+            Command::Numeric { code, .. } if nick::is_rejection(*code) => {
+                let Some(next) = fallbacks.next() else {
+                    bail!("no usable nickname: the server rejected {}", nick);
+                };
+                warn!("nickname {} rejected, trying {}", nick, next);
+                write_line(writer, &format!("NICK {}", next)).await?;
+                nick = next;
+            }
+            _ => {
+                if let Some(reply) = CapReply::from_msg(&msg) {
+                    for out in negotiation.on_reply(caps, &reply) {
+                        write_line(writer, &out).await?;
+                    }
+                }
+            }
+        }
+
+        if !sasl_started
+            && let Some(session) = sasl.as_ref()
+            && negotiation.is_settled()
+        {
+            check_sasl_offered(session, caps)?;
+            write_line(writer, &session.start()).await?;
+            sasl_started = true;
+        }
+
+        if !cap_ended && sasl_done && negotiation.is_settled() {
+            write_line(writer, "CAP END").await?;
+            cap_ended = true;
+        }
+
+        backlog.push(line.to_owned());
+        if let Some(reply) = msg.reply()
+            && reply.numeric == Numeric::RplWelcome
+        {
+            // The server has the final say on our nick, e.g. if it truncated it.
+            if let Some(target) = reply.target() {
+                nick = target.to_owned();
+            }
+            return Ok(Registered { nick, backlog });
+        }
+    }
+}
+
+/// Fails early if the server cannot authenticate us with the configured mechanism.
+fn check_sasl_offered(session: &SaslSession, caps: &Capabilities) -> Result<(), SaslError> {
+    if !caps.is_enabled("sasl") {
+        return Err(SaslError::NotSupported);
+    }
+
+    // CAP 302 servers list their mechanisms as the capability value.
+    let offered = caps.value("sasl").unwrap_or_default();
+    if !offered.is_empty()
+        && !offered
+            .split(',')
+            .any(|m| m.eq_ignore_ascii_case(session.mechanism().name()))
+    {
+        return Err(SaslError::MechanismUnsupported {
+            available: offered.split(',').map(str::to_owned).collect(),
+        });
+    }
+    Ok(())
+}
+
+/// Handles the SASL numerics (900-908). Returns true once authentication succeeded.
+fn on_sasl_numeric(session: &mut SaslSession, reply: &Reply<'_>) -> Result<bool, SaslError> {
+    match reply.numeric {
+        Numeric::RplLoggedIn => {
+            info!("logged in as {}", reply.param(2).unwrap_or("?"));
+            Ok(false)
+        }
+        Numeric::RplSaslSuccess => Ok(true),
+        Numeric::ErrNickLocked | Numeric::ErrSaslFail => match session.server_mechanisms.take() {
+            Some(available) => Err(SaslError::MechanismUnsupported { available }),
+            None => Err(SaslError::Failed(
+                reply.text().unwrap_or_default().to_owned(),
+            )),
+        },
+        Numeric::ErrSaslTooLong => Err(SaslError::TooLong),
+        Numeric::ErrSaslAborted => Err(SaslError::Aborted),
+        Numeric::RplSaslMechs => {
+            session.server_mechanisms = Some(reply.sasl_mechanisms().map(str::to_owned).collect());
+            Ok(false)
+        }
+        // ERR_SASLALREADY and anything else in range is informational.
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncWriteExt, BufReader, duplex};
+
+    use super::*;
+    use crate::ctcp::CtcpReplies;
+    use crate::decode::Decoder;
+    use crate::flood::FloodControl;
+    use crate::keepalive::Keepalive;
+    use crate::nick::NickRegain;
+    use crate::reconnect::ReconnectPolicy;
+    use crate::sasl::{SaslCredentials, SaslMechanism};
+    use crate::testing::capture_logs;
+
+    fn config(caps: &[&str]) -> Config {
+        Config {
+            server: "irc.example.com:6667".into(),
+            nick: "botty".into(),
+            user: "botty".into(),
+            caps: caps.iter().map(|c| c.to_string()).collect(),
+            sasl: None,
+            tls: None,
+            split_marker: None,
+            flood: FloodControl::default(),
+            reconnect: ReconnectPolicy::default(),
+            keepalive: Keepalive::default(),
+            alt_nicks: vec![],
+            regain: NickRegain::default(),
+            decoder: Decoder::default(),
+            ctcp: CtcpReplies::default(),
+            query_timeout: Duration::from_secs(30),
+            registration_timeout: Duration::from_secs(60),
+        }
+    }
+
+    fn sasl_config() -> Config {
+        Config {
+            sasl: Some(SaslCredentials {
+                mechanism: SaslMechanism::Plain,
+                account: "botty".into(),
+                password: "hunter2".into(),
+            }),
+            ..config(&["sasl"])
+        }
+    }
+
+    /// Runs registration against a scripted server and returns what the client sent.
+    async fn run_script(config: &Config, server_lines: &[&str]) -> (Vec<String>, Capabilities) {
+        let (sent, caps, result) = try_script(config, server_lines).await;
+        result.unwrap();
+        (sent, caps)
+    }
+
+    async fn try_script(
+        config: &Config,
+        server_lines: &[&str],
+    ) -> (Vec<String>, Capabilities, anyhow::Result<Registered>) {
+        let (client_side, mut server_side) = duplex(4096);
+        let (client_read, mut client_write) = tokio::io::split(client_side);
+
+        for l in server_lines {
+            server_side
+                .write_all(format!("{}\r\n", l).as_bytes())
+                .await
+                .unwrap();
+        }
+
+        let mut caps = Capabilities::default();
+        let mut reader = BufReader::new(client_read);
+        let result = register(&mut reader, &mut client_write, config, &mut caps).await;
+        drop((reader, client_write));
+
+        let mut sent = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut server_side, &mut sent)
+            .await
+            .unwrap();
+        (sent.lines().map(str::to_owned).collect(), caps, result)
+    }
+
+    #[tokio::test]
+    async fn register_negotiates_caps() {
+        let (sent, caps) = run_script(
+            &config(&["server-time", "away-notify"]),
+            &[
+                ":irc.example.com CAP * LS * :multi-prefix server-time",
+                ":irc.example.com CAP * LS :away-notify",
+                ":irc.example.com CAP * ACK :server-time away-notify",
+                ":irc.example.com 001 botty :Welcome",
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            sent,
+            vec![
+                "CAP LS 302",
+                "NICK botty",
+                "USER botty 0 * :botty",
+                "CAP REQ :server-time away-notify",
+                "CAP END",
+            ]
+        );
+        assert!(caps.is_enabled("server-time"));
+        assert!(caps.is_enabled("away-notify"));
+        assert!(!caps.is_enabled("multi-prefix"));
+    }
+
+    #[tokio::test]
+    async fn register_without_cap_support() {
+        let (sent, caps) = run_script(
+            &config(&["server-time"]),
+            &[
+                "PING :abc",
+                ":irc.example.com 421 botty CAP :Unknown command",
+                ":irc.example.com 001 botty :Welcome",
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            sent,
+            vec![
+                "CAP LS 302",
+                "NICK botty",
+                "USER botty 0 * :botty",
+                "PONG :abc",
+            ]
+        );
+        assert_eq!(caps.enabled().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn register_sasl_plain() {
+        let (sent, _) = run_script(
+            &sasl_config(),
+            &[
+                ":irc.example.com CAP * LS :sasl=PLAIN,EXTERNAL",
+                ":irc.example.com CAP * ACK :sasl",
+                "AUTHENTICATE +",
+                ":irc.example.com 900 botty botty!botty@host botty :You are now logged in as botty",
+                ":irc.example.com 903 botty :SASL authentication successful",
+                ":irc.example.com 001 botty :Welcome",
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            sent,
+            vec![
+                "CAP LS 302",
+                "NICK botty",
+                "USER botty 0 * :botty",
+                "CAP REQ :sasl",
+                "AUTHENTICATE PLAIN",
+                "AUTHENTICATE Ym90dHkAYm90dHkAaHVudGVyMg==",
+                "CAP END",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sasl_credentials_stay_out_of_the_log() {
+        let (logs, _guard) = capture_logs();
+        run_script(
+            &sasl_config(),
+            &[
+                ":irc.example.com CAP * LS :sasl=PLAIN",
+                ":irc.example.com CAP * ACK :sasl",
+                "AUTHENTICATE +",
+                ":irc.example.com 903 botty :SASL authentication successful",
+                ":irc.example.com 001 botty :Welcome",
+            ],
+        )
+        .await;
+
+        let logs = logs.contents();
+        assert!(logs.contains("==> AUTHENTICATE ***"), "{logs}");
+        assert!(!logs.contains("Ym90dHkAYm90dHkAaHVudGVyMg=="), "{logs}");
+        assert!(!logs.contains("hunter2"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn register_sasl_failure_is_typed() {
+        let (_, _, result) = try_script(
+            &sasl_config(),
+            &[
+                ":irc.example.com CAP * LS :sasl",
+                ":irc.example.com CAP * ACK :sasl",
+                "AUTHENTICATE +",
+                ":irc.example.com 904 botty :SASL authentication failed",
+            ],
+        )
+        .await;
+
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SaslError>(),
+            Some(&SaslError::Failed("SASL authentication failed".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn register_sasl_wrong_mechanism() {
+        let (_, _, result) = try_script(
+            &sasl_config(),
+            &[
+                ":irc.example.com CAP * LS :sasl=EXTERNAL",
+                ":irc.example.com CAP * ACK :sasl",
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            result.unwrap_err().downcast_ref::<SaslError>(),
+            Some(&SaslError::MechanismUnsupported {
+                available: vec!["EXTERNAL".into()]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn register_sasl_not_offered() {
+        let (_, _, result) =
+            try_script(&sasl_config(), &[":irc.example.com CAP * LS :multi-prefix"]).await;
+
+        assert_eq!(
+            result.unwrap_err().downcast_ref::<SaslError>(),
+            Some(&SaslError::NotSupported)
+        );
+    }
+
+    #[tokio::test]
+    async fn register_falls_back_when_nick_is_taken() {
+        let config = Config {
+            alt_nicks: vec!["botty2".into()],
+            ..config(&[])
+        };
+        let (sent, _, result) = try_script(
+            &config,
+            &[
+                ":irc.example.com CAP * LS :",
+                ":irc.example.com 433 * botty :Nickname is already in use",
+                ":irc.example.com 437 * botty2 :Nick/channel is temporarily unavailable",
+                ":irc.example.com 001 botty_ :Welcome",
+            ],
+        )
+        .await;
+
+        assert_eq!(
+            sent,
+            vec![
+                "CAP LS 302",
+                "NICK botty",
+                "USER botty 0 * :botty",
+                "CAP END",
+                "NICK botty2",
+                "NICK botty_",
+            ]
+        );
+        let registered = result.unwrap();
+        assert_eq!(registered.nick, "botty_");
+        assert_eq!(registered.backlog.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn register_gives_up_when_out_of_nicks() {
+        let mut lines = vec![":irc.example.com 421 * CAP :Unknown command".to_owned()];
+        lines.extend((0..10).map(|_| ":irc.example.com 432 * x :Erroneous nickname".to_owned()));
+        let lines: Vec<&str> = lines.iter().map(String::as_str).collect();
+        let (_, _, result) = try_script(&config(&[]), &lines).await;
+
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("no usable nickname"), "{err}");
+    }
+}
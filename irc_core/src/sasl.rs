@@ -0,0 +1,498 @@
+//! SASL authentication over IRCv3 `AUTHENTICATE`.
+//!
+//! See <https://ircv3.net/specs/extensions/sasl-3.1>. Supports `PLAIN` and
+//! `SCRAM-SHA-256` (RFC 7677).
+
+use std::borrow::Cow;
+use std::fmt;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Maximum size of a single `AUTHENTICATE` payload chunk.
+const CHUNK_LEN: usize = 400;
+
+/// The most SCRAM iterations we'll compute. Servers typically ask for 4096; the count
+/// is theirs to choose, so without a limit one could keep us busy for hours.
+const MAX_SCRAM_ITERATIONS: u32 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaslMechanism {
+    Plain,
+    ScramSha256,
+}
+
+impl SaslMechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+        }
+    }
+}
+
+impl std::str::FromStr for SaslMechanism {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "PLAIN" => Ok(SaslMechanism::Plain),
+            "SCRAM-SHA-256" => Ok(SaslMechanism::ScramSha256),
+            other => Err(format!("unsupported SASL mechanism: {}", other)),
+        }
+    }
+}
+
+/// Account credentials used to authenticate during registration.
+#[derive(Clone)]
+pub struct SaslCredentials {
+    pub mechanism: SaslMechanism,
+    pub account: Cow<'static, str>,
+    pub password: Cow<'static, str>,
+}
+
+impl fmt::Debug for SaslCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SaslCredentials")
+            .field("mechanism", &self.mechanism)
+            .field("account", &self.account)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Why SASL authentication did not succeed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SaslError {
+    /// The server does not offer the `sasl` capability.
+    NotSupported,
+    /// The server does not accept our mechanism. `available` lists the ones it does.
+    MechanismUnsupported { available: Vec<String> },
+    /// ERR_SASLFAIL (904) or ERR_NICKLOCKED (902): usually bad credentials.
+    Failed(String),
+    /// ERR_SASLTOOLONG (905).
+    TooLong,
+    /// ERR_SASLABORTED (906).
+    Aborted,
+    /// The server sent a challenge we could not make sense of.
+    Malformed(String),
+    /// The SCRAM server signature did not match; the server may not know our password.
+    BadServerSignature,
+    /// The SCRAM server asked for more iterations than we are willing to compute.
+    TooManyIterations(u32),
+}
+
+impl fmt::Display for SaslError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaslError::NotSupported => write!(f, "server does not support SASL"),
+            SaslError::MechanismUnsupported { available } => write!(
+                f,
+                "SASL mechanism not supported by server (available: {})",
+                available.join(",")
+            ),
+            SaslError::Failed(reason) => write!(f, "SASL authentication failed: {}", reason),
+            SaslError::TooLong => write!(f, "SASL message too long"),
+            SaslError::Aborted => write!(f, "SASL authentication aborted"),
+            SaslError::Malformed(what) => write!(f, "malformed SASL challenge: {}", what),
+            SaslError::BadServerSignature => write!(f, "SCRAM server signature mismatch"),
+            SaslError::TooManyIterations(n) => write!(
+                f,
+                "SCRAM iteration count {} exceeds the limit of {}",
+                n, MAX_SCRAM_ITERATIONS
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SaslError {}
+
+#[derive(Debug)]
+enum Step {
+    Start,
+    ScramServerFirst {
+        client_nonce: String,
+        client_first_bare: String,
+    },
+    ScramServerFinal {
+        server_signature: Vec<u8>,
+    },
+    Finished,
+}
+
+/// A single SASL exchange, fed with the server's `AUTHENTICATE` lines.
+#[derive(Debug)]
+pub(crate) struct SaslSession {
+    creds: SaslCredentials,
+    step: Step,
+    /// Base64 chunks received so far for a challenge split across several lines.
+    incoming: String,
+    /// Mechanisms listed by RPL_SASLMECHS (908), if the server sent it.
+    pub server_mechanisms: Option<Vec<String>>,
+    client_nonce: Option<String>,
+}
+
+impl SaslSession {
+    pub fn new(creds: SaslCredentials) -> Self {
+        Self {
+            creds,
+            step: Step::Start,
+            incoming: String::new(),
+            server_mechanisms: None,
+            client_nonce: None,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_nonce(mut self, nonce: &str) -> Self {
+        self.client_nonce = Some(nonce.to_owned());
+        self
+    }
+
+    pub fn mechanism(&self) -> SaslMechanism {
+        self.creds.mechanism
+    }
+
+    /// The line that starts the exchange.
+    pub fn start(&self) -> String {
+        format!("AUTHENTICATE {}", self.creds.mechanism.name())
+    }
+
+    /// Handles one `AUTHENTICATE <data>` line from the server and returns the lines to
+    /// send back, if any. Returns nothing while a chunked challenge is still arriving.
+    pub async fn on_authenticate(&mut self, data: &str) -> Result<Vec<String>, SaslError> {
+        if data != "+" {
+            self.incoming.push_str(data);
+            if data.len() == CHUNK_LEN {
+                return Ok(vec![]);
+            }
+        }
+        let challenge = if self.incoming.is_empty() {
+            vec![]
+        } else {
+            BASE64
+                .decode(std::mem::take(&mut self.incoming))
+                .map_err(|e| SaslError::Malformed(e.to_string()))?
+        };
+
+        let response = self.respond(&challenge).await?;
+        Ok(encode_authenticate(&response))
+    }
+
+    async fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>, SaslError> {
+        match (self.creds.mechanism, &self.step) {
+            (SaslMechanism::Plain, Step::Start) => {
+                self.step = Step::Finished;
+                let account = self.creds.account.as_bytes();
+                let mut out = Vec::with_capacity(account.len() * 2 + self.creds.password.len() + 2);
+                out.extend_from_slice(account);
+                out.push(0);
+                out.extend_from_slice(account);
+                out.push(0);
+                out.extend_from_slice(self.creds.password.as_bytes());
+                Ok(out)
+            }
+            (SaslMechanism::ScramSha256, Step::Start) => {
+                let client_nonce = self.client_nonce.take().unwrap_or_else(generate_nonce);
+                let client_first_bare =
+                    format!("n={},r={}", scram_escape(&self.creds.account), client_nonce);
+                let out = format!("n,,{}", client_first_bare).into_bytes();
+                self.step = Step::ScramServerFirst {
+                    client_nonce,
+                    client_first_bare,
+                };
+                Ok(out)
+            }
+            (
+                SaslMechanism::ScramSha256,
+                Step::ScramServerFirst {
+                    client_nonce,
+                    client_first_bare,
+                },
+            ) => {
+                let server_first = std::str::from_utf8(challenge)
+                    .map_err(|_| SaslError::Malformed("server-first is not UTF-8".into()))?;
+                let (client_final, server_signature) = scram_client_final(
+                    &self.creds.password,
+                    client_nonce,
+                    client_first_bare,
+                    server_first,
+                )
+                .await?;
+                self.step = Step::ScramServerFinal { server_signature };
+                Ok(client_final.into_bytes())
+            }
+            (SaslMechanism::ScramSha256, Step::ScramServerFinal { server_signature }) => {
+                let server_final = std::str::from_utf8(challenge)
+                    .map_err(|_| SaslError::Malformed("server-final is not UTF-8".into()))?;
+                if let Some(err) = scram_attr(server_final, 'e') {
+                    return Err(SaslError::Failed(err.to_owned()));
+                }
+                let verifier = scram_attr(server_final, 'v')
+                    .and_then(|v| BASE64.decode(v).ok())
+                    .ok_or_else(|| SaslError::Malformed(server_final.to_owned()))?;
+                if &verifier != server_signature {
+                    return Err(SaslError::BadServerSignature);
+                }
+                self.step = Step::Finished;
+                Ok(vec![])
+            }
+            (_, _) => Err(SaslError::Malformed(
+                "unexpected challenge after exchange finished".into(),
+            )),
+        }
+    }
+}
+
+/// Encodes a payload as one or more `AUTHENTICATE` lines, splitting it into 400-byte
+/// chunks. An empty payload, or one whose last chunk is exactly 400 bytes, ends with
+/// `AUTHENTICATE +`.
+pub(crate) fn encode_authenticate(payload: &[u8]) -> Vec<String> {
+    let encoded = BASE64.encode(payload);
+    let mut lines: Vec<String> = encoded
+        .as_bytes()
+        .chunks(CHUNK_LEN)
+        // base64 output is ASCII, so every chunk is valid UTF-8.
+        .map(|c| format!("AUTHENTICATE {}", std::str::from_utf8(c).unwrap()))
+        .collect();
+    if encoded.len().is_multiple_of(CHUNK_LEN) {
+        lines.push("AUTHENTICATE +".to_string());
+    }
+    lines
+}
+
+fn generate_nonce() -> String {
+    use rand::Rng;
+
+    let bytes: [u8; 18] = rand::rng().random();
+    BASE64.encode(bytes)
+}
+
+/// Escapes a SCRAM username: `=` and `,` are not allowed verbatim.
+fn scram_escape(s: &str) -> String {
+    s.replace('=', "=3D").replace(',', "=2C")
+}
+
+/// Finds attribute `key` in a comma-separated SCRAM message like `r=...,s=...,i=...`.
+fn scram_attr(msg: &str, key: char) -> Option<&str> {
+    msg.split(',').find_map(|attr| {
+        let mut chars = attr.chars();
+        (chars.next() == Some(key) && chars.next() == Some('=')).then(|| &attr[2..])
+    })
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Computes the SCRAM client-final message and the server signature we expect back.
+/// The key derivation is deliberately slow, so it runs off the async workers.
+async fn scram_client_final(
+    password: &str,
+    client_nonce: &str,
+    client_first_bare: &str,
+    server_first: &str,
+) -> Result<(String, Vec<u8>), SaslError> {
+    let malformed = || SaslError::Malformed(server_first.to_owned());
+
+    let nonce = scram_attr(server_first, 'r').ok_or_else(malformed)?;
+    if !nonce.starts_with(client_nonce) {
+        return Err(SaslError::Malformed(
+            "server nonce does not extend ours".into(),
+        ));
+    }
+    let salt = scram_attr(server_first, 's')
+        .and_then(|s| BASE64.decode(s).ok())
+        .ok_or_else(malformed)?;
+    let iterations: u32 = scram_attr(server_first, 'i')
+        .and_then(|i| i.parse().ok())
+        .filter(|i| *i > 0)
+        .ok_or_else(malformed)?;
+    if iterations > MAX_SCRAM_ITERATIONS {
+        return Err(SaslError::TooManyIterations(iterations));
+    }
+
+    let password = password.to_owned();
+    let salted_password = tokio::task::spawn_blocking(move || {
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted_password);
+        salted_password
+    })
+    .await
+    .expect("PBKDF2 does not panic");
+
+    let client_key = hmac(&salted_password, b"Client Key");
+    let stored_key = Sha256::digest(&client_key);
+    let server_key = hmac(&salted_password, b"Server Key");
+
+    // "biws" is base64("n,,"): no channel binding, no authzid.
+    let client_final_without_proof = format!("c=biws,r={}", nonce);
+    let auth_message = format!(
+        "{},{},{}",
+        client_first_bare, server_first, client_final_without_proof
+    );
+
+    let client_signature = hmac(&stored_key, auth_message.as_bytes());
+    let proof: Vec<u8> = client_key
+        .iter()
+        .zip(&client_signature)
+        .map(|(k, s)| k ^ s)
+        .collect();
+    let server_signature = hmac(&server_key, auth_message.as_bytes());
+
+    Ok((
+        format!("{},p={}", client_final_without_proof, BASE64.encode(proof)),
+        server_signature,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creds(
+        mechanism: SaslMechanism,
+        account: &'static str,
+        password: &'static str,
+    ) -> SaslCredentials {
+        SaslCredentials {
+            mechanism,
+            account: account.into(),
+            password: password.into(),
+        }
+    }
+
+    fn decode_line(line: &str) -> String {
+        let data = line.strip_prefix("AUTHENTICATE ").unwrap();
+        String::from_utf8(BASE64.decode(data).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn plain_response() {
+        let mut session = SaslSession::new(creds(SaslMechanism::Plain, "botty", "hunter2"));
+        assert_eq!(session.start(), "AUTHENTICATE PLAIN");
+
+        let lines = session.on_authenticate("+").await.unwrap();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(decode_line(&lines[0]), "botty\0botty\0hunter2");
+    }
+
+    #[test]
+    fn encode_authenticate_chunks() {
+        assert_eq!(encode_authenticate(b""), vec!["AUTHENTICATE +"]);
+
+        // 300 bytes encode to exactly 400 base64 characters.
+        let lines = encode_authenticate(&[b'a'; 300]);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), "AUTHENTICATE ".len() + 400);
+        assert_eq!(lines[1], "AUTHENTICATE +");
+
+        let lines = encode_authenticate(&[b'a'; 301]);
+        assert_eq!(lines.len(), 2);
+        assert_ne!(lines[1], "AUTHENTICATE +");
+    }
+
+    #[tokio::test]
+    async fn incoming_chunks_are_joined() {
+        let mut session = SaslSession::new(creds(SaslMechanism::ScramSha256, "user", "pencil"))
+            .with_nonce("rOprNGfwEbeRWgbNEkqO");
+        session.on_authenticate("+").await.unwrap();
+
+        let server_first = BASE64.encode(format!(
+            "r=rOprNGfwEbeRWgbNEkqO{},s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            "x".repeat(400)
+        ));
+        let (head, tail) = server_first.split_at(CHUNK_LEN);
+
+        assert_eq!(
+            session.on_authenticate(head).await.unwrap(),
+            Vec::<String>::new()
+        );
+        let client_final = session.on_authenticate(tail).await.unwrap();
+        assert!(decode_line(&client_final[0]).starts_with("c=biws,r=rOprNGfwEbeRWgbNEkqOxxx"));
+    }
+
+    /// Test vector from RFC 7677, section 3.
+    #[tokio::test]
+    async fn scram_sha256_rfc7677() {
+        let mut session = SaslSession::new(creds(SaslMechanism::ScramSha256, "user", "pencil"))
+            .with_nonce("rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(session.start(), "AUTHENTICATE SCRAM-SHA-256");
+
+        let client_first = session.on_authenticate("+").await.unwrap();
+        assert_eq!(
+            decode_line(&client_first[0]),
+            "n,,n=user,r=rOprNGfwEbeRWgbNEkqO"
+        );
+
+        let server_first = BASE64.encode(
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+        );
+        let client_final = session.on_authenticate(&server_first).await.unwrap();
+        assert_eq!(
+            decode_line(&client_final[0]),
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+
+        let server_final = BASE64.encode("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=");
+        let done = session.on_authenticate(&server_final).await.unwrap();
+        assert_eq!(done, vec!["AUTHENTICATE +"]);
+    }
+
+    #[tokio::test]
+    async fn scram_rejects_bad_server_signature() {
+        let mut session = SaslSession::new(creds(SaslMechanism::ScramSha256, "user", "pencil"))
+            .with_nonce("rOprNGfwEbeRWgbNEkqO");
+        session.on_authenticate("+").await.unwrap();
+        let server_first = BASE64.encode(
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+        );
+        session.on_authenticate(&server_first).await.unwrap();
+
+        let server_final = BASE64.encode("v=AAAA");
+        assert_eq!(
+            session.on_authenticate(&server_final).await,
+            Err(SaslError::BadServerSignature)
+        );
+    }
+
+    #[tokio::test]
+    async fn scram_rejects_foreign_nonce() {
+        let mut session = SaslSession::new(creds(SaslMechanism::ScramSha256, "user", "pencil"))
+            .with_nonce("rOprNGfwEbeRWgbNEkqO");
+        session.on_authenticate("+").await.unwrap();
+        let server_first = BASE64.encode("r=somethingelse,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096");
+
+        assert!(matches!(
+            session.on_authenticate(&server_first).await,
+            Err(SaslError::Malformed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn scram_refuses_huge_iteration_counts() {
+        let mut session = SaslSession::new(creds(SaslMechanism::ScramSha256, "user", "pencil"))
+            .with_nonce("rOprNGfwEbeRWgbNEkqO");
+        session.on_authenticate("+").await.unwrap();
+        let server_first = BASE64.encode(
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4294967295",
+        );
+
+        assert_eq!(
+            session.on_authenticate(&server_first).await,
+            Err(SaslError::TooManyIterations(u32::MAX))
+        );
+    }
+
+    #[test]
+    fn scram_escapes_username() {
+        assert_eq!(scram_escape("a=b,c"), "a=3Db=2Cc");
+    }
+}
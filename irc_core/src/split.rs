@@ -0,0 +1,136 @@
+//! Splitting long outgoing messages so each piece fits in a single line.
+//!
+//! The server prepends our full `nick!user@host` when it relays a message, so the
+//! budget for the text is the line length minus everything the recipient will see
+//! around it, not just what we send.
+
+/// Assumed worst-case length of our `user@host` when the server hasn't shown it to us:
+/// a `~`-prefixed 10 character username and a 63 character hostname.
+const UNKNOWN_USERHOST_LEN: usize = 1 + 10 + 1 + 63;
+
+/// Bytes available for the text of `command target :text` once relayed with a source
+/// of `source_len` bytes. `linelen` includes the CRLF, as in `RPL_ISUPPORT`.
+pub fn text_budget(linelen: usize, source_len: usize, command: &str, target: &str) -> usize {
+    // ":source COMMAND target :text\r\n"
+    let overhead = 1 + source_len + 1 + command.len() + 1 + target.len() + 2 + 2;
+    linelen.saturating_sub(overhead)
+}
+
+/// The worst-case source the server will relay for `nick` when we don't know our host.
+pub fn assumed_source_len(nick: &str) -> usize {
+    nick.len() + 1 + UNKNOWN_USERHOST_LEN
+}
+
+/// Splits `text` into pieces of at most `max_bytes` each.
+///
+/// Every CR or LF starts a new piece, so the text can never inject a second command,
+/// and NUL is dropped. Long lines break at the last whitespace that fits, or at a
+/// UTF-8 character boundary if a single word is too long. If `marker` is given it is
+/// appended to every piece but the last of each line, e.g. `…` or ` (cont)`.
+pub fn split_message(text: &str, max_bytes: usize, marker: Option<&str>) -> Vec<String> {
+    let marker = marker.unwrap_or_default();
+    // A marker that could leave no room for even one character is not worth honouring.
+    let marker = if marker.len() + 4 <= max_bytes {
+        marker
+    } else {
+        ""
+    };
+
+    let mut pieces = vec![];
+    for line in text.split(['\r', '\n']) {
+        let line = line.replace('\0', "");
+        let mut rest = line.trim_end();
+        while rest.len() > max_bytes {
+            let cut = break_point(rest, max_bytes - marker.len());
+            let (head, tail) = rest.split_at(cut);
+            let head = head.trim_end();
+            if !head.is_empty() {
+                pieces.push(format!("{}{}", head, marker));
+            }
+            rest = tail.trim_start();
+        }
+        if !rest.is_empty() {
+            pieces.push(rest.to_owned());
+        }
+    }
+    pieces
+}
+
+/// Where to cut `text` so the head is at most `max` bytes: after the last whitespace
+/// that fits, or at the last character boundary. Always makes progress.
+fn break_point(text: &str, max: usize) -> usize {
+    let mut limit = max.min(text.len());
+    while !text.is_char_boundary(limit) {
+        limit -= 1;
+    }
+    if limit == 0 {
+        // Not even one character fits; take it anyway rather than loop forever.
+        return text.chars().next().map_or(0, char::len_utf8);
+    }
+
+    if text[limit..].starts_with(char::is_whitespace) {
+        return limit;
+    }
+    match text[..limit].rfind(char::is_whitespace) {
+        Some(space) if space > 0 => space,
+        _ => limit,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_untouched() {
+        assert_eq!(split_message("hello world", 20, None), vec!["hello world"]);
+    }
+
+    #[test]
+    fn splits_on_words() {
+        assert_eq!(
+            split_message("the quick brown fox jumps", 10, None),
+            vec!["the quick", "brown fox", "jumps"]
+        );
+    }
+
+    #[test]
+    fn splits_long_words_on_char_boundaries() {
+        let pieces = split_message("ääääää", 5, None);
+        assert_eq!(pieces, vec!["ää", "ää", "ää"]);
+        assert!(pieces.iter().all(|p| p.len() <= 5));
+    }
+
+    #[test]
+    fn appends_marker_within_budget() {
+        let pieces = split_message("the quick brown fox jumps", 12, Some("…"));
+        assert_eq!(pieces, vec!["the quick…", "brown fox…", "jumps"]);
+        assert!(pieces.iter().all(|p| p.len() <= 12));
+    }
+
+    #[test]
+    fn newlines_never_reach_a_piece() {
+        let pieces = split_message("hi\r\nQUIT :bye\n\nthere\0", 100, None);
+        assert_eq!(pieces, vec!["hi", "QUIT :bye", "there"]);
+    }
+
+    #[test]
+    fn budget_accounts_for_relayed_source() {
+        let source = "botty!~bot@example.org";
+        let budget = text_budget(512, source.len(), "PRIVMSG", "#rust");
+        let relayed = format!(":{} PRIVMSG #rust :{}\r\n", source, "x".repeat(budget));
+        assert_eq!(relayed.len(), 512);
+    }
+
+    #[test]
+    fn every_piece_fits() {
+        let text =
+            "lorem ipsum dolor sit amet, ünïcödé wörds and averyveryverylongword ".repeat(20);
+        for max in [1, 3, 7, 16, 50, 400] {
+            for piece in split_message(&text, max, Some(" +")) {
+                assert!(piece.len() <= max.max(2), "{piece:?} over {max}");
+                assert!(!piece.is_empty());
+            }
+        }
+    }
+}
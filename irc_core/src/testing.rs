@@ -0,0 +1,106 @@
+//! Shared test fixtures: a scripted fake server for tests that need a real
+//! [`Client`](crate::client::Client), and the vendored irc-parser-tests vectors.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+use crate::irc_msg::Tags;
+
+pub(crate) type ServerReader = tokio::io::Lines<BufReader<OwnedReadHalf>>;
+
+/// Accepts a client and registers it as `botty`, without any capabilities.
+pub(crate) async fn accept_and_register(listener: &TcpListener) -> (ServerReader, OwnedWriteHalf) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    wait_for(&mut lines, "USER botty 0 * :bot").await;
+    write
+        .write_all(b":srv CAP * LS :\r\n:srv 001 botty :Welcome\r\n")
+        .await
+        .unwrap();
+    (lines, write)
+}
+
+/// Reads lines until `expected`, panicking if the client hangs up first.
+pub(crate) async fn wait_for(lines: &mut ServerReader, expected: &str) {
+    while let Some(line) = lines.next_line().await.unwrap() {
+        if line == expected {
+            return;
+        }
+    }
+    panic!("connection closed before {expected:?}");
+}
+
+/// Log output captured by [`capture_logs`].
+#[derive(Clone, Default)]
+pub(crate) struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl LogBuffer {
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records everything logged on this thread until the guard is dropped. Tasks
+/// spawned onto other threads aren't captured, so use a current-thread runtime.
+pub(crate) fn capture_logs() -> (LogBuffer, tracing::subscriber::DefaultGuard) {
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_writer(move || writer.clone())
+        .finish();
+    (logs, tracing::subscriber::set_default(subscriber))
+}
+
+/// One test file from `testdata/parser-tests`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Vectors<T> {
+    pub tests: Vec<T>,
+}
+
+/// The parts of a message, as the parser tests describe them.
+#[derive(Debug, Deserialize)]
+pub(crate) struct Atoms {
+    #[serde(default, deserialize_with = "tags")]
+    pub tags: Tags,
+    pub source: Option<String>,
+    pub verb: String,
+    #[serde(default)]
+    pub params: Vec<String>,
+}
+
+/// The tests write a tag without a value as `null`; this crate represents it as
+/// an empty string.
+fn tags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Tags, D::Error> {
+    let tags = Option::<BTreeMap<String, Option<String>>>::deserialize(deserializer)?;
+    Ok(tags
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(key, value)| (key, value.unwrap_or_default()))
+        .collect())
+}
+
+/// Loads the vectors from a vendored parser-tests file, given its contents.
+pub(crate) fn vectors<T: DeserializeOwned>(yaml: &str) -> Vec<T> {
+    serde_yaml::from_str::<Vectors<T>>(yaml)
+        .expect("malformed parser-tests file")
+        .tests
+}
@@ -0,0 +1,317 @@
+//! TLS transport, backed by rustls.
+
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Context as _;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    self, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+/// TLS settings for a connection. The default trusts the Mozilla root store
+/// (via `webpki-roots`) and does not present a client certificate.
+#[derive(Clone, Default)]
+pub struct TlsConfig {
+    ca_pem: Vec<Vec<u8>>,
+    client_cert: Option<(Vec<u8>, Vec<u8>)>,
+    accept_invalid_certs: bool,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("extra_cas", &self.ca_pem.len())
+            .field("client_cert", &self.client_cert.is_some())
+            .field("accept_invalid_certs", &self.accept_invalid_certs)
+            .finish()
+    }
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also trusts the PEM-encoded CA certificate(s), e.g. for a private network.
+    pub fn with_ca_pem(mut self, pem: impl Into<Vec<u8>>) -> Self {
+        self.ca_pem.push(pem.into());
+        self
+    }
+
+    /// Presents a client certificate, which servers use for CertFP authentication.
+    /// Both arguments are PEM-encoded; the key may be PKCS#1, PKCS#8 or SEC1.
+    pub fn with_client_cert_pem(
+        mut self,
+        cert_chain: impl Into<Vec<u8>>,
+        key: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.client_cert = Some((cert_chain.into(), key.into()));
+        self
+    }
+
+    /// Skips certificate validation entirely. Only meant for self-signed test ircds:
+    /// the connection is encrypted, but anyone on the path can impersonate the server.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    fn client_config(&self) -> anyhow::Result<ClientConfig> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = if self.accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            for pem in &self.ca_pem {
+                for cert in CertificateDer::pem_slice_iter(pem) {
+                    roots
+                        .add(cert.context("invalid CA certificate PEM")?)
+                        .context("invalid CA certificate")?;
+                }
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        let config = match &self.client_cert {
+            Some((cert_pem, key_pem)) => {
+                let chain = CertificateDer::pem_slice_iter(cert_pem)
+                    .collect::<Result<Vec<_>, _>>()
+                    .context("invalid client certificate PEM")?;
+                let key =
+                    PrivateKeyDer::from_pem_slice(key_pem).context("invalid client key PEM")?;
+                builder.with_client_auth_cert(chain, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(config)
+    }
+}
+
+/// Performs the TLS handshake over an established TCP connection to `server`, which
+/// is the same `host:port` string that was used to connect.
+pub(crate) async fn handshake(
+    tls: &TlsConfig,
+    server: &str,
+    stream: TcpStream,
+) -> anyhow::Result<TlsStream<TcpStream>> {
+    let connector = TlsConnector::from(Arc::new(tls.client_config()?));
+    let name = ServerName::try_from(host_of(server).to_owned())
+        .with_context(|| format!("invalid TLS server name in {}", server))?;
+
+    connector
+        .connect(name, stream)
+        .await
+        .with_context(|| format!("TLS handshake with {} failed", server))
+}
+
+/// Strips the port (and IPv6 brackets) from a `host:port` address.
+fn host_of(server: &str) -> &str {
+    let host = match server.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => server,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Certificate verifier for [`TlsConfig::danger_accept_invalid_certs`]. Handshake
+/// signatures are still checked so the session itself is sound.
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::rustls::server::WebPkiClientVerifier;
+
+    use super::*;
+
+    struct TestCert {
+        cert_pem: String,
+        key_pem: String,
+    }
+
+    fn generate(name: &str) -> TestCert {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_owned()]).unwrap();
+        TestCert {
+            cert_pem: cert.cert.pem(),
+            key_pem: cert.key_pair.serialize_pem(),
+        }
+    }
+
+    /// Starts a one-shot TLS listener that writes a greeting and echoes the first line
+    /// it receives. Returns its `host:port`.
+    async fn tls_listener(server: &TestCert, client_ca: Option<&TestCert>) -> String {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = match client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add(CertificateDer::from_pem_slice(ca.cert_pem.as_bytes()).unwrap())
+                    .unwrap();
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .build()
+                    .unwrap();
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(
+                vec![CertificateDer::from_pem_slice(server.cert_pem.as_bytes()).unwrap()],
+                PrivateKeyDer::from_pem_slice(server.key_pem.as_bytes()).unwrap(),
+            )
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let Ok(mut tls) = acceptor.accept(tcp).await else {
+                return;
+            };
+            tls.write_all(b"NOTICE * :hello\r\n").await.unwrap();
+            let mut line = String::new();
+            let mut reader = BufReader::new(&mut tls);
+            reader.read_line(&mut line).await.unwrap();
+            tls.write_all(line.as_bytes()).await.unwrap();
+            tls.shutdown().await.unwrap();
+        });
+
+        format!("localhost:{}", addr.port())
+    }
+
+    async fn exchange(tls: &TlsConfig, server: &str) -> anyhow::Result<(String, String)> {
+        let tcp = TcpStream::connect(server.replace("localhost", "127.0.0.1")).await?;
+        let stream = handshake(tls, server, tcp).await?;
+        let (read, mut write) = tokio::io::split(stream);
+        let mut reader = BufReader::new(read);
+
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).await?;
+        write.write_all(b"PING :echo\r\n").await?;
+        let mut echo = String::new();
+        reader.read_line(&mut echo).await?;
+        Ok((greeting, echo))
+    }
+
+    #[test]
+    fn host_of_strips_port() {
+        assert_eq!(host_of("irc.libera.chat:6697"), "irc.libera.chat");
+        assert_eq!(host_of("irc.libera.chat"), "irc.libera.chat");
+        assert_eq!(host_of("[::1]:6697"), "::1");
+    }
+
+    #[tokio::test]
+    async fn tls_with_custom_ca() {
+        let cert = generate("localhost");
+        let server = tls_listener(&cert, None).await;
+
+        let tls = TlsConfig::new().with_ca_pem(cert.cert_pem.clone());
+        let (greeting, echo) = exchange(&tls, &server).await.unwrap();
+        assert_eq!(greeting, "NOTICE * :hello\r\n");
+        assert_eq!(echo, "PING :echo\r\n");
+    }
+
+    #[tokio::test]
+    async fn tls_rejects_unknown_ca() {
+        let cert = generate("localhost");
+        let server = tls_listener(&cert, None).await;
+
+        let err = exchange(&TlsConfig::new(), &server).await.unwrap_err();
+        assert!(
+            format!("{err:#}").contains("TLS handshake"),
+            "error was: {err:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn tls_accept_invalid_certs() {
+        let cert = generate("some.other.name");
+        let server = tls_listener(&cert, None).await;
+
+        let tls = TlsConfig::new().danger_accept_invalid_certs(true);
+        let (greeting, _) = exchange(&tls, &server).await.unwrap();
+        assert_eq!(greeting, "NOTICE * :hello\r\n");
+    }
+
+    #[tokio::test]
+    async fn tls_presents_client_cert() {
+        let server_cert = generate("localhost");
+        let client_cert = generate("botty");
+        let server = tls_listener(&server_cert, Some(&client_cert)).await;
+
+        let tls = TlsConfig::new()
+            .with_ca_pem(server_cert.cert_pem.clone())
+            .with_client_cert_pem(client_cert.cert_pem.clone(), client_cert.key_pem.clone());
+        let (greeting, _) = exchange(&tls, &server).await.unwrap();
+        assert_eq!(greeting, "NOTICE * :hello\r\n");
+    }
+}
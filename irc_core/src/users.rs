@@ -0,0 +1,415 @@
+//! Everyone we share a channel with, followed across nick changes, host changes,
+//! kicks and quits.
+//!
+//! [`Users`] is updated alongside [`Channels`], and turns what it sees into
+//! [`UserEvent`]s. Those carry what the raw message doesn't, such as which channels a
+//! quitting user was in, so handlers don't have to keep their own records.
+
+use std::collections::HashMap;
+
+use crate::casemap::CaseMapping;
+use crate::channels::Channels;
+use crate::irc_msg::{Command, Msg};
+use crate::isupport::ServerInfo;
+use crate::msg_ref::MsgRef;
+use crate::numeric::Numeric;
+use crate::prefix::Prefix;
+
+/// What we know about a user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    /// The services account, from `account-notify`, `extended-join` or `account-tag`.
+    pub account: Option<String>,
+    /// The away message, from `away-notify`. `None` means present.
+    pub away: Option<String>,
+}
+
+impl User {
+    fn new(nick: &str) -> Self {
+        Self {
+            nick: nick.to_owned(),
+            user: None,
+            host: None,
+            account: None,
+            away: None,
+        }
+    }
+
+    /// Fills in `user@host` from a message source.
+    fn learn(&mut self, source: &Prefix) {
+        if let Prefix::User {
+            user: Some(user),
+            host: Some(host),
+            ..
+        } = source
+        {
+            self.user = Some(user.clone());
+            self.host = Some(host.clone());
+        }
+    }
+}
+
+/// Something a user did, as seen across every channel we share with them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserEvent {
+    Joined {
+        nick: String,
+        channel: String,
+    },
+    Parted {
+        nick: String,
+        channel: String,
+        message: Option<String>,
+    },
+    Kicked {
+        nick: String,
+        channel: String,
+        by: Option<String>,
+        reason: Option<String>,
+    },
+    /// The user disconnected, leaving `channels`.
+    Quit {
+        nick: String,
+        channels: Vec<String>,
+        message: Option<String>,
+    },
+    /// `old` is now known as `new` in `channels`.
+    NickChanged {
+        old: String,
+        new: String,
+        channels: Vec<String>,
+    },
+    HostChanged {
+        nick: String,
+        user: String,
+        host: String,
+    },
+}
+
+/// Users in our channels, keyed by folded nick.
+#[derive(Debug, Clone, Default)]
+pub struct Users {
+    mapping: CaseMapping,
+    users: HashMap<String, User>,
+}
+
+impl Users {
+    pub fn get(&self, nick: &str) -> Option<&User> {
+        self.users.get(&self.mapping.fold(nick))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &User> {
+        self.users.values()
+    }
+
+    pub fn clear(&mut self) {
+        self.users.clear();
+    }
+
+    /// Updates the registry from a message and returns what happened. `channels` must
+    /// not have seen the message yet: it tells us where a user was before leaving.
+    pub fn apply(
+        &mut self,
+        msg: &Msg,
+        me: &str,
+        info: &ServerInfo,
+        channels: &Channels,
+    ) -> Vec<UserEvent> {
+        self.mapping = info.casemapping;
+        let mapping = self.mapping;
+        if let Command::Numeric { .. } = msg.command {
+            self.apply_reply(msg, info);
+            return vec![];
+        }
+        let Some(from) = msg.nick() else {
+            return vec![];
+        };
+
+        if let Some(user) = self.get_mut(from) {
+            if let Some(source) = &msg.source {
+                user.learn(source);
+            }
+            if let Some(account) = msg.tags.get("account") {
+                user.account = Some(account.clone());
+            }
+        }
+        let names =
+            |nick: &str| -> Vec<String> { channels.of(nick).map(|c| c.name.clone()).collect() };
+
+        match &msg.command {
+            Command::Join { channel, .. } => {
+                let user = self
+                    .users
+                    .entry(mapping.fold(from))
+                    .or_insert_with(|| User::new(from));
+                if let Some(source) = &msg.source {
+                    user.learn(source);
+                }
+                // extended-join sends `JOIN #channel account :realname`, which the
+                // typed command drops. `*` means not logged in.
+                if let Some(account) = MsgRef::parse(&msg.meta.raw).and_then(|m| m.param(1)) {
+                    user.account = (account != "*").then(|| account.to_owned());
+                }
+                vec![UserEvent::Joined {
+                    nick: from.to_owned(),
+                    channel: channel.clone(),
+                }]
+            }
+            Command::Part { channel, message } => {
+                self.leave(from, channel, me, channels);
+                vec![UserEvent::Parted {
+                    nick: from.to_owned(),
+                    channel: channel.clone(),
+                    message: message.clone(),
+                }]
+            }
+            Command::Kick {
+                channel,
+                nick,
+                message,
+            } => {
+                self.leave(nick, channel, me, channels);
+                vec![UserEvent::Kicked {
+                    nick: nick.clone(),
+                    channel: channel.clone(),
+                    by: Some(from.to_owned()),
+                    reason: message.clone(),
+                }]
+            }
+            Command::Quit { message } => {
+                self.users.remove(&mapping.fold(from));
+                vec![UserEvent::Quit {
+                    nick: from.to_owned(),
+                    channels: names(from),
+                    message: message.clone(),
+                }]
+            }
+            Command::Nick { nick: new } => {
+                if let Some(mut user) = self.users.remove(&mapping.fold(from)) {
+                    user.nick = new.clone();
+                    self.users.insert(mapping.fold(new), user);
+                }
+                vec![UserEvent::NickChanged {
+                    old: from.to_owned(),
+                    new: new.clone(),
+                    channels: names(from),
+                }]
+            }
+            Command::Chghost { user, host } => {
+                if let Some(known) = self.get_mut(from) {
+                    known.user = Some(user.clone());
+                    known.host = Some(host.clone());
+                }
+                vec![UserEvent::HostChanged {
+                    nick: from.to_owned(),
+                    user: user.clone(),
+                    host: host.clone(),
+                }]
+            }
+            Command::Account { account } => {
+                if let Some(known) = self.get_mut(from) {
+                    known.account.clone_from(account);
+                }
+                vec![]
+            }
+            Command::Away { message } => {
+                if let Some(known) = self.get_mut(from) {
+                    known.away.clone_from(message);
+                }
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Adds the users listed in RPL_NAMREPLY, with their hosts if the server sends
+    /// them (`userhost-in-names`).
+    fn apply_reply(&mut self, msg: &Msg, info: &ServerInfo) {
+        let Some(reply) = msg.reply() else { return };
+        if reply.numeric != Numeric::RplNamReply {
+            return;
+        }
+        for entry in reply.names() {
+            let entry = entry.trim_start_matches(|c| info.mode_for_prefix(c).is_some());
+            let source = Prefix::parse(entry);
+            let Some(nick) = source.nick() else { continue };
+            self.users
+                .entry(self.mapping.fold(nick))
+                .or_insert_with(|| User::new(nick))
+                .learn(&source);
+        }
+    }
+
+    /// Forgets `nick` once they have left `channel`, unless we still share another one.
+    /// If we are the one leaving, forgets everyone we only saw there.
+    fn leave(&mut self, nick: &str, channel: &str, me: &str, channels: &Channels) {
+        let mapping = self.mapping;
+        let elsewhere = |nick: &str| channels.of(nick).any(|c| !mapping.eq(&c.name, channel));
+        if mapping.eq(nick, me) {
+            if let Some(left) = channels.get(channel) {
+                for member in left.members() {
+                    if !elsewhere(&member.nick) && !mapping.eq(&member.nick, me) {
+                        self.users.remove(&mapping.fold(&member.nick));
+                    }
+                }
+            }
+        } else if !elsewhere(nick) {
+            self.users.remove(&mapping.fold(nick));
+        }
+    }
+
+    fn get_mut(&mut self, nick: &str) -> Option<&mut User> {
+        self.users.get_mut(&self.mapping.fold(nick))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    /// Feeds lines through both trackers the way the client does, collecting events.
+    fn feed(
+        users: &mut Users,
+        channels: &mut Channels,
+        info: &ServerInfo,
+        lines: &[&str],
+    ) -> Vec<UserEvent> {
+        let mut events = vec![];
+        for line in lines {
+            let msg = Msg::parse(line, SystemTime::UNIX_EPOCH.into()).unwrap();
+            events.extend(users.apply(&msg, "botty", info, channels));
+            channels.apply(&msg, "botty", info);
+        }
+        events
+    }
+
+    fn setup() -> (Users, Channels, ServerInfo) {
+        let info = ServerInfo::default();
+        let (mut users, mut channels) = (Users::default(), Channels::default());
+        feed(
+            &mut users,
+            &mut channels,
+            &info,
+            &[
+                ":botty!b@h JOIN #rust",
+                ":srv 353 botty = #rust :botty @alice!a@alice.host bob",
+                ":srv 366 botty #rust :End",
+                ":botty!b@h JOIN #go",
+                ":srv 353 botty = #go :botty alice!a@alice.host",
+                ":srv 366 botty #go :End",
+            ],
+        );
+        (users, channels, info)
+    }
+
+    #[test]
+    fn quit_and_nick_report_shared_channels() {
+        let (mut users, mut channels, info) = setup();
+        let mut events = feed(
+            &mut users,
+            &mut channels,
+            &info,
+            &[
+                ":alice!a@alice.host NICK Alicia",
+                ":Alicia!a@alice.host QUIT :bye",
+            ],
+        );
+        for event in &mut events {
+            if let UserEvent::NickChanged { channels, .. } | UserEvent::Quit { channels, .. } =
+                event
+            {
+                channels.sort();
+            }
+        }
+        assert_eq!(
+            events,
+            [
+                UserEvent::NickChanged {
+                    old: "alice".into(),
+                    new: "Alicia".into(),
+                    channels: vec!["#go".into(), "#rust".into()],
+                },
+                UserEvent::Quit {
+                    nick: "Alicia".into(),
+                    channels: vec!["#go".into(), "#rust".into()],
+                    message: Some("bye".into()),
+                },
+            ]
+        );
+        assert_eq!(users.get("alicia"), None);
+    }
+
+    #[test]
+    fn users_are_forgotten_when_no_channel_is_shared() {
+        let (mut users, mut channels, info) = setup();
+        let events = feed(
+            &mut users,
+            &mut channels,
+            &info,
+            &[
+                ":alice!a@alice.host PART #go",
+                ":alice!a@alice.host KICK #rust bob :spam",
+            ],
+        );
+        assert!(matches!(&events[1], UserEvent::Kicked { by: Some(by), .. } if by == "alice"));
+        assert!(users.get("alice").is_some(), "still in #rust");
+        assert!(users.get("bob").is_none());
+
+        feed(&mut users, &mut channels, &info, &[":botty!b@h PART #rust"]);
+        assert!(users.get("alice").is_none());
+        assert!(users.get("botty").is_some());
+    }
+
+    #[test]
+    fn follows_hosts_and_accounts() {
+        let (mut users, mut channels, info) = setup();
+        assert_eq!(
+            users.get("ALICE").unwrap().host.as_deref(),
+            Some("alice.host")
+        );
+
+        let events = feed(
+            &mut users,
+            &mut channels,
+            &info,
+            &[
+                ":alice!a@alice.host CHGHOST ali new.host",
+                ":carol!c@carol.host JOIN #rust carolacct :Carol",
+                ":bob!b@bob.host ACCOUNT bobacct",
+                ":bob!b@bob.host AWAY :lunch",
+            ],
+        );
+        assert_eq!(
+            events,
+            [
+                UserEvent::HostChanged {
+                    nick: "alice".into(),
+                    user: "ali".into(),
+                    host: "new.host".into(),
+                },
+                UserEvent::Joined {
+                    nick: "carol".into(),
+                    channel: "#rust".into(),
+                },
+            ]
+        );
+        let alice = users.get("alice").unwrap();
+        assert_eq!(
+            (alice.user.as_deref(), alice.host.as_deref()),
+            (Some("ali"), Some("new.host"))
+        );
+        assert_eq!(
+            users.get("carol").unwrap().account.as_deref(),
+            Some("carolacct")
+        );
+        let bob = users.get("bob").unwrap();
+        assert_eq!(bob.account.as_deref(), Some("bobacct"));
+        assert_eq!(bob.host.as_deref(), Some("bob.host"));
+        assert_eq!(bob.away.as_deref(), Some("lunch"));
+    }
+}